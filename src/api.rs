@@ -0,0 +1,106 @@
+/*
+qBittorrent Mover - A tool to automatically move torrents to different categories based on their state.
+Copyright (C) 2023 Harrison Chin
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use super::errors::api_schema;
+use anyhow::Result;
+use std::fmt;
+
+/// A qBittorrent Web API version as reported by `/api/v2/app/webapiVersion`,
+/// such as 2.9.3. It is not the qBittorrent version: 2.11.0 came with
+/// qBittorrent 5.0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ApiVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl ApiVersion {
+    pub const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+
+    /// The first version of the v2 API, from qBittorrent 4.1.
+    pub const MIN_SUPPORTED: ApiVersion = ApiVersion::new(2, 0, 0);
+
+    /// Parses "2.9.3"; a missing minor or patch counts as 0.
+    pub fn parse(text: &str) -> Result<Self> {
+        let text = text.trim();
+        let invalid = || api_schema(format!("Invalid Web API version {:?}", text));
+        let mut parts = text.split('.').map(|part| part.parse::<u32>());
+        let major = match parts.next() {
+            Some(Ok(major)) => major,
+            _ => return Err(invalid()),
+        };
+        let mut next = || parts.next().unwrap_or(Ok(0)).map_err(|_| invalid());
+        let version = Self::new(major, next()?, next()?);
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+        Ok(version)
+    }
+
+    /// Fails for the versions whose endpoints the mover does not know: the
+    /// v1 API of qBittorrent before 4.1, and any later major version.
+    pub fn check_supported(self) -> Result<()> {
+        if self < Self::MIN_SUPPORTED || self.major > Self::MIN_SUPPORTED.major {
+            return Err(api_schema(format!(
+                "qBittorrent Web API {} is not supported; the mover needs a 2.x API (qBittorrent 4.1 or later)",
+                self
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ApiVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::{error_kind, ErrorKind};
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        assert_eq!(ApiVersion::parse("2.9.3\n")?, ApiVersion::new(2, 9, 3));
+        assert_eq!(ApiVersion::parse("2.11")?, ApiVersion::new(2, 11, 0));
+        assert!(ApiVersion::parse("2.11.0")? > ApiVersion::parse("2.9.3")?);
+        for invalid in ["", "v2.9", "2.x", "2.9.3.1", "<html>"] {
+            let err = ApiVersion::parse(invalid).unwrap_err();
+            assert_eq!(error_kind(&err), ErrorKind::ApiSchema, "{}", invalid);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_supported() {
+        assert!(ApiVersion::new(2, 0, 0).check_supported().is_ok());
+        assert!(ApiVersion::new(2, 11, 2).check_supported().is_ok());
+        let err = ApiVersion::new(1, 0, 0).check_supported().unwrap_err();
+        assert!(err.to_string().contains("Web API 1.0.0 is not supported"));
+        assert!(ApiVersion::new(3, 0, 0).check_supported().is_err());
+    }
+}
@@ -0,0 +1,93 @@
+/*
+qBittorrent Mover - A tool to automatically move torrents to different categories based on their state.
+Copyright (C) 2023 Harrison Chin
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use rand::Rng;
+use std::time::Duration;
+
+/// Exponential backoff with jitter. Each consecutive failure doubles the
+/// delay, starting from `base` and capped at `max`; the jitter spreads
+/// retries of servers that went down together.
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    failures: u32,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max: max.max(base),
+            failures: 0,
+        }
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// The delay before the next attempt without jitter.
+    fn ceiling(&self) -> Duration {
+        let doublings = self.failures.saturating_sub(1).min(31);
+        self.base.saturating_mul(1 << doublings).min(self.max)
+    }
+
+    /// Records a failure and returns the delay before the next attempt,
+    /// somewhere between half and all of the backoff ceiling.
+    pub fn fail(&mut self) -> Duration {
+        self.failures = self.failures.saturating_add(1);
+        self.ceiling()
+            .mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+
+    /// Records a success. Returns true if it ended a run of failures.
+    pub fn succeed(&mut self) -> bool {
+        std::mem::take(&mut self.failures) > 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles_up_to_max() {
+        let mut backoff = Backoff::new(Duration::from_secs(5), Duration::from_secs(60));
+        let mut ceilings = Vec::new();
+        for _ in 0..6 {
+            let delay = backoff.fail();
+            let ceiling = backoff.ceiling();
+            assert!(delay >= ceiling / 2 && delay <= ceiling, "{:?}", delay);
+            ceilings.push(ceiling.as_secs());
+        }
+        assert_eq!(ceilings, vec![5, 10, 20, 40, 60, 60]);
+        assert_eq!(backoff.failures(), 6);
+    }
+
+    #[test]
+    fn test_backoff_resets_on_success() {
+        let mut backoff = Backoff::new(Duration::from_secs(5), Duration::from_secs(1));
+        assert!(!backoff.succeed());
+        backoff.fail();
+        // A max below the base never shortens the normal interval
+        assert_eq!(backoff.ceiling(), Duration::from_secs(5));
+        assert!(backoff.succeed());
+        assert!(!backoff.succeed());
+        assert_eq!(backoff.failures(), 0);
+    }
+}
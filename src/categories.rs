@@ -0,0 +1,170 @@
+/*
+qBittorrent Mover - A tool to automatically move torrents to different categories based on their state.
+Copyright (C) 2023 Harrison Chin
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use super::config::ServerConfig;
+use super::paths::PathMapper;
+use super::torrent::{self, TorrentClient};
+use anyhow::Result;
+use log::{info, warn};
+use std::collections::HashMap;
+
+/// Whether the server's categories have to be checked against qBittorrent.
+pub fn wants_sync(server: &ServerConfig) -> bool {
+    server.discover_categories || server.create_missing_categories || !server.categories.is_empty()
+}
+
+/// Reconciles the configured categories with the ones qBittorrent knows.
+///
+/// Configured categories missing on the server are either created
+/// (`create_missing_categories`) or reported, since torrents can never match
+/// them. With `discover_categories`, server categories that are not
+/// configured are added, using their mapped save path as the destination.
+/// Returns the effective category to destination map.
+pub async fn sync_categories(client: &TorrentClient) -> Result<HashMap<String, String>> {
+    let server = client.server();
+    let remote = torrent::get_categories(client).await?;
+    let mut categories = server.categories.clone();
+
+    let mut configured: Vec<&String> = server.categories.keys().collect();
+    configured.sort();
+    for name in configured {
+        if remote.contains_key(name) {
+            continue;
+        }
+        if server.create_missing_categories {
+            torrent::create_category(client, name).await?;
+            info!("Created category {} on {}", name, server.qbit_url);
+        } else {
+            warn!(
+                "Category {} is configured for {} but does not exist on the server, so no torrent will match it",
+                name, server.qbit_url
+            );
+        }
+    }
+
+    if server.discover_categories {
+        let mapper = PathMapper::from_server(server);
+        let mut discovered: Vec<_> = remote
+            .values()
+            .filter(|category| !categories.contains_key(&category.name))
+            .collect();
+        discovered.sort_by(|a, b| a.name.cmp(&b.name));
+
+        for category in discovered {
+            if category.save_path.is_empty() {
+                info!(
+                    "Ignoring category {} on {}: it has no save path",
+                    category.name, server.qbit_url
+                );
+                continue;
+            }
+            match mapper.map(&category.save_path) {
+                Ok(mapped) => {
+                    info!(
+                        "Discovered category {} on {}, moving to {:?}",
+                        category.name, server.qbit_url, mapped.path
+                    );
+                    categories.insert(
+                        category.name.clone(),
+                        mapped.path.to_string_lossy().into_owned(),
+                    );
+                }
+                Err(e) => warn!(
+                    "Ignoring category {} on {}: {}",
+                    category.name, server.qbit_url, e
+                ),
+            }
+        }
+    }
+
+    Ok(categories)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PathMapping;
+    use mockito::Server;
+
+    const CATEGORIES: &str = r#"{
+        "movies": {"name": "movies", "savePath": "/downloads/movies"},
+        "tv": {"name": "tv", "savePath": ""},
+        "music": {"name": "music", "savePath": "/downloads/music"}
+    }"#;
+
+    #[tokio::test]
+    async fn test_sync_categories_discovers() -> Result<()> {
+        let mut server = Server::new();
+        let _m = server
+            .mock("GET", "/api/v2/torrents/categories")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(CATEGORIES)
+            .create();
+
+        let client = TorrentClient::new(ServerConfig {
+            qbit_url: server.url(),
+            categories: HashMap::from([(String::from("movies"), String::from("/media/movies"))]),
+            path_mappings: vec![PathMapping {
+                remote: String::from("/downloads"),
+                local: String::from("/srv/downloads"),
+            }],
+            discover_categories: true,
+            ..Default::default()
+        })?;
+        let categories = sync_categories(&client).await?;
+
+        // Configured destinations win and categories without a save path are skipped
+        assert_eq!(categories.len(), 2);
+        assert_eq!(categories["movies"], "/media/movies");
+        assert_eq!(categories["music"], "/srv/downloads/music");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sync_categories_creates_missing() -> Result<()> {
+        let mut server = Server::new();
+        let _m = server
+            .mock("GET", "/api/v2/torrents/categories")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(CATEGORIES)
+            .create();
+        let create = server
+            .mock("POST", "/api/v2/torrents/createCategory")
+            .match_body("category=moveis&savePath=")
+            .with_status(200)
+            .expect(1)
+            .create();
+
+        let client = TorrentClient::new(ServerConfig {
+            qbit_url: server.url(),
+            categories: HashMap::from([
+                (String::from("movies"), String::from("/media/movies")),
+                (String::from("moveis"), String::from("/media/typo")),
+            ]),
+            create_missing_categories: true,
+            ..Default::default()
+        })?;
+        let categories = sync_categories(&client).await?;
+
+        create.assert();
+        assert_eq!(categories.len(), 2);
+        Ok(())
+    }
+}
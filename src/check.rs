@@ -0,0 +1,572 @@
+/*
+qBittorrent Mover - A tool to automatically move torrents to different categories based on their state.
+Copyright (C) 2023 Harrison Chin
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use super::config::{
+    env_lookup, parse_config, Config, ConfigErrorKind, LoadedConfig, ServerConfig,
+};
+use super::errors::{error_kind, ErrorKind};
+use super::throttle::Bandwidth;
+use super::torrent::{self, TorrentClient};
+use anyhow::Result;
+use std::collections::HashSet;
+use std::fmt;
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// One problem found in the configuration, with a suggested fix.
+#[derive(Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub location: Option<String>,
+    pub message: String,
+    pub fix: String,
+}
+
+impl Diagnostic {
+    fn error(message: String, fix: &str) -> Self {
+        Self {
+            severity: Severity::Error,
+            location: None,
+            message,
+            fix: fix.to_string(),
+        }
+    }
+
+    fn warning(message: String, fix: &str) -> Self {
+        Self {
+            severity: Severity::Warning,
+            ..Self::error(message, fix)
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        match &self.location {
+            Some(location) => write!(f, "{}: {}: {}", severity, location, self.message)?,
+            None => write!(f, "{}: {}", severity, self.message)?,
+        }
+        write!(f, "\n  fix: {}", self.fix)
+    }
+}
+
+/// Loads the config file and its includes, turning a failure into a
+/// diagnostic that points at the offending file, line and column.
+pub fn parse(filename: &str) -> Result<LoadedConfig, Diagnostic> {
+    parse_config(filename, &env_lookup).map_err(|e| {
+        let fix = match e.kind {
+            ConfigErrorKind::NotFound => {
+                "Create the file with `qbittorrent_mover init` or pass --config"
+            }
+            ConfigErrorKind::Unreadable => "Check that the file exists and is readable",
+            ConfigErrorKind::Format => "Name the file .yaml, .yml, .toml or .json",
+            ConfigErrorKind::Syntax => {
+                "Fix the syntax at this position, such as indentation or an unclosed quote"
+            }
+            ConfigErrorKind::Layout => {
+                "Fix the outdated keys the message names; see the example configuration in the README"
+            }
+            ConfigErrorKind::Template => {
+                "Define the template under templates, or fix the name in the server's template key"
+            }
+            ConfigErrorKind::Variable => {
+                "Export the variable before starting the mover, or write $${ for a literal ${"
+            }
+            ConfigErrorKind::Override => {
+                "Fix or unset the environment variable overriding this setting"
+            }
+            ConfigErrorKind::MissingField => {
+                "Add the missing key; see the example configuration in the README"
+            }
+            ConfigErrorKind::UnknownField => "Check the spelling of the key or remove it",
+            ConfigErrorKind::Size => {
+                "Use a size such as \"512K\", \"1.5GiB\" or \"10MB\", or a rate such as \"20MiB/s\""
+            }
+            ConfigErrorKind::Duration => {
+                "Use a duration such as \"30s\", \"5m\" or \"1h30m\", or a number of seconds"
+            }
+            ConfigErrorKind::InvalidValue => {
+                "Use a value of the expected type, quoting strings that look like numbers"
+            }
+        };
+        Diagnostic {
+            location: Some(match e.position {
+                Some((line, column)) => format!("{}:{}:{}", e.file, line, column),
+                None => e.file.clone(),
+            }),
+            ..Diagnostic::error(e.message, fix)
+        }
+    })
+}
+
+/// Validates settings that deserialize fine but are rejected at startup,
+/// such as malformed bandwidth schedules.
+pub fn check_settings(config: &Config) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    if config.servers.is_empty() {
+        diagnostics.push(Diagnostic::error(
+            String::from("No servers are configured"),
+            "Add a qBittorrent instance under servers",
+        ));
+    }
+    for server in &config.servers {
+        if server.path_mappings.is_empty() && server.download_root.is_none() {
+            diagnostics.push(Diagnostic::error(
+                format!(
+                    "{}: no download_root or path_mappings, so nothing will be moved",
+                    server.qbit_url
+                ),
+                "Set download_root to the directory qBittorrent saves downloads in",
+            ));
+        }
+    }
+    if let Err(e) = Bandwidth::from_config(&config.bandwidth) {
+        diagnostics.push(Diagnostic::error(
+            format!("bandwidth: {}", e),
+            "Use times of day such as \"07:30\"",
+        ));
+    }
+    diagnostics
+}
+
+#[cfg(unix)]
+fn is_writable(path: &Path) -> bool {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let Ok(c_path) = CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // SAFETY: `c_path` is a valid NUL-terminated string.
+    unsafe { libc::access(c_path.as_ptr(), libc::W_OK) == 0 }
+}
+
+#[cfg(not(unix))]
+fn is_writable(path: &Path) -> bool {
+    std::fs::metadata(path)
+        .map(|metadata| !metadata.permissions().readonly())
+        .unwrap_or(false)
+}
+
+/// Checks that every category destination is an existing, writable directory.
+pub fn check_destinations(config: &Config) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let mut seen = HashSet::new();
+    for server in &config.servers {
+        let mut categories: Vec<_> = server.categories.iter().collect();
+        categories.sort();
+        for (category, dest) in categories {
+            if !seen.insert(dest) {
+                continue;
+            }
+            let path = Path::new(dest);
+            if !path.exists() {
+                diagnostics.push(Diagnostic::error(
+                    format!(
+                        "Destination {} for category {} does not exist",
+                        dest, category
+                    ),
+                    "Create the directory, mount its filesystem, or correct the path under categories",
+                ));
+            } else if !path.is_dir() {
+                diagnostics.push(Diagnostic::error(
+                    format!(
+                        "Destination {} for category {} is not a directory",
+                        dest, category
+                    ),
+                    "Point the category at a directory",
+                ));
+            } else if !is_writable(path) {
+                diagnostics.push(Diagnostic::error(
+                    format!(
+                        "Destination {} for category {} is not writable",
+                        dest, category
+                    ),
+                    "Give the user running the mover write access to the directory",
+                ));
+            }
+        }
+    }
+    diagnostics
+}
+
+/// Number of single-character edits needed to turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous + usize::from(ca != *cb);
+            previous = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(previous + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Logs in to the server and compares its categories and torrents with the
+/// configured categories.
+pub async fn check_server(server: &ServerConfig) -> Vec<Diagnostic> {
+    let url = &server.qbit_url;
+    let client = match TorrentClient::new(server.clone()) {
+        Ok(client) => client,
+        Err(e) => {
+            return vec![Diagnostic::error(
+                e.to_string(),
+                "Check the headers and proxy, that the tls files exist and hold PEM data, and that fingerprint is a SHA-256 hash",
+            )]
+        }
+    };
+
+    if let Err(e) = torrent::get_version(&client).await {
+        let diagnostic = match error_kind(&e) {
+            ErrorKind::Auth => Diagnostic::error(
+                format!("{} rejected the configured credentials", url),
+                "Check username and password, or allow the mover's address in qBittorrent's Web UI authentication settings",
+            ),
+            _ => Diagnostic::error(
+                format!("Cannot reach {}: {}", url, e),
+                "Check qbit_url and that qBittorrent's Web UI is enabled and reachable from this machine",
+            ),
+        };
+        return vec![diagnostic];
+    }
+
+    if let Err(e) = torrent::get_api_version(&client)
+        .await
+        .and_then(|version| version.check_supported())
+    {
+        return vec![Diagnostic::error(
+            format!("Unsupported Web API on {}: {}", url, e),
+            "The mover works with qBittorrent 4.1 or later; check that qbit_url points at qBittorrent itself",
+        )];
+    }
+
+    let (remote, torrents) = match tokio::try_join!(
+        torrent::get_categories(&client),
+        torrent::get_all_torrents(&client)
+    ) {
+        Ok(result) => result,
+        Err(e) => {
+            return vec![Diagnostic::error(
+                format!("Unable to list categories and torrents on {}: {}", url, e),
+                "Check that the qBittorrent version supports the Web API v2",
+            )]
+        }
+    };
+
+    let mut diagnostics = Vec::new();
+    let mut configured: Vec<&String> = server.categories.keys().collect();
+    configured.sort();
+    for category in configured {
+        if !remote.contains_key(category) {
+            let closest = remote
+                .keys()
+                .map(|name| {
+                    (
+                        edit_distance(&category.to_lowercase(), &name.to_lowercase()),
+                        name,
+                    )
+                })
+                .filter(|(distance, _)| *distance <= 2)
+                .min();
+            let fix = match closest {
+                Some((_, name)) => format!("Did you mean {:?}? Rename the key under categories", name),
+                None => String::from(
+                    "Create the category in qBittorrent, set create_missing_categories: true, or fix the name",
+                ),
+            };
+            diagnostics.push(Diagnostic::warning(
+                format!("Category {} does not exist on {}", category, url),
+                &fix,
+            ));
+        } else if !torrents.iter().any(|torrent| &torrent.category == category) {
+            diagnostics.push(Diagnostic::warning(
+                format!("Category {} on {} has no torrents", category, url),
+                "Nothing is moved until torrents are assigned to it; check that this is the category you meant",
+            ));
+        }
+    }
+    diagnostics
+}
+
+/// Runs every check against `config_file`, prints the diagnostics and fails
+/// if any of them is an error.
+pub async fn run(config_file: &str) -> Result<()> {
+    let mut diagnostics = Vec::new();
+    match parse(config_file) {
+        Ok(LoadedConfig {
+            mut config,
+            warnings,
+            ..
+        }) => {
+            for warning in warnings {
+                let fix = if warning.contains("migrate-config") {
+                    "Run `qbittorrent_mover migrate-config`"
+                } else if warning.contains("verification is disabled") {
+                    "Trust the server with tls.ca_file or tls.fingerprint instead of tls.insecure_skip_verify"
+                } else {
+                    "Remove the key or correct its spelling"
+                };
+                diagnostics.push(Diagnostic::warning(warning, fix));
+            }
+            for server in &mut config.servers {
+                if let Err(e) = server.resolve_password() {
+                    diagnostics.push(Diagnostic::error(
+                        e.to_string(),
+                        "Use exactly one of password, password_env and password_file, and make sure the variable is set or the file is readable",
+                    ));
+                }
+            }
+            diagnostics.extend(check_settings(&config));
+            diagnostics.extend(check_destinations(&config));
+            for server in &config.servers {
+                diagnostics.extend(check_server(server).await);
+            }
+        }
+        Err(diagnostic) => diagnostics.push(diagnostic),
+    }
+
+    for diagnostic in &diagnostics {
+        println!("{}", diagnostic);
+    }
+    let errors = diagnostics
+        .iter()
+        .filter(|diagnostic| diagnostic.severity == Severity::Error)
+        .count();
+    let warnings = diagnostics.len() - errors;
+    if errors > 0 {
+        return Err(anyhow::anyhow!(
+            "{} has {} errors and {} warnings",
+            config_file,
+            errors,
+            warnings
+        ));
+    }
+    println!("{} is valid ({} warnings)", config_file, warnings);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BandwidthWindow;
+    use mockito::Server;
+    use std::collections::HashMap;
+    use std::fs;
+
+    #[test]
+    fn test_parse_reports_position() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let filename = dir.path().join("config.yaml");
+        let filename = filename.to_str().unwrap();
+        fs::write(
+            filename,
+            "servers: []\nrate_limit_delay: 5\nlog_file: mover.log\nmax_log_file_size: 10M\nmax_concurrent_moves: two\n",
+        )?;
+
+        let diagnostic = parse(filename).unwrap_err();
+        assert_eq!(diagnostic.severity, Severity::Error);
+        assert!(diagnostic
+            .location
+            .as_deref()
+            .unwrap()
+            .starts_with(&format!("{}:5:", filename)));
+        assert!(diagnostic.fix.contains("expected type"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_units() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let filename = dir.path().join("config.yaml");
+        let filename = filename.to_str().unwrap();
+        fs::write(
+            filename,
+            "servers: []\nrate_limit_delay: 5\nlog_file: mover.log\nmax_log_file_size: 10M\nfree_space_reserve: lots\n",
+        )?;
+        let diagnostic = parse(filename).unwrap_err();
+        assert!(diagnostic.fix.contains("1.5GiB"), "{}", diagnostic);
+
+        fs::write(
+            filename,
+            "servers: []\nrate_limit_delay: 5 minutes\nlog_file: mover.log\nmax_log_file_size: 10M\n",
+        )?;
+        let diagnostic = parse(filename).unwrap_err();
+        assert!(diagnostic.fix.contains("1h30m"), "{}", diagnostic);
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_settings() {
+        let mut config = Config::default();
+        config.bandwidth.global.schedule.push(BandwidthWindow {
+            from: String::from("25:00"),
+            to: String::from("07:00"),
+            limit: None,
+        });
+        let diagnostics = check_settings(&config);
+        assert_eq!(diagnostics.len(), 2);
+        assert!(diagnostics[0].message.contains("No servers"));
+
+        config.servers.push(ServerConfig::default());
+        let diagnostics = check_settings(&config);
+        assert_eq!(diagnostics.len(), 2);
+        assert!(diagnostics[0].message.contains("no download_root"));
+    }
+
+    #[test]
+    fn test_check_destinations() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file = dir.path().join("file");
+        fs::write(&file, b"")?;
+        let config = Config {
+            servers: vec![ServerConfig {
+                categories: HashMap::from([
+                    (
+                        String::from("ok"),
+                        dir.path().to_string_lossy().into_owned(),
+                    ),
+                    (String::from("file"), file.to_string_lossy().into_owned()),
+                    (
+                        String::from("missing"),
+                        dir.path().join("missing").to_string_lossy().into_owned(),
+                    ),
+                ]),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let diagnostics = check_destinations(&config);
+        assert_eq!(diagnostics.len(), 2);
+        assert!(diagnostics[0].message.contains("not a directory"));
+        assert!(diagnostics[1].message.contains("does not exist"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("movies", "movies"), 0);
+        assert_eq!(edit_distance("moveis", "movies"), 2);
+        assert_eq!(edit_distance("tv", "movies"), 5);
+    }
+
+    #[tokio::test]
+    async fn test_check_server_categories() {
+        let mut server = Server::new();
+        let _version = server
+            .mock("GET", "/api/v2/app/version")
+            .with_status(200)
+            .with_body("v4.6.0")
+            .create();
+        let _api = server
+            .mock("GET", "/api/v2/app/webapiVersion")
+            .with_status(200)
+            .with_body("2.9.3")
+            .create();
+        let _categories = server
+            .mock("GET", "/api/v2/torrents/categories")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"movies": {"name": "movies", "savePath": ""}, "tv": {"name": "tv", "savePath": ""}}"#)
+            .create();
+        let _torrents = server
+            .mock("GET", "/api/v2/torrents/info")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"[{"save_path": "/downloads", "name": "Show", "category": "tv", "hash": "a"}]"#,
+            )
+            .create();
+
+        let diagnostics = check_server(&ServerConfig {
+            qbit_url: server.url(),
+            categories: HashMap::from([
+                (String::from("tv"), String::from("/media/tv")),
+                (String::from("movies"), String::from("/media/movies")),
+                (String::from("moveis"), String::from("/media/movies")),
+            ]),
+            ..Default::default()
+        })
+        .await;
+
+        assert_eq!(diagnostics.len(), 2);
+        assert!(diagnostics[0].message.contains("moveis does not exist"));
+        assert!(diagnostics[0].fix.contains("\"movies\""));
+        assert!(diagnostics[1].message.contains("movies on"));
+        assert!(diagnostics[1].message.contains("has no torrents"));
+    }
+
+    #[tokio::test]
+    async fn test_check_server_rejected_credentials() {
+        let mut server = Server::new();
+        let _version = server
+            .mock("GET", "/api/v2/app/version")
+            .with_status(403)
+            .create();
+
+        let diagnostics = check_server(&ServerConfig {
+            qbit_url: server.url(),
+            ..Default::default()
+        })
+        .await;
+
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0]
+            .message
+            .contains("rejected the configured credentials"));
+    }
+
+    #[tokio::test]
+    async fn test_check_server_unsupported_api() {
+        let mut server = Server::new();
+        let _version = server
+            .mock("GET", "/api/v2/app/version")
+            .with_status(200)
+            .with_body("v9.0.0")
+            .create();
+        let _api = server
+            .mock("GET", "/api/v2/app/webapiVersion")
+            .with_status(200)
+            .with_body("3.0.0")
+            .create();
+
+        let diagnostics = check_server(&ServerConfig {
+            qbit_url: server.url(),
+            ..Default::default()
+        })
+        .await;
+
+        assert_eq!(diagnostics.len(), 1);
+        assert!(diagnostics[0]
+            .message
+            .contains("Web API 3.0.0 is not supported"));
+    }
+}
@@ -0,0 +1,300 @@
+/*
+qBittorrent Mover - A tool to automatically move torrents to different categories based on their state.
+Copyright (C) 2023 Harrison Chin
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use anyhow::Result;
+
+pub const USAGE: &str = "\
+Usage: qbittorrent_mover [--config FILE] [COMMAND]
+
+Options:
+  -c, --config FILE  Config file to use instead of searching for one
+                     (also QBIT_MOVER_CONFIG)
+
+Commands:
+  run           Watch the configured servers and move completed torrents (default)
+  status        Show what the running daemon is currently doing
+  failed        List the torrents quarantined after repeated failures
+  retry HASH    Clear a torrent's failures so that it is tried again
+  ignore HASH   Stop trying to move a torrent that keeps failing
+  init          Create the config file interactively
+                  --force        Replace an existing config file
+  check-config  Validate the config file, destinations and servers, and suggest fixes
+  migrate-config
+                Upgrade the config files to the current layout, keeping backups
+  convert-config OUTPUT
+                Write the config file to OUTPUT in the format of its extension
+                (.yaml, .yml, .toml or .json)
+                  --force        Replace an existing OUTPUT
+  detect-paths  Propose path mappings by comparing qBittorrent's paths with local files
+                  --search DIR   Directory to look for torrent files in (repeatable)
+                  --write        Save the proposed mappings to the config file
+";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Run,
+    Status,
+    Failed,
+    Retry { hash: String },
+    Ignore { hash: String },
+    Init { force: bool },
+    CheckConfig,
+    MigrateConfig,
+    ConvertConfig { output: String, force: bool },
+    DetectPaths { write: bool, search: Vec<String> },
+    Help,
+}
+
+fn option_value(args: &mut impl Iterator<Item = String>, option: &str) -> Result<String> {
+    args.next()
+        .ok_or_else(|| anyhow::anyhow!("Missing value for {}\n\n{}", option, USAGE))
+}
+
+/// The parsed command line: the command to run and the config file given
+/// with `--config`, if any.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Args {
+    pub command: Command,
+    pub config: Option<String>,
+}
+
+fn parse_command(name: &str) -> Result<Command> {
+    Ok(match name {
+        "run" => Command::Run,
+        "status" => Command::Status,
+        "failed" => Command::Failed,
+        "retry" => Command::Retry {
+            hash: String::new(),
+        },
+        "ignore" => Command::Ignore {
+            hash: String::new(),
+        },
+        "init" => Command::Init { force: false },
+        "check-config" => Command::CheckConfig,
+        "migrate-config" => Command::MigrateConfig,
+        "convert-config" => Command::ConvertConfig {
+            output: String::new(),
+            force: false,
+        },
+        "detect-paths" => Command::DetectPaths {
+            write: false,
+            search: Vec::new(),
+        },
+        "help" | "-h" | "--help" => Command::Help,
+        other => return Err(anyhow::anyhow!("Unknown command: {}\n\n{}", other, USAGE)),
+    })
+}
+
+pub fn parse_args<I>(args: I) -> Result<Args>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut args = args.into_iter().skip(1);
+    let mut command = None;
+    let mut config = None;
+
+    while let Some(arg) = args.next() {
+        if arg == "--config" || arg == "-c" {
+            config = Some(option_value(&mut args, &arg)?);
+            continue;
+        }
+        match (&mut command, arg.as_str()) {
+            (None, name) => command = Some(parse_command(name)?),
+            (Some(Command::Init { force }), "--force") => *force = true,
+            (Some(Command::ConvertConfig { force, .. }), "--force") => *force = true,
+            (Some(Command::ConvertConfig { output, .. }), path)
+                if output.is_empty() && !path.starts_with('-') =>
+            {
+                *output = path.to_string()
+            }
+            (Some(Command::Retry { hash } | Command::Ignore { hash }), value)
+                if hash.is_empty() && !value.starts_with('-') =>
+            {
+                *hash = value.to_string()
+            }
+            (Some(Command::DetectPaths { write, .. }), "--write") => *write = true,
+            (Some(Command::DetectPaths { search, .. }), "--search") => {
+                search.push(option_value(&mut args, "--search")?)
+            }
+            _ => return Err(anyhow::anyhow!("Unexpected argument: {}\n\n{}", arg, USAGE)),
+        }
+    }
+    match &command {
+        Some(Command::ConvertConfig { output, .. }) if output.is_empty() => {
+            return Err(anyhow::anyhow!(
+                "convert-config needs an OUTPUT file\n\n{}",
+                USAGE
+            ));
+        }
+        Some(Command::Retry { hash } | Command::Ignore { hash }) if hash.is_empty() => {
+            return Err(anyhow::anyhow!("Missing torrent HASH\n\n{}", USAGE));
+        }
+        _ => {}
+    }
+    Ok(Args {
+        command: command.unwrap_or(Command::Run),
+        config,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        std::iter::once("qbittorrent_mover")
+            .chain(list.iter().copied())
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_args_defaults_to_run() {
+        assert_eq!(parse_args(args(&[])).unwrap().command, Command::Run);
+        assert_eq!(parse_args(args(&["run"])).unwrap().command, Command::Run);
+    }
+
+    #[test]
+    fn test_parse_args_status() {
+        assert_eq!(
+            parse_args(args(&["status"])).unwrap().command,
+            Command::Status
+        );
+    }
+
+    #[test]
+    fn test_parse_args_failures() {
+        assert_eq!(
+            parse_args(args(&["failed"])).unwrap().command,
+            Command::Failed
+        );
+        assert_eq!(
+            parse_args(args(&["retry", "abc123"])).unwrap().command,
+            Command::Retry {
+                hash: String::from("abc123")
+            }
+        );
+        assert_eq!(
+            parse_args(args(&["ignore", "abc123"])).unwrap().command,
+            Command::Ignore {
+                hash: String::from("abc123")
+            }
+        );
+        assert!(parse_args(args(&["retry"])).is_err());
+        assert!(parse_args(args(&["ignore", "abc", "def"])).is_err());
+        assert!(parse_args(args(&["failed", "abc"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_init() {
+        assert_eq!(
+            parse_args(args(&["init"])).unwrap().command,
+            Command::Init { force: false }
+        );
+        assert_eq!(
+            parse_args(args(&["init", "--force"])).unwrap().command,
+            Command::Init { force: true }
+        );
+    }
+
+    #[test]
+    fn test_parse_args_check_config() {
+        assert_eq!(
+            parse_args(args(&["check-config"])).unwrap().command,
+            Command::CheckConfig
+        );
+        assert!(parse_args(args(&["check-config", "--write"])).is_err());
+        assert_eq!(
+            parse_args(args(&["migrate-config"])).unwrap().command,
+            Command::MigrateConfig
+        );
+    }
+
+    #[test]
+    fn test_parse_args_convert_config() {
+        assert_eq!(
+            parse_args(args(&["convert-config", "config.toml"]))
+                .unwrap()
+                .command,
+            Command::ConvertConfig {
+                output: String::from("config.toml"),
+                force: false
+            }
+        );
+        assert_eq!(
+            parse_args(args(&["convert-config", "--force", "config.json"]))
+                .unwrap()
+                .command,
+            Command::ConvertConfig {
+                output: String::from("config.json"),
+                force: true
+            }
+        );
+        assert!(parse_args(args(&["convert-config"])).is_err());
+        assert!(parse_args(args(&["convert-config", "a.toml", "b.toml"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_detect_paths() {
+        assert_eq!(
+            parse_args(args(&["detect-paths"])).unwrap().command,
+            Command::DetectPaths {
+                write: false,
+                search: Vec::new()
+            }
+        );
+        assert_eq!(
+            parse_args(args(&[
+                "detect-paths",
+                "--search",
+                "/mnt",
+                "--write",
+                "--search",
+                "/srv"
+            ]))
+            .unwrap()
+            .command,
+            Command::DetectPaths {
+                write: true,
+                search: vec![String::from("/mnt"), String::from("/srv")]
+            }
+        );
+        assert!(parse_args(args(&["detect-paths", "--search"])).is_err());
+        assert!(parse_args(args(&["status", "--write"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_config() {
+        let parsed = parse_args(args(&["--config", "/etc/mover.yaml", "status"])).unwrap();
+        assert_eq!(parsed.command, Command::Status);
+        assert_eq!(parsed.config.as_deref(), Some("/etc/mover.yaml"));
+
+        let parsed = parse_args(args(&["run", "-c", "mover.yaml"])).unwrap();
+        assert_eq!(parsed.command, Command::Run);
+        assert_eq!(parsed.config.as_deref(), Some("mover.yaml"));
+
+        assert_eq!(parse_args(args(&[])).unwrap().config, None);
+        assert!(parse_args(args(&["--config"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_args_rejects_unknown() {
+        assert!(parse_args(args(&["frobnicate"])).is_err());
+        assert!(parse_args(args(&["status", "extra"])).is_err());
+    }
+}
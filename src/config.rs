@@ -16,29 +16,195 @@ You should have received a copy of the GNU Affero General Public License
 along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
+use super::format::{self, Format, ParseError};
+use super::merge;
+use super::migrate;
+use super::secrets::{interpolate_value, read_secret_file, Secret, REDACTED};
+use super::units::{self, ByteRate, ByteSize, HumanDuration};
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use serde_yaml;
+use serde_yaml::{Mapping, Value};
 use std::collections::HashMap;
-use std::fs::File;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
 
 pub const CONFIG_FILE: &str = "config.yaml";
+pub const CONFIG_ENV: &str = "QBIT_MOVER_CONFIG";
+const CONFIG_DIR: &str = "qbittorrent-mover";
+const ENV_PREFIX: &str = "QBIT_MOVER_";
 
 #[derive(Debug, Deserialize, Clone, Serialize)]
 pub struct Config {
     pub servers: Vec<ServerConfig>,
-    pub rate_limit_delay: u64,
+    pub rate_limit_delay: HumanDuration, // Like "30s" or "5m"; a bare number is seconds
     pub log_file: String,
-    pub max_log_file_size: String, // Size as a string, like "10MB", "1GB", etc.
+    pub max_log_file_size: ByteSize, // Like "10MiB" or "1GB"
+    #[serde(default = "default_status_file")]
+    pub status_file: String,
+    #[serde(default = "default_max_concurrent_moves")]
+    pub max_concurrent_moves: usize,
+    #[serde(default = "default_max_moves_per_device")]
+    pub max_moves_per_device: usize, // 0 disables per-device scheduling
+    #[serde(default)]
+    pub bandwidth: BandwidthConfig,
+    #[serde(default = "default_progress_interval")]
+    pub progress_interval: HumanDuration, // Between copy progress reports, 0 disables them
+    #[serde(default)]
+    pub free_space_reserve: ByteSize, // Space to keep free on destinations, like "10GiB"
+    #[serde(default)]
+    pub destinations: HashMap<String, DestinationConfig>, // Keyed by destination directory
+    #[serde(default)]
+    pub notify_command: Option<String>, // Run with `sh -c` when something needs attention
+    #[serde(default = "default_max_offline_backoff")]
+    pub max_offline_backoff: HumanDuration, // Longest wait between polls of an unreachable server
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default = "default_failures_file")]
+    pub failures_file: String,
+    #[serde(default = "default_max_failures")]
+    pub max_failures: u32, // Failed moves in a row before a torrent is quarantined, 0 never
+}
+
+/// How often to retry an operation that failed with a transient error, such
+/// as a timeout or an HTTP 503. Permanent errors are never retried.
+#[derive(Debug, Deserialize, Clone, Copy, Serialize, PartialEq)]
+pub struct RetryPolicy {
+    #[serde(default = "default_retry_attempts")]
+    pub attempts: u32, // Including the first try; 1 disables retries
+    #[serde(default = "default_retry_initial_delay")]
+    pub initial_delay: HumanDuration, // Doubled after every failed attempt
+    #[serde(default = "default_retry_max_delay")]
+    pub max_delay: HumanDuration,
+}
+
+/// Retry policies per class of operation.
+#[derive(Debug, Deserialize, Clone, Copy, Serialize, PartialEq, Default)]
+pub struct RetryConfig {
+    #[serde(default)]
+    pub api: RetryPolicy, // Calls to the qBittorrent Web API
+    #[serde(default)]
+    pub copy: RetryPolicy, // Copying torrent files to their destination
+    #[serde(default)]
+    pub delete: RetryPolicy, // Deleting the source files after a copy
+}
+
+/// Per-destination settings, matched against the category paths.
+#[derive(Debug, Deserialize, Clone, Serialize, PartialEq, Default)]
+pub struct DestinationConfig {
+    #[serde(default)]
+    pub max_bytes: Option<ByteSize>, // Quota for everything under the destination, like "2TiB"
+    #[serde(default)]
+    pub reserve: Option<ByteSize>, // Overrides free_space_reserve for this destination
+    #[serde(default)]
+    pub require_mountpoint: bool, // Pause moves unless the directory is a mounted filesystem
+    #[serde(default)]
+    pub sentinel_file: Option<String>, // Pause moves unless this file exists, relative to the destination
+}
+
+#[derive(Debug, Deserialize, Clone, Serialize, PartialEq, Default)]
+pub struct RateLimitConfig {
+    #[serde(default)]
+    pub limit: Option<ByteRate>, // Like "20MiB/s"; unset means unlimited
+    #[serde(default)]
+    pub schedule: Vec<BandwidthWindow>,
+}
+
+/// Time-of-day window that overrides the surrounding limit, e.g. full speed
+/// from "01:00" to "07:00". Windows may wrap around midnight.
+#[derive(Debug, Deserialize, Clone, Serialize, PartialEq)]
+pub struct BandwidthWindow {
+    pub from: String,
+    pub to: String,
+    #[serde(default)]
+    pub limit: Option<ByteRate>,
+}
+
+#[derive(Debug, Deserialize, Clone, Serialize, PartialEq, Default)]
+pub struct BandwidthConfig {
+    #[serde(flatten)]
+    pub global: RateLimitConfig,
+    #[serde(default)]
+    pub destinations: HashMap<String, RateLimitConfig>, // Keyed by destination directory
+}
+
+fn default_status_file() -> String {
+    String::from("qbittorrent-mover.status.json")
+}
+
+fn default_max_concurrent_moves() -> usize {
+    2
+}
+
+fn default_max_moves_per_device() -> usize {
+    1
+}
+
+fn default_progress_interval() -> HumanDuration {
+    HumanDuration::from_secs(30)
+}
+
+fn default_max_offline_backoff() -> HumanDuration {
+    HumanDuration::from_secs(600)
+}
+
+fn default_failures_file() -> String {
+    String::from("qbittorrent-mover.failures.json")
+}
+
+fn default_max_failures() -> u32 {
+    5
+}
+
+fn default_connect_timeout() -> HumanDuration {
+    HumanDuration::from_secs(10)
+}
+
+fn default_request_timeout() -> HumanDuration {
+    HumanDuration::from_secs(30)
+}
+
+fn default_retry_attempts() -> u32 {
+    3
+}
+
+fn default_retry_initial_delay() -> HumanDuration {
+    HumanDuration::from_secs(1)
+}
+
+fn default_retry_max_delay() -> HumanDuration {
+    HumanDuration::from_secs(30)
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: default_retry_attempts(),
+            initial_delay: default_retry_initial_delay(),
+            max_delay: default_retry_max_delay(),
+        }
+    }
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             servers: Vec::new(),
-            rate_limit_delay: 5,
+            rate_limit_delay: HumanDuration::from_secs(5),
             log_file: String::from("qbittorrent-mover.log"),
-            max_log_file_size: String::from("10M"),
+            max_log_file_size: ByteSize(10 << 20),
+            status_file: default_status_file(),
+            max_concurrent_moves: default_max_concurrent_moves(),
+            max_moves_per_device: default_max_moves_per_device(),
+            bandwidth: BandwidthConfig::default(),
+            progress_interval: default_progress_interval(),
+            free_space_reserve: ByteSize::default(),
+            destinations: HashMap::new(),
+            notify_command: None,
+            max_offline_backoff: default_max_offline_backoff(),
+            retry: RetryConfig::default(),
+            failures_file: default_failures_file(),
+            max_failures: default_max_failures(),
         }
     }
 }
@@ -47,10 +213,64 @@ impl Default for Config {
 pub struct ServerConfig {
     pub qbit_url: String,
     pub username: String,
-    pub password: String,
+    #[serde(default)]
+    pub password: Secret,
+    #[serde(default)]
+    pub password_env: Option<String>, // Read the password from this environment variable
+    #[serde(default)]
+    pub password_file: Option<String>, // Read the password from this file, e.g. a Docker secret
     pub categories: HashMap<String, String>,
-    pub root_path: Option<String>,
-    pub path_prefix: Option<String>,
+    #[serde(default)]
+    pub path_mappings: Vec<PathMapping>, // Longest matching remote prefix wins
+    #[serde(default)]
+    pub download_root: Option<String>, // Where unmapped torrents are; moves are refused outside of it
+    #[serde(default)]
+    pub discover_categories: bool, // Add the server's categories, using their save paths as destinations
+    #[serde(default)]
+    pub create_missing_categories: bool, // Create configured categories the server does not have
+    #[serde(default)]
+    pub poll_interval: Option<HumanDuration>, // Overrides rate_limit_delay for this server
+    #[serde(default)]
+    pub tls: TlsConfig,
+    #[serde(default)]
+    pub headers: HashMap<String, Secret>, // Sent with every request, e.g. proxy auth, Referer and Origin
+    #[serde(default)]
+    pub proxy: Option<Secret>, // Like "http://proxy:3128" or "socks5h://jump:1080"
+    #[serde(default = "default_connect_timeout")]
+    pub connect_timeout: HumanDuration, // 0 waits indefinitely
+    #[serde(default = "default_request_timeout")]
+    pub request_timeout: HumanDuration, // For a whole request and response, 0 waits indefinitely
+}
+
+/// TLS settings for a Web UI behind HTTPS. Without any of them the usual
+/// certificate checks apply.
+#[derive(Debug, Deserialize, Clone, Serialize, PartialEq, Default)]
+pub struct TlsConfig {
+    #[serde(default)]
+    pub ca_file: Option<String>, // PEM bundle of extra CAs to trust, e.g. an internal CA
+    #[serde(default)]
+    pub fingerprint: Option<String>, // SHA-256 of the server certificate, trusted whoever signed it
+    #[serde(default)]
+    pub insecure_skip_verify: bool, // Accept any certificate; only for testing
+    #[serde(default)]
+    pub client_cert: Option<String>, // PEM certificate for mutual TLS
+    #[serde(default)]
+    pub client_key: Option<String>, // PEM private key of client_cert
+}
+
+impl TlsConfig {
+    /// True when none of the settings is used, so the default client will do.
+    pub fn is_default(&self) -> bool {
+        *self == TlsConfig::default()
+    }
+}
+
+/// Translates a path as qBittorrent reports it into the path where the mover
+/// sees the same files, e.g. when qBittorrent runs in a container.
+#[derive(Debug, Deserialize, Clone, Serialize, PartialEq)]
+pub struct PathMapping {
+    pub remote: String,
+    pub local: String,
 }
 
 impl Default for ServerConfig {
@@ -58,27 +278,482 @@ impl Default for ServerConfig {
         Self {
             qbit_url: String::from("http://localhost:8080"),
             username: String::from("admin"),
-            password: String::from("adminadmin"),
+            password: Secret::new("adminadmin"),
+            password_env: None,
+            password_file: None,
             categories: HashMap::new(),
-            root_path: None,
-            path_prefix: None,
+            path_mappings: Vec::new(),
+            download_root: None,
+            discover_categories: false,
+            create_missing_categories: false,
+            poll_interval: None,
+            tls: TlsConfig::default(),
+            headers: HashMap::new(),
+            proxy: None,
+            connect_timeout: default_connect_timeout(),
+            request_timeout: default_request_timeout(),
         }
     }
 }
 
-pub fn load_config(filename: &str) -> Result<Config> {
-    let file = File::open(filename);
-    match file {
-        Ok(file) => serde_yaml::from_reader(file).map_err(|e| e.into()),
-        Err(_) => {
-            let default_config = Config::default();
-            let file = File::create(filename)?;
-            serde_yaml::to_writer(&file, &default_config)?;
-            Ok(default_config)
+impl ServerConfig {
+    /// Fills in `password` from `password_env` or `password_file`. Only one
+    /// of the three may be set.
+    pub fn resolve_password(&mut self) -> Result<()> {
+        let sources = [
+            !self.password.is_empty(),
+            self.password_env.is_some(),
+            self.password_file.is_some(),
+        ];
+        if sources.iter().filter(|set| **set).count() > 1 {
+            return Err(anyhow::anyhow!(
+                "{}: set only one of password, password_env and password_file",
+                self.qbit_url
+            ));
+        }
+
+        if let Some(name) = &self.password_env {
+            let value = std::env::var(name).map_err(|_| {
+                anyhow::anyhow!(
+                    "{}: environment variable {} from password_env is not set",
+                    self.qbit_url,
+                    name
+                )
+            })?;
+            self.password = Secret::new(value);
+        } else if let Some(path) = &self.password_file {
+            self.password =
+                read_secret_file(path).map_err(|e| anyhow::anyhow!("{}: {}", self.qbit_url, e))?;
         }
+        Ok(())
     }
 }
 
+pub fn env_lookup(name: &str) -> Option<String> {
+    std::env::var(name).ok()
+}
+
+/// The config file named by `--config` or `QBIT_MOVER_CONFIG`, along with
+/// which of the two named it.
+pub fn config_override(
+    explicit: Option<&str>,
+    lookup: &dyn Fn(&str) -> Option<String>,
+) -> Option<(PathBuf, &'static str)> {
+    match explicit {
+        Some(path) => Some((PathBuf::from(path), "--config")),
+        None => lookup(CONFIG_ENV)
+            .filter(|path| !path.is_empty())
+            .map(|path| (PathBuf::from(path), CONFIG_ENV)),
+    }
+}
+
+/// Files tried in order when no config file is named explicitly: the
+/// working directory, the XDG config directory and /etc, each with
+/// `config.yaml`, then `config.toml` and `config.json`.
+pub fn config_search_path(lookup: &dyn Fn(&str) -> Option<String>) -> Vec<PathBuf> {
+    let mut dirs = vec![PathBuf::new()];
+    let xdg_config = lookup("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| lookup("HOME").map(|home| Path::new(&home).join(".config")));
+    if let Some(dir) = xdg_config {
+        dirs.push(dir.join(CONFIG_DIR));
+    }
+    dirs.push(Path::new("/etc").join(CONFIG_DIR));
+
+    let names = [CONFIG_FILE, "config.toml", "config.json"];
+    dirs.iter()
+        .flat_map(|dir| names.iter().map(move |name| dir.join(name)))
+        .collect()
+}
+
+/// Picks the config file to load and says how it was found.
+pub fn find_config(
+    explicit: Option<&str>,
+    lookup: &dyn Fn(&str) -> Option<String>,
+) -> Result<(PathBuf, &'static str)> {
+    if let Some(found) = config_override(explicit, lookup) {
+        return Ok(found);
+    }
+    let candidates = config_search_path(lookup);
+    candidates
+        .iter()
+        .find(|path| path.is_file())
+        .map(|path| (path.clone(), "search path"))
+        .ok_or_else(|| {
+            let searched: Vec<_> = candidates.iter().map(|path| path.display().to_string()).collect();
+            anyhow::anyhow!(
+                "No config file found in {}; run `qbittorrent_mover init` to create one, or pass --config",
+                searched.join(", ")
+            )
+        })
+}
+
+/// Where an effective setting came from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    File,
+    Env(String),
+    Default,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::File => write!(f, "config file"),
+            Source::Env(name) => write!(f, "environment variable {}", name),
+            Source::Default => write!(f, "default"),
+        }
+    }
+}
+
+/// The effective value of one scalar setting and its origin. Values from the
+/// file are recorded before `${VAR}` references are replaced, and values from
+/// the environment are redacted, since either may hold a secret.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Setting {
+    pub key: String,
+    pub value: String,
+    pub source: Source,
+}
+
+fn scalar_to_string(value: &Value) -> String {
+    match value {
+        Value::Null => String::from("unset"),
+        Value::String(string) => string.clone(),
+        Value::Bool(flag) => flag.to_string(),
+        Value::Number(number) => number.to_string(),
+        other => format!("{:?}", other),
+    }
+}
+
+/// Converts an environment variable to the YAML type of the setting it
+/// overrides.
+fn env_value(name: &str, raw: &str, default: &Value) -> Result<Value> {
+    match default {
+        Value::Number(_) => raw
+            .trim()
+            .parse::<u64>()
+            .map(Value::from)
+            .map_err(|_| anyhow::anyhow!("{} must be a whole number, got {:?}", name, raw)),
+        Value::Bool(_) => raw
+            .trim()
+            .parse::<bool>()
+            .map(Value::from)
+            .map_err(|_| anyhow::anyhow!("{} must be true or false, got {:?}", name, raw)),
+        _ => Ok(Value::from(raw)),
+    }
+}
+
+/// Walks the scalar settings of `defaults`, replacing the matching value in
+/// `document` when `QBIT_MOVER_<PATH>` is set. Nested settings join their
+/// keys with underscores, e.g. `QBIT_MOVER_BANDWIDTH_LIMIT`. Lists such as
+/// `servers` are left alone. `raw` is the document before interpolation.
+fn override_mapping(
+    document: &mut Value,
+    raw: &Value,
+    defaults: &Mapping,
+    prefix: &str,
+    lookup: &dyn Fn(&str) -> Option<String>,
+    settings: &mut Vec<Setting>,
+) -> Result<()> {
+    if document.is_null() {
+        *document = Value::Mapping(Mapping::new());
+    }
+    let Some(mapping) = document.as_mapping_mut() else {
+        return Ok(());
+    };
+
+    for (key, default) in defaults {
+        let Some(name) = key.as_str() else {
+            continue;
+        };
+        let path = if prefix.is_empty() {
+            name.to_string()
+        } else {
+            format!("{}.{}", prefix, name)
+        };
+        match default {
+            Value::Sequence(_) => {}
+            Value::Mapping(nested) => {
+                let existed = mapping.contains_key(key);
+                let mut child = mapping.get(key).cloned().unwrap_or(Value::Null);
+                let raw_child = raw.get(key).unwrap_or(&Value::Null);
+                override_mapping(&mut child, raw_child, nested, &path, lookup, settings)?;
+                if existed || child.as_mapping().is_some_and(|child| !child.is_empty()) {
+                    mapping.insert(key.clone(), child);
+                }
+            }
+            scalar => {
+                let env_name = format!("{}{}", ENV_PREFIX, path.replace('.', "_").to_uppercase());
+                let (value, source) = match lookup(&env_name) {
+                    Some(env) => {
+                        mapping.insert(key.clone(), env_value(&env_name, &env, scalar)?);
+                        (REDACTED.to_string(), Source::Env(env_name))
+                    }
+                    None => match raw.get(key) {
+                        Some(value) => (scalar_to_string(value), Source::File),
+                        None => (scalar_to_string(scalar), Source::Default),
+                    },
+                };
+                settings.push(Setting {
+                    key: path,
+                    value,
+                    source,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Which step of loading the config failed, so callers can suggest a fix
+/// without reading the message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigErrorKind {
+    NotFound,     // The config file does not exist
+    Unreadable,   // A config file or the conf.d directory could not be read
+    Format,       // The file extension names no known format
+    Syntax,       // The file is not valid YAML, TOML or JSON
+    Layout,       // The file could not be upgraded to the current layout
+    Template,     // server_defaults or a template could not be applied
+    Variable,     // A ${VAR} reference could not be replaced
+    Override,     // A QBIT_MOVER_* environment variable has an invalid value
+    MissingField, // A required key is missing
+    UnknownField, // A key or variant the mover does not know
+    Size,         // A size or rate that does not parse
+    Duration,     // A duration that does not parse
+    InvalidValue, // Any other value of the wrong type
+}
+
+impl ConfigErrorKind {
+    /// Classifies an error deserializing the document into a [`Config`].
+    /// serde only passes messages along, so this relies on the wording of
+    /// serde's own errors and on the prefixes of the unit parsers.
+    fn of_schema_error(message: &str) -> Self {
+        if message.contains(units::INVALID_SIZE) {
+            Self::Size
+        } else if message.contains(units::INVALID_DURATION) {
+            Self::Duration
+        } else if message.contains("missing field `") {
+            Self::MissingField
+        } else if message.contains("unknown field `") || message.contains("unknown variant `") {
+            Self::UnknownField
+        } else {
+            Self::InvalidValue
+        }
+    }
+}
+
+/// A problem loading the config, with the file and, when known, the line and
+/// column it refers to.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub kind: ConfigErrorKind,
+    pub file: String,
+    pub position: Option<(usize, usize)>,
+    pub message: String,
+}
+
+impl ConfigError {
+    fn new(kind: ConfigErrorKind, file: &str, message: impl ToString) -> Self {
+        Self {
+            kind,
+            file: file.to_string(),
+            position: None,
+            message: message.to_string(),
+        }
+    }
+
+    fn from_parse(kind: ConfigErrorKind, file: &str, error: ParseError) -> Self {
+        Self {
+            kind,
+            file: file.to_string(),
+            position: error.position,
+            message: error.message,
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.position {
+            Some((line, column)) => {
+                write!(f, "{}:{}:{}: {}", self.file, line, column, self.message)
+            }
+            None => write!(f, "{}: {}", self.file, self.message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Reads a config file in the format its extension names.
+fn read_document(path: &str) -> std::result::Result<(String, Value), ConfigError> {
+    let format = Format::from_path(Path::new(path))
+        .map_err(|e| ConfigError::new(ConfigErrorKind::Format, path, e))?;
+    let contents = fs::read_to_string(path).map_err(|e| {
+        if e.kind() == std::io::ErrorKind::NotFound {
+            ConfigError::new(
+                ConfigErrorKind::NotFound,
+                path,
+                "not found; run `qbittorrent_mover init` to create it",
+            )
+        } else {
+            ConfigError::new(
+                ConfigErrorKind::Unreadable,
+                path,
+                format!("unable to read: {}", e),
+            )
+        }
+    })?;
+    let document = format
+        .parse(&contents)
+        .map_err(|e| ConfigError::from_parse(ConfigErrorKind::Syntax, path, e))?;
+    Ok((contents, document))
+}
+
+/// Reads one config file and upgrades it to the current layout, noting in
+/// `warnings` when it needed upgrading.
+fn read_migrated(
+    path: &str,
+    warnings: &mut Vec<String>,
+) -> std::result::Result<(String, Value), ConfigError> {
+    let (contents, mut document) = read_document(path)?;
+    let applied = migrate::migrate(&mut document)
+        .map_err(|e| ConfigError::new(ConfigErrorKind::Layout, path, e))?;
+    if !applied.is_empty() {
+        warnings.push(format!(
+            "{} uses an older config layout and was upgraded while loading; run `qbittorrent_mover migrate-config` to update the file",
+            path
+        ));
+    }
+    if let Some(mapping) = document.as_mapping_mut() {
+        mapping.remove("version");
+    }
+    Ok((contents, document))
+}
+
+/// The config files that make up the configuration: `filename` followed by
+/// the files of the `conf.d` directory next to it, in name order.
+pub fn config_files(filename: &str) -> Result<Vec<String>> {
+    let include_dir = Path::new(filename)
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(merge::INCLUDE_DIR);
+    let includes = merge::include_files(&include_dir, format::EXTENSIONS)?;
+    Ok(std::iter::once(filename.to_string())
+        .chain(
+            includes
+                .iter()
+                .map(|path| path.to_string_lossy().into_owned()),
+        )
+        .collect())
+}
+
+/// A parsed configuration, where each scalar setting came from, and
+/// warnings about the files, such as unknown keys.
+#[derive(Debug)]
+pub struct LoadedConfig {
+    pub config: Config,
+    pub settings: Vec<Setting>,
+    pub warnings: Vec<String>,
+}
+
+/// Reads the config file and merges the files of the `conf.d` directory next
+/// to it, each upgraded to the current layout. Servers then inherit from
+/// `server_defaults` and their `template`s, `${VAR}` references in every
+/// string are replaced, and `QBIT_MOVER_<SETTING>` environment variables
+/// override scalar settings.
+pub fn parse_config(
+    filename: &str,
+    lookup: &dyn Fn(&str) -> Option<String>,
+) -> std::result::Result<LoadedConfig, ConfigError> {
+    let mut warnings = Vec::new();
+    let files = config_files(filename)
+        .map_err(|e| ConfigError::new(ConfigErrorKind::Unreadable, filename, e))?;
+    let (contents, mut document) = read_migrated(filename, &mut warnings)?;
+    for include in &files[1..] {
+        let (_, overlay) = read_migrated(include, &mut warnings)?;
+        merge::merge_document(&mut document, overlay);
+    }
+
+    merge::apply_templates(&mut document)
+        .map_err(|e| ConfigError::new(ConfigErrorKind::Template, filename, e))?;
+    let raw = document.clone();
+    interpolate_value(&mut document, lookup)
+        .map_err(|e| ConfigError::new(ConfigErrorKind::Variable, filename, e))?;
+    let defaults = serde_yaml::to_value(Config::default())
+        .map_err(|e| ConfigError::new(ConfigErrorKind::InvalidValue, filename, e))?;
+    let mut settings = Vec::new();
+    if let Some(defaults) = defaults.as_mapping() {
+        override_mapping(&mut document, &raw, defaults, "", lookup, &mut settings)
+            .map_err(|e| ConfigError::new(ConfigErrorKind::Override, filename, e))?;
+    }
+
+    let mut unknown = Vec::new();
+    let config: Config =
+        serde_ignored::deserialize(document, |path| unknown.push(path.to_string())).map_err(
+            |e: serde_yaml::Error| {
+                // Errors from the merged document carry no position or key path.
+                // When the main file on its own fails the same way, use its error.
+                let raw = Format::from_path(Path::new(filename))
+                    .ok()
+                    .and_then(|format| format.deserialize::<Config>(&contents).err());
+                let kind = ConfigErrorKind::of_schema_error(&e.to_string());
+                match raw {
+                    Some(raw) if raw.message.ends_with(&e.to_string()) => {
+                        ConfigError::from_parse(kind, filename, raw)
+                    }
+                    _ => ConfigError::new(kind, filename, e),
+                }
+            },
+        )?;
+    warnings.extend(
+        unknown
+            .into_iter()
+            .map(|path| format!("Unknown key {} is ignored; check its spelling", path)),
+    );
+    warnings.extend(
+        config
+            .servers
+            .iter()
+            .filter(|server| server.tls.insecure_skip_verify)
+            .map(|server| {
+                format!(
+                    "TLS certificate verification is disabled for {}; anyone on the network path can impersonate it",
+                    server.qbit_url
+                )
+            }),
+    );
+    Ok(LoadedConfig {
+        config,
+        settings,
+        warnings,
+    })
+}
+
+/// Loads the config file, along with where each scalar setting came from and
+/// any warnings, so startup can log them.
+pub fn load_config(filename: &str) -> Result<LoadedConfig> {
+    let mut loaded = parse_config(filename, &env_lookup)?;
+    for server in &mut loaded.config.servers {
+        server.resolve_password()?;
+    }
+    Ok(loaded)
+}
+
+/// Reads the config file as a plain document, lets `edit` change it and
+/// writes it back in the same format. Unlike serializing a [`Config`], this
+/// keeps `${VAR}` references and secrets as they were written.
+pub fn edit_config_file(filename: &str, edit: impl FnOnce(&mut Value) -> Result<()>) -> Result<()> {
+    let format = Format::from_path(Path::new(filename))?;
+    let (_, mut value) = read_document(filename)?;
+    edit(&mut value)?;
+    fs::write(filename, format.render(&value)?)?;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -88,9 +763,22 @@ mod tests {
     fn test_default_config() {
         let config = Config::default();
         assert!(config.servers.is_empty());
-        assert_eq!(config.rate_limit_delay, 5);
+        assert_eq!(config.rate_limit_delay, HumanDuration::from_secs(5));
         assert_eq!(config.log_file, "qbittorrent-mover.log");
-        assert_eq!(config.max_log_file_size, "10M");
+        assert_eq!(config.max_log_file_size, ByteSize(10 << 20));
+        assert_eq!(config.status_file, "qbittorrent-mover.status.json");
+        assert_eq!(config.max_concurrent_moves, 2);
+        assert_eq!(config.max_moves_per_device, 1);
+        assert_eq!(config.bandwidth, BandwidthConfig::default());
+        assert_eq!(config.progress_interval, HumanDuration::from_secs(30));
+        assert_eq!(config.free_space_reserve, ByteSize(0));
+        assert!(config.destinations.is_empty());
+        assert_eq!(config.notify_command, None);
+        assert_eq!(config.max_offline_backoff, HumanDuration::from_secs(600));
+        assert_eq!(config.retry.copy.attempts, 3);
+        assert_eq!(config.retry.api.initial_delay, HumanDuration::from_secs(1));
+        assert_eq!(config.failures_file, "qbittorrent-mover.failures.json");
+        assert_eq!(config.max_failures, 5);
     }
 
     #[test]
@@ -98,36 +786,498 @@ mod tests {
         let server_config = ServerConfig::default();
         assert_eq!(server_config.qbit_url, "http://localhost:8080");
         assert_eq!(server_config.username, "admin");
-        assert_eq!(server_config.password, "adminadmin");
+        assert_eq!(server_config.password.expose(), "adminadmin");
+        assert_eq!(server_config.password_env, None);
+        assert_eq!(server_config.password_file, None);
         assert_eq!(server_config.categories, HashMap::new());
+        assert!(server_config.path_mappings.is_empty());
+        assert_eq!(server_config.download_root, None);
+        assert!(!server_config.discover_categories);
+        assert!(!server_config.create_missing_categories);
+        assert_eq!(server_config.poll_interval, None);
+        assert!(server_config.tls.is_default());
+        assert!(server_config.headers.is_empty());
+        assert_eq!(server_config.proxy, None);
+        assert_eq!(server_config.connect_timeout, HumanDuration::from_secs(10));
+        assert_eq!(server_config.request_timeout, HumanDuration::from_secs(30));
     }
     #[test]
     fn test_load_config() {
         let mut test_config = Config::default();
         test_config.servers.push(ServerConfig::default());
         let filename = "test_config.yaml";
-        let file = File::create(filename).expect("Failed to create file");
+        let file = fs::File::create(filename).expect("Failed to create file");
         serde_yaml::to_writer(file, &test_config).expect("Failed to write to file");
 
         let config = load_config(filename);
         assert!(config.is_ok());
-        let config = config.expect("Failed to load config");
+        let config = config.expect("Failed to load config").config;
         assert_eq!(config.servers.len(), 1);
-        assert_eq!(config.rate_limit_delay, 5);
+        assert_eq!(config.rate_limit_delay, HumanDuration::from_secs(5));
         assert_eq!(config.log_file, "qbittorrent-mover.log");
-        assert_eq!(config.max_log_file_size, "10M");
+        assert_eq!(config.max_log_file_size, ByteSize(10 << 20));
 
         fs::remove_file(filename).expect("Failed to remove file");
     }
     #[test]
-    fn test_load_config_creates_file() {
-        let filename = "test_config_create.yaml";
-        let _ = fs::remove_file(filename); // Ensure the file does not exist before the test
+    fn test_load_config_without_optional_fields() {
+        let filename = "test_config_optional.yaml";
+        fs::write(
+            filename,
+            "servers: []\nrate_limit_delay: 5\nlog_file: mover.log\nmax_log_file_size: 10M\n",
+        )
+        .expect("Failed to write to file");
 
-        let config = load_config(filename);
-        assert!(config.is_ok(), "Failed to load config");
-        assert!(fs::metadata(filename).is_ok(), "File was not created");
+        let config = load_config(filename).expect("Failed to load config").config;
+        assert_eq!(config.status_file, "qbittorrent-mover.status.json");
+        assert_eq!(config.max_concurrent_moves, 2);
+        assert_eq!(config.max_moves_per_device, 1);
+
+        fs::remove_file(filename).expect("Failed to remove file");
+    }
+
+    #[test]
+    fn test_load_config_reports_position() {
+        let filename = "test_config_invalid.yaml";
+        fs::write(
+            filename,
+            "servers: []\nrate_limit_delay: soon\nlog_file: mover.log\nmax_log_file_size: 10M\n",
+        )
+        .expect("Failed to write to file");
+
+        let err = load_config(filename).unwrap_err().to_string();
+        fs::remove_file(filename).expect("Failed to remove file");
+        assert!(err.starts_with("test_config_invalid.yaml:2:"), "{}", err);
+        assert!(err.contains("rate_limit_delay"), "{}", err);
+        assert!(!err.contains("at line"), "{}", err);
+    }
+
+    #[test]
+    fn test_load_config_bandwidth() {
+        let filename = "test_config_bandwidth.yaml";
+        fs::write(
+            filename,
+            r#"
+servers: []
+rate_limit_delay: 5
+log_file: mover.log
+max_log_file_size: 10M
+bandwidth:
+  limit: 20MiB/s
+  schedule:
+    - from: "01:00"
+      to: "07:00"
+  destinations:
+    /mnt/media:
+      limit: 80MiB/s
+"#,
+        )
+        .expect("Failed to write to file");
+
+        let config = load_config(filename).expect("Failed to load config").config;
+        assert_eq!(
+            config.bandwidth.global.limit,
+            Some("20MiB/s".parse().unwrap())
+        );
+        assert_eq!(config.bandwidth.global.schedule.len(), 1);
+        assert_eq!(config.bandwidth.global.schedule[0].limit, None);
+        assert_eq!(
+            config.bandwidth.destinations["/mnt/media"].limit,
+            Some("80MiB/s".parse().unwrap())
+        );
 
         fs::remove_file(filename).expect("Failed to remove file");
     }
+
+    #[test]
+    fn test_load_config_secrets() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let password_file = dir.path().join("password");
+        fs::write(&password_file, "from-file\n")?;
+        std::env::set_var("QBIT_MOVER_TEST_HOST", "nas");
+        std::env::set_var("QBIT_MOVER_TEST_PASSWORD", "from-env");
+
+        let filename = dir.path().join("config.yaml");
+        fs::write(
+            &filename,
+            format!(
+                r#"
+servers:
+  - qbit_url: "http://${{QBIT_MOVER_TEST_HOST}}:8080"
+    username: admin
+    password_env: QBIT_MOVER_TEST_PASSWORD
+    categories: {{}}
+  - qbit_url: "http://other:8080"
+    username: admin
+    password_file: "{}"
+    categories: {{}}
+rate_limit_delay: 5
+log_file: mover.log
+max_log_file_size: 10M
+"#,
+                password_file.display()
+            ),
+        )?;
+
+        let config = load_config(filename.to_str().unwrap())?.config;
+        assert_eq!(config.servers[0].qbit_url, "http://nas:8080");
+        assert_eq!(config.servers[0].password.expose(), "from-env");
+        assert_eq!(config.servers[1].password.expose(), "from-file");
+        assert!(!format!("{:?}", config).contains("from-env"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_config_env_overrides() -> Result<()> {
+        let lookup = |name: &str| match name {
+            "QBIT_MOVER_RATE_LIMIT_DELAY" => Some(String::from("30")),
+            "QBIT_MOVER_BANDWIDTH_LIMIT" => Some(String::from("5MiB/s")),
+            "QBIT_MOVER_LOG_FILE" => Some(String::from("/var/log/mover.log")),
+            "STATE_DIR" => Some(String::from("/var/lib/mover")),
+            _ => None,
+        };
+        let dir = tempfile::tempdir()?;
+        let filename = dir.path().join("config.yaml");
+        let filename = filename.to_str().unwrap();
+        fs::write(
+            filename,
+            "servers: []\nlog_file: mover.log\nmax_log_file_size: 10M\nstatus_file: \"${STATE_DIR}/status.json\"\n",
+        )?;
+        let LoadedConfig {
+            config, settings, ..
+        } = parse_config(filename, &lookup)?;
+
+        assert_eq!(config.rate_limit_delay, HumanDuration::from_secs(30));
+        assert_eq!(config.log_file, "/var/log/mover.log");
+        assert_eq!(
+            config.bandwidth.global.limit,
+            Some("5MiB/s".parse().unwrap())
+        );
+
+        let source = |key: &str| {
+            settings
+                .iter()
+                .find(|setting| setting.key == key)
+                .map(|setting| setting.source.clone())
+        };
+        assert_eq!(
+            source("rate_limit_delay"),
+            Some(Source::Env(String::from("QBIT_MOVER_RATE_LIMIT_DELAY")))
+        );
+        assert_eq!(source("max_log_file_size"), Some(Source::File));
+        assert_eq!(source("failures_file"), Some(Source::Default));
+        assert_eq!(config.status_file, "/var/lib/mover/status.json");
+
+        // Neither interpolated nor environment values end up in the log
+        let value = |key: &str| {
+            settings
+                .iter()
+                .find(|setting| setting.key == key)
+                .map(|setting| setting.value.as_str())
+        };
+        assert_eq!(value("status_file"), Some("${STATE_DIR}/status.json"));
+        assert_eq!(value("log_file"), Some(REDACTED));
+        assert_eq!(value("max_log_file_size"), Some("10M"));
+
+        fs::write(
+            filename,
+            "servers: []\nlog_file: mover.log\nmax_log_file_size: 10M\n",
+        )?;
+        assert_eq!(
+            source("bandwidth.limit"),
+            Some(Source::Env(String::from("QBIT_MOVER_BANDWIDTH_LIMIT")))
+        );
+
+        let bad =
+            |name: &str| (name == "QBIT_MOVER_MAX_CONCURRENT_MOVES").then(|| String::from("two"));
+        let err = parse_config(filename, &bad).unwrap_err().to_string();
+        assert!(
+            err.contains("QBIT_MOVER_MAX_CONCURRENT_MOVES must be a whole number"),
+            "{}",
+            err
+        );
+        let bad =
+            |name: &str| (name == "QBIT_MOVER_RATE_LIMIT_DELAY").then(|| String::from("soon"));
+        let err = parse_config(filename, &bad).unwrap_err().to_string();
+        assert!(err.contains("Invalid duration \"soon\""), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_config_includes_and_templates() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let filename = dir.path().join("config.yaml");
+        fs::write(
+            &filename,
+            r#"
+templates:
+  media:
+    username: admin
+    password: secret
+    categories:
+      movies: /media/movies
+      tv: /media/tv
+servers:
+  - qbit_url: "http://one:8080"
+    template: media
+rate_limit_delay: 5
+log_file: mover.log
+max_log_file_size: 10M
+"#,
+        )?;
+        let conf_d = dir.path().join("conf.d");
+        fs::create_dir(&conf_d)?;
+        fs::write(
+            conf_d.join("20-three.yaml"),
+            "servers:\n  - qbit_url: \"http://three:8080\"\n    template: media\n",
+        )?;
+        fs::write(
+            conf_d.join("10-two.yaml"),
+            "servers:\n  - qbit_url: \"http://two:8080\"\n    template: media\n    categories:\n      tv: /media/kids-tv\nrate_limit_delay: 10\n",
+        )?;
+
+        let config = load_config(filename.to_str().unwrap())?.config;
+        let urls: Vec<_> = config
+            .servers
+            .iter()
+            .map(|server| server.qbit_url.as_str())
+            .collect();
+        assert_eq!(
+            urls,
+            vec!["http://one:8080", "http://two:8080", "http://three:8080"]
+        );
+        assert_eq!(config.rate_limit_delay, HumanDuration::from_secs(10));
+        assert_eq!(config.servers[1].password.expose(), "secret");
+        assert_eq!(config.servers[1].categories["movies"], "/media/movies");
+        assert_eq!(config.servers[1].categories["tv"], "/media/kids-tv");
+        assert_eq!(config.servers[2].categories["tv"], "/media/tv");
+
+        // Syntax errors point at the included file
+        fs::write(conf_d.join("30-broken.yaml"), "servers: [\n")?;
+        let err = load_config(filename.to_str().unwrap())
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("30-broken.yaml:"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_config_toml_and_json() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let filename = dir.path().join("config.toml");
+        let filename = filename.to_str().unwrap();
+        fs::write(
+            filename,
+            r#"
+rate_limit_delay = 5
+log_file = "mover.log"
+max_log_file_size = "10M"
+
+[[servers]]
+qbit_url = "http://one:8080"
+username = "admin"
+categories = { movies = "/media/movies" }
+"#,
+        )?;
+        let conf_d = dir.path().join("conf.d");
+        fs::create_dir(&conf_d)?;
+        fs::write(
+            conf_d.join("10-two.json"),
+            r#"{"servers": [{"qbit_url": "http://two:8080", "username": "admin", "categories": {}}]}"#,
+        )?;
+
+        let config = load_config(filename)?.config;
+        assert_eq!(config.servers.len(), 2);
+        assert_eq!(config.servers[0].categories["movies"], "/media/movies");
+        assert_eq!(config.servers[1].qbit_url, "http://two:8080");
+        assert_eq!(config.max_concurrent_moves, default_max_concurrent_moves());
+
+        // Validation errors point into the TOML file as well
+        fs::write(filename, "rate_limit_delay = \"soon\"\nservers = []\n")?;
+        let err = load_config(filename).unwrap_err().to_string();
+        assert!(err.contains("config.toml:1:"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_config_migrates_and_warns() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let filename = dir.path().join("config.yaml");
+        fs::write(
+            &filename,
+            r#"
+servers:
+  - qbit_url: "http://one:8080"
+    username: admin
+    path_prefix: /downloads
+    root_path: /srv/downloads
+    categories: {}
+    catgories: {}
+rate_limit_delay: 5
+log_file: mover.log
+max_log_file_size: 10M
+notfy_command: "true"
+"#,
+        )?;
+
+        let loaded = load_config(filename.to_str().unwrap())?;
+        let mappings = &loaded.config.servers[0].path_mappings;
+        assert_eq!(mappings.len(), 1);
+        assert_eq!(mappings[0].remote, "/downloads");
+        assert_eq!(mappings[0].local, "/srv/downloads");
+        assert_eq!(loaded.warnings.len(), 3, "{:?}", loaded.warnings);
+        assert!(loaded.warnings[0].contains("migrate-config"));
+        assert!(loaded.warnings[1].contains("servers.0.catgories"));
+        assert!(loaded.warnings[2].contains("notfy_command"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_config_tls() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let filename = dir.path().join("config.yaml");
+        fs::write(
+            &filename,
+            r#"
+servers:
+  - qbit_url: "https://one:8080"
+    username: admin
+    categories: {}
+    tls:
+      ca_file: /etc/ssl/internal-ca.pem
+  - qbit_url: "https://two:8080"
+    username: admin
+    categories: {}
+    tls:
+      insecure_skip_verify: true
+rate_limit_delay: 5
+log_file: mover.log
+max_log_file_size: 10M
+"#,
+        )?;
+
+        let loaded = load_config(filename.to_str().unwrap())?;
+        let servers = &loaded.config.servers;
+        assert_eq!(
+            servers[0].tls.ca_file.as_deref(),
+            Some("/etc/ssl/internal-ca.pem")
+        );
+        assert!(!servers[0].tls.is_default());
+        assert!(servers[1].tls.insecure_skip_verify);
+        assert_eq!(loaded.warnings.len(), 1, "{:?}", loaded.warnings);
+        assert!(loaded.warnings[0].contains("https://two:8080"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_config() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let xdg = dir.path().to_string_lossy().into_owned();
+        let lookup = |name: &str| (name == "XDG_CONFIG_HOME").then(|| xdg.clone());
+
+        let search_path = config_search_path(&lookup);
+        assert_eq!(search_path[0], Path::new(CONFIG_FILE));
+        assert_eq!(search_path[1], Path::new("config.toml"));
+        assert_eq!(
+            search_path[3],
+            dir.path().join("qbittorrent-mover").join("config.yaml")
+        );
+        assert_eq!(
+            search_path[8],
+            Path::new("/etc/qbittorrent-mover/config.json")
+        );
+
+        assert_eq!(
+            find_config(Some("mover.yaml"), &lookup)?,
+            (PathBuf::from("mover.yaml"), "--config")
+        );
+        let with_env = |name: &str| (name == CONFIG_ENV).then(|| String::from("/srv/mover.yaml"));
+        assert_eq!(
+            find_config(None, &with_env)?,
+            (PathBuf::from("/srv/mover.yaml"), CONFIG_ENV)
+        );
+
+        fs::create_dir_all(dir.path().join("qbittorrent-mover"))?;
+        fs::write(&search_path[4], "servers = []\n")?;
+        if search_path[..3].iter().all(|path| !path.exists()) {
+            assert_eq!(
+                find_config(None, &lookup)?,
+                (search_path[4].clone(), "search path")
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_password_conflict() {
+        let mut server = ServerConfig {
+            password_env: Some(String::from("QBIT_MOVER_TEST_UNUSED")),
+            ..Default::default()
+        };
+        assert!(server.resolve_password().is_err());
+
+        server.password_env = Some(String::from("QBIT_MOVER_TEST_UNSET"));
+        server.password = Secret::default();
+        assert!(server
+            .resolve_password()
+            .unwrap_err()
+            .to_string()
+            .contains("QBIT_MOVER_TEST_UNSET"));
+    }
+
+    #[test]
+    fn test_config_error_kinds() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let filename = dir.path().join("config.yaml");
+        let filename = filename.to_str().unwrap();
+        let kind = |contents: &str, lookup: &dyn Fn(&str) -> Option<String>| {
+            fs::write(filename, contents).unwrap();
+            parse_config(filename, lookup).unwrap_err().kind
+        };
+        let unset = |_: &str| None;
+
+        assert_eq!(kind("servers: [", &unset), ConfigErrorKind::Syntax);
+        assert_eq!(
+            kind("servers:\n  - template: missing\n", &unset),
+            ConfigErrorKind::Template
+        );
+        assert_eq!(
+            kind("log_file: \"${UNSET}\"\nservers: []\n", &unset),
+            ConfigErrorKind::Variable
+        );
+        assert_eq!(
+            kind("servers:\n  - qbit_url: http://nas\n", &unset),
+            ConfigErrorKind::MissingField
+        );
+        assert_eq!(
+            kind("servers: []\nfree_space_reserve: lots\n", &unset),
+            ConfigErrorKind::Size
+        );
+        assert_eq!(
+            kind("servers: []\nrate_limit_delay: soon\n", &unset),
+            ConfigErrorKind::Duration
+        );
+        assert_eq!(
+            kind("servers: []\nmax_failures: many\n", &unset),
+            ConfigErrorKind::InvalidValue
+        );
+        let bad =
+            |name: &str| (name == "QBIT_MOVER_MAX_CONCURRENT_MOVES").then(|| String::from("two"));
+        assert_eq!(kind("servers: []\n", &bad), ConfigErrorKind::Override);
+
+        let missing = dir.path().join("missing.yaml");
+        let err = parse_config(missing.to_str().unwrap(), &unset).unwrap_err();
+        assert_eq!(err.kind, ConfigErrorKind::NotFound);
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_config_missing_file() {
+        let filename = "test_config_missing.yaml";
+        let _ = fs::remove_file(filename); // Ensure the file does not exist before the test
+
+        let err = load_config(filename).unwrap_err();
+        assert!(err.to_string().contains("init"), "{}", err);
+        assert!(fs::metadata(filename).is_err(), "File was created");
+    }
 }
@@ -0,0 +1,440 @@
+/*
+qBittorrent Mover - A tool to automatically move torrents to different categories based on their state.
+Copyright (C) 2023 Harrison Chin
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use super::errors::conflict;
+use super::throttle::Bandwidth;
+use super::units::{format_duration, format_size};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Progress of a single copy, as reported to the log and the status file.
+#[derive(Debug, Deserialize, Clone, Serialize, PartialEq, Default)]
+pub struct CopyProgress {
+    pub bytes_copied: u64,
+    pub total_bytes: u64,
+    pub files_done: u64,
+    pub total_files: u64,
+    pub bytes_per_sec: u64,
+    pub eta_secs: Option<u64>,
+}
+
+impl fmt::Display for CopyProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {}, {}/{} files, {}/s",
+            format_size(self.bytes_copied),
+            format_size(self.total_bytes),
+            self.files_done,
+            self.total_files,
+            format_size(self.bytes_per_sec)
+        )?;
+        match self.eta_secs {
+            Some(eta) => write!(f, ", ETA {}", format_duration(Duration::from_secs(eta))),
+            None => write!(f, ", ETA unknown"),
+        }
+    }
+}
+
+/// Tracks one copy and calls `report` at most once per `interval`.
+struct Transfer<'a> {
+    root: PathBuf, // Canonical source root; symlinks must not point outside of it
+    progress: CopyProgress,
+    started: Instant,
+    last_report: Instant,
+    interval: Option<Duration>,
+    report: &'a mut dyn FnMut(&CopyProgress),
+}
+
+impl Transfer<'_> {
+    fn advance(&mut self, bytes: u64) {
+        self.progress.bytes_copied += bytes;
+        let Some(interval) = self.interval else {
+            return;
+        };
+        if self.last_report.elapsed() < interval {
+            return;
+        }
+
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let rate = if elapsed > 0.0 {
+            self.progress.bytes_copied as f64 / elapsed
+        } else {
+            0.0
+        };
+        let remaining = self
+            .progress
+            .total_bytes
+            .saturating_sub(self.progress.bytes_copied);
+        self.progress.bytes_per_sec = rate as u64;
+        self.progress.eta_secs = (rate >= 1.0).then(|| (remaining as f64 / rate).ceil() as u64);
+        self.last_report = Instant::now();
+        (self.report)(&self.progress);
+    }
+}
+
+/// Copies files and directory trees in fixed-size chunks, pausing between
+/// chunks as needed to stay within the configured bandwidth.
+#[derive(Clone)]
+pub struct Copier {
+    bandwidth: Arc<Bandwidth>,
+    progress_interval: Option<Duration>, // None disables progress reports
+}
+
+/// Returns the total size and number of files below `path`. Symlinks count
+/// as files of their own and are not followed, as in [`Copier::copy`].
+pub fn measure(path: &Path) -> Result<(u64, u64)> {
+    let metadata = fs::symlink_metadata(path)?;
+    if metadata.file_type().is_symlink() {
+        return Ok((0, 1));
+    }
+    if !metadata.is_dir() {
+        return Ok((metadata.len(), 1));
+    }
+
+    let (mut bytes, mut files) = (0, 0);
+    for entry in fs::read_dir(path)? {
+        let (entry_bytes, entry_files) = measure(&entry?.path())?;
+        bytes += entry_bytes;
+        files += entry_files;
+    }
+    Ok((bytes, files))
+}
+
+impl Copier {
+    pub fn new(bandwidth: Bandwidth, progress_interval: Option<Duration>) -> Self {
+        Self {
+            bandwidth: Arc::new(bandwidth),
+            progress_interval,
+        }
+    }
+
+    /// Copies `src` to `dest`, recursing into directories, and calls `report`
+    /// with the progress so far once per progress interval. Returns the number
+    /// of bytes copied.
+    ///
+    /// Never overwrites: fails with a conflict when `dest` already exists, so
+    /// that deleting the source afterwards cannot lose what was there.
+    pub fn copy(
+        &self,
+        src: &Path,
+        dest: &Path,
+        report: &mut dyn FnMut(&CopyProgress),
+    ) -> Result<u64> {
+        if fs::symlink_metadata(dest).is_ok() {
+            return Err(conflict(format!(
+                "Refusing to overwrite existing destination {:?}",
+                dest
+            )));
+        }
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let (total_bytes, total_files) = measure(src)?;
+        let mut transfer = Transfer {
+            root: src.canonicalize()?,
+            progress: CopyProgress {
+                total_bytes,
+                total_files,
+                ..Default::default()
+            },
+            started: Instant::now(),
+            last_report: Instant::now(),
+            interval: self.progress_interval,
+            report,
+        };
+
+        self.copy_tree(src, dest, &mut transfer)?;
+        Ok(transfer.progress.bytes_copied)
+    }
+
+    fn copy_tree(&self, src: &Path, dest: &Path, transfer: &mut Transfer) -> Result<()> {
+        let metadata = fs::symlink_metadata(src)?;
+        if metadata.file_type().is_symlink() {
+            return copy_symlink(src, dest, transfer);
+        }
+        if !metadata.is_dir() {
+            return self.copy_file(src, dest, &metadata, transfer);
+        }
+
+        fs::create_dir(dest).map_err(|e| already_exists(e, dest))?;
+        for entry in fs::read_dir(src)? {
+            let entry = entry?;
+            self.copy_tree(&entry.path(), &dest.join(entry.file_name()), transfer)?;
+        }
+        fs::set_permissions(dest, metadata.permissions())?;
+        Ok(())
+    }
+
+    fn copy_file(
+        &self,
+        src: &Path,
+        dest: &Path,
+        metadata: &Metadata,
+        transfer: &mut Transfer,
+    ) -> Result<()> {
+        let mut reader = File::open(src)?;
+        let mut writer = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(dest)
+            .map_err(|e| already_exists(e, dest))?;
+        let mut buffer = vec![0; CHUNK_SIZE];
+
+        loop {
+            let read = reader.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            writer.write_all(&buffer[..read])?;
+            self.bandwidth.throttle(dest, read as u64);
+            transfer.advance(read as u64);
+        }
+
+        writer.sync_all()?;
+        fs::set_permissions(dest, metadata.permissions())?;
+        transfer.progress.files_done += 1;
+        Ok(())
+    }
+}
+
+/// Turns a failure to create `dest` because something appeared there since
+/// the copy started into a conflict, which is never cleaned up.
+fn already_exists(error: io::Error, dest: &Path) -> anyhow::Error {
+    if error.kind() == io::ErrorKind::AlreadyExists {
+        conflict(format!(
+            "Refusing to overwrite existing destination {:?}",
+            dest
+        ))
+    } else {
+        error.into()
+    }
+}
+
+/// The path that leads from the directory `base` to `target`, both absolute.
+fn relative_path(target: &Path, base: &Path) -> PathBuf {
+    let target: Vec<_> = target.components().collect();
+    let base: Vec<_> = base.components().collect();
+    let common = target.iter().zip(&base).take_while(|(a, b)| a == b).count();
+    let mut path = PathBuf::new();
+    for _ in common..base.len() {
+        path.push("..");
+    }
+    path.extend(&target[common..]);
+    if path.as_os_str().is_empty() {
+        path.push(".");
+    }
+    path
+}
+
+/// Recreates a symlink that points inside the tree being copied, and refuses
+/// to follow one that leads out of it. The link is written relative to its
+/// own directory, so that an absolute link into the source tree does not keep
+/// pointing at the source, which is deleted after the copy.
+fn copy_symlink(src: &Path, dest: &Path, transfer: &mut Transfer) -> Result<()> {
+    let target = src
+        .canonicalize()
+        .ok()
+        .filter(|target| target.starts_with(&transfer.root));
+    let Some(target) = target else {
+        return Err(anyhow::anyhow!(
+            "Refusing to follow symlink {:?} out of {:?}",
+            src,
+            transfer.root
+        ));
+    };
+    let parent = src
+        .parent()
+        .ok_or_else(|| anyhow::anyhow!("Symlink {:?} has no parent", src))?
+        .canonicalize()?;
+
+    #[cfg(unix)]
+    std::os::unix::fs::symlink(relative_path(&target, &parent), dest)
+        .map_err(|e| already_exists(e, dest))?;
+    #[cfg(not(unix))]
+    let _ = (target, parent);
+    #[cfg(not(unix))]
+    return Err(anyhow::anyhow!("Cannot copy symlink {:?}", src));
+
+    transfer.progress.files_done += 1;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BandwidthConfig;
+    use crate::errors::{error_kind, ErrorKind};
+
+    fn copier(progress_interval: Option<Duration>) -> Result<Copier> {
+        Ok(Copier::new(
+            Bandwidth::from_config(&BandwidthConfig::default())?,
+            progress_interval,
+        ))
+    }
+
+    #[test]
+    fn test_copy_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let src = dir.path().join("src.bin");
+        let dest = dir.path().join("dest.bin");
+        let contents = vec![7u8; CHUNK_SIZE * 2 + 13];
+        fs::write(&src, &contents)?;
+
+        let copied = copier(None)?.copy(&src, &dest, &mut |_| {})?;
+        assert_eq!(copied, contents.len() as u64);
+        assert_eq!(fs::read(&dest)?, contents);
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_directory_tree() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let src = dir.path().join("src");
+        fs::create_dir_all(src.join("Season 1"))?;
+        fs::write(src.join("info.nfo"), b"hello")?;
+        fs::write(src.join("Season 1").join("episode.mkv"), b"world!")?;
+
+        let dest = dir.path().join("dest").join("src");
+        assert_eq!(copier(None)?.copy(&src, &dest, &mut |_| {})?, 11);
+        assert_eq!(fs::read(dest.join("info.nfo"))?, b"hello");
+        assert_eq!(
+            fs::read(dest.join("Season 1").join("episode.mkv"))?,
+            b"world!"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_reports_progress() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let src = dir.path().join("src");
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("a.bin"), vec![1u8; CHUNK_SIZE + 1])?;
+        fs::write(src.join("b.bin"), vec![2u8; 10])?;
+
+        let mut reports = Vec::new();
+        copier(Some(Duration::ZERO))?.copy(&src, &dir.path().join("dest"), &mut |progress| {
+            reports.push(progress.clone())
+        })?;
+
+        assert!(reports.len() >= 3);
+        let last = reports.last().unwrap();
+        assert_eq!(last.bytes_copied, CHUNK_SIZE as u64 + 11);
+        assert_eq!(last.total_bytes, CHUNK_SIZE as u64 + 11);
+        assert_eq!(last.total_files, 2);
+        assert!(last.to_string().contains("of 1.0 MiB"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_without_progress_interval_is_silent() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let src = dir.path().join("src.bin");
+        fs::write(&src, b"hello")?;
+
+        let mut reports = 0;
+        copier(None)?.copy(&src, &dir.path().join("dest.bin"), &mut |_| reports += 1)?;
+        assert_eq!(reports, 0);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_copy_symlinks() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let src = dir.path().join("src");
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("movie.mkv"), b"movie")?;
+        std::os::unix::fs::symlink("movie.mkv", src.join("link.mkv"))?;
+
+        let dest = dir.path().join("dest");
+        copier(None)?.copy(&src, &dest, &mut |_| {})?;
+        assert_eq!(
+            fs::read_link(dest.join("link.mkv"))?,
+            Path::new("movie.mkv")
+        );
+
+        fs::write(dir.path().join("secret"), b"secret")?;
+        std::os::unix::fs::symlink(dir.path().join("secret"), src.join("escape"))?;
+        let result = copier(None)?.copy(&src, &dir.path().join("dest2"), &mut |_| {});
+        assert!(result.is_err());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_copy_rewrites_absolute_symlinks() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let src = dir.path().join("src");
+        fs::create_dir_all(src.join("extras"))?;
+        fs::write(src.join("movie.mkv"), b"movie")?;
+        std::os::unix::fs::symlink(src.join("movie.mkv"), src.join("extras").join("link.mkv"))?;
+
+        let dest = dir.path().join("dest");
+        copier(None)?.copy(&src, &dest, &mut |_| {})?;
+        fs::remove_dir_all(&src)?;
+        let link = dest.join("extras").join("link.mkv");
+        assert_eq!(fs::read_link(&link)?, Path::new("../movie.mkv"));
+        assert_eq!(fs::read(&link)?, b"movie");
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_measure_does_not_follow_symlinks() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let src = dir.path().join("src");
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("movie.mkv"), b"movie")?;
+        fs::write(dir.path().join("outside.bin"), vec![0u8; 1000])?;
+        std::os::unix::fs::symlink(dir.path().join("outside.bin"), src.join("outside"))?;
+        std::os::unix::fs::symlink(&src, src.join("loop"))?;
+
+        assert_eq!(measure(&src)?, (5, 3));
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_refuses_to_overwrite() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let src = dir.path().join("src");
+        fs::create_dir_all(&src)?;
+        fs::write(src.join("movie.mkv"), b"new")?;
+        let dest = dir.path().join("dest");
+        fs::create_dir_all(&dest)?;
+        fs::write(dest.join("movie.mkv"), b"existing")?;
+
+        for dest in [dest.clone(), dest.join("movie.mkv")] {
+            let err = copier(None)?
+                .copy(&src.join("movie.mkv"), &dest, &mut |_| {})
+                .unwrap_err();
+            assert_eq!(error_kind(&err), ErrorKind::Conflict);
+        }
+        assert_eq!(fs::read(dest.join("movie.mkv"))?, b"existing");
+        Ok(())
+    }
+}
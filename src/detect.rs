@@ -0,0 +1,309 @@
+/*
+qBittorrent Mover - A tool to automatically move torrents to different categories based on their state.
+Copyright (C) 2023 Harrison Chin
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use super::config::{edit_config_file, Config, PathMapping, ServerConfig};
+use super::paths::PathMapper;
+use super::torrent::{self, TorrentClient};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+const MAX_SEARCH_DEPTH: usize = 4;
+const SAMPLE_TORRENTS: usize = 5;
+
+pub const DEFAULT_SEARCH_ROOTS: &[&str] = &["/mnt", "/media", "/srv", "/data", "/downloads"];
+
+/// Proposed mappings for one server, plus notes explaining what was found.
+#[derive(Debug, Default)]
+pub struct Detection {
+    pub mappings: Vec<PathMapping>,
+    pub notes: Vec<String>,
+}
+
+/// Collects entries named `name` up to `depth` levels below `dir`, without
+/// following symlinks.
+fn find_named(dir: &Path, name: &OsStr, depth: usize, found: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if entry.file_name() == name {
+            found.push(path.clone());
+        }
+        let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+        if is_dir && depth > 1 {
+            find_named(&path, name, depth - 1, found);
+        }
+    }
+}
+
+/// Aligns a remote and a local path on their common trailing components and
+/// returns the differing prefixes as a mapping. Returns `None` when nothing
+/// lines up or when both paths are already the same.
+pub fn propose_mapping(remote: &Path, local: &Path) -> Option<PathMapping> {
+    let remote_components: Vec<Component> = remote.components().collect();
+    let local_components: Vec<Component> = local.components().collect();
+    let common = remote_components
+        .iter()
+        .rev()
+        .zip(local_components.iter().rev())
+        .take_while(|(r, l)| r == l && matches!(r, Component::Normal(_)))
+        .count();
+    if common == 0 {
+        return None;
+    }
+
+    let remote_prefix: PathBuf = remote_components[..remote_components.len() - common]
+        .iter()
+        .collect();
+    let local_prefix: PathBuf = local_components[..local_components.len() - common]
+        .iter()
+        .collect();
+    if remote_prefix == local_prefix {
+        return None;
+    }
+
+    Some(PathMapping {
+        remote: remote_prefix.to_string_lossy().into_owned(),
+        local: local_prefix.to_string_lossy().into_owned(),
+    })
+}
+
+/// Reads qBittorrent's default paths and a few torrents' content paths, looks
+/// for the same files below `search_roots` and proposes path mappings.
+pub async fn detect_paths(client: &TorrentClient, search_roots: &[PathBuf]) -> Result<Detection> {
+    let preferences = torrent::get_preferences(client).await?;
+    let torrents = torrent::get_torrents(client, SAMPLE_TORRENTS).await?;
+    let mut detection = Detection::default();
+    let mut votes: HashMap<String, HashMap<String, usize>> = HashMap::new();
+
+    for content_path in torrents.iter().filter_map(|t| t.content_path.as_deref()) {
+        let remote = Path::new(content_path);
+        if remote.exists() {
+            detection
+                .notes
+                .push(format!("{} is visible at the same path", content_path));
+            continue;
+        }
+        let Some(name) = remote.file_name() else {
+            continue;
+        };
+
+        let mut found = Vec::new();
+        for root in search_roots {
+            find_named(root, name, MAX_SEARCH_DEPTH, &mut found);
+        }
+        let proposals: Vec<PathMapping> = found
+            .iter()
+            .filter_map(|local| propose_mapping(remote, local))
+            .collect();
+        if proposals.is_empty() {
+            detection.notes.push(format!(
+                "Could not find {} below {:?}",
+                content_path, search_roots
+            ));
+        }
+        for proposal in proposals {
+            *votes
+                .entry(proposal.remote)
+                .or_default()
+                .entry(proposal.local)
+                .or_default() += 1;
+        }
+    }
+
+    // Keep the best supported local prefix for each remote prefix
+    for (remote, locals) in votes {
+        let mut locals: Vec<(String, usize)> = locals.into_iter().collect();
+        locals.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        if locals.len() > 1 {
+            detection.notes.push(format!(
+                "{} matched several local directories, picked {}",
+                remote, locals[0].0
+            ));
+        }
+        detection.mappings.push(PathMapping {
+            remote,
+            local: locals.swap_remove(0).0,
+        });
+    }
+    detection.mappings.sort_by(|a, b| a.remote.cmp(&b.remote));
+
+    let mut default_paths = vec![("Default save path", preferences.save_path)];
+    if preferences.temp_path_enabled {
+        default_paths.push(("Incomplete downloads path", preferences.temp_path));
+    }
+    let mapper = PathMapper::from_server(&ServerConfig {
+        path_mappings: detection.mappings.clone(),
+        ..Default::default()
+    });
+    for (label, remote) in default_paths {
+        let note = match mapper.map(&remote) {
+            Ok(mapped) if mapped.path.exists() => {
+                format!("{} {} is available at {:?}", label, remote, mapped.path)
+            }
+            Ok(mapped) => format!(
+                "{} {} maps to {:?}, which does not exist",
+                label, remote, mapped.path
+            ),
+            Err(_) => format!("{} {} is not covered by any mapping", label, remote),
+        };
+        detection.notes.push(note);
+    }
+
+    Ok(detection)
+}
+
+/// Runs detection for every configured server, prints the proposals and,
+/// with `write`, stores them in the config file.
+pub async fn run(config_file: &str, config: Config, write: bool, search: &[String]) -> Result<()> {
+    let search_roots: Vec<PathBuf> = if search.is_empty() {
+        DEFAULT_SEARCH_ROOTS.iter().map(PathBuf::from).collect()
+    } else {
+        search.iter().map(PathBuf::from).collect()
+    };
+
+    let mut detected = Vec::new();
+    for (index, server) in config.servers.iter().enumerate() {
+        println!("{}:", server.qbit_url);
+        let detection = match TorrentClient::new(server.clone()) {
+            Ok(client) => detect_paths(&client, &search_roots).await,
+            Err(e) => Err(e),
+        };
+        let detection = match detection {
+            Ok(detection) => detection,
+            Err(e) => {
+                println!("  Detection failed: {}", e);
+                continue;
+            }
+        };
+
+        for note in &detection.notes {
+            println!("  {}", note);
+        }
+        if detection.mappings.is_empty() {
+            println!("  No path mappings needed or found");
+            continue;
+        }
+        println!("  Proposed path_mappings:");
+        for mapping in &detection.mappings {
+            println!("    - remote: {:?}", mapping.remote);
+            println!("      local: {:?}", mapping.local);
+        }
+        detected.push((index, detection.mappings));
+    }
+
+    if write && !detected.is_empty() {
+        edit_config_file(config_file, |document| {
+            for (index, mappings) in detected {
+                // Servers from conf.d files come after the main file's own
+                let Some(server) = document
+                    .get_mut("servers")
+                    .and_then(|servers| servers.get_mut(index))
+                    .and_then(|server| server.as_mapping_mut())
+                else {
+                    println!(
+                        "Not saving mappings for server {}: it is defined in an included file",
+                        index + 1
+                    );
+                    continue;
+                };
+                server.insert("path_mappings".into(), serde_yaml::to_value(mappings)?);
+                server.remove("path_prefix");
+                server.remove("root_path");
+            }
+            Ok(())
+        })?;
+        println!("Saved path mappings to {}", config_file);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::Server;
+
+    #[test]
+    fn test_propose_mapping() {
+        let mapping = propose_mapping(
+            Path::new("/downloads/movies/Some Movie"),
+            Path::new("/srv/qbittorrent/data/movies/Some Movie"),
+        )
+        .unwrap();
+        assert_eq!(mapping.remote, "/downloads");
+        assert_eq!(mapping.local, "/srv/qbittorrent/data");
+
+        assert!(propose_mapping(Path::new("/a/b"), Path::new("/a/b")).is_none());
+        assert!(propose_mapping(Path::new("/a/b"), Path::new("/c/d")).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_detect_paths() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let local_downloads = dir.path().join("volumes").join("data");
+        fs::create_dir_all(local_downloads.join("movies").join("Some Movie"))?;
+        fs::create_dir_all(local_downloads.join("tv"))?;
+        fs::write(local_downloads.join("tv").join("episode.mkv"), b"")?;
+
+        let mut server = Server::new();
+        let _preferences = server
+            .mock("GET", "/api/v2/app/preferences")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"save_path": "/qbt-test/downloads", "temp_path": "/qbt-test/incomplete", "temp_path_enabled": true}"#)
+            .create();
+        let _torrents = server
+            .mock("GET", "/api/v2/torrents/info?limit=5")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(
+                r#"[
+                {"save_path": "/qbt-test/downloads/movies", "name": "Some Movie", "category": "movies", "hash": "a", "content_path": "/qbt-test/downloads/movies/Some Movie"},
+                {"save_path": "/qbt-test/downloads/tv", "name": "episode.mkv", "category": "tv", "hash": "b", "content_path": "/qbt-test/downloads/tv/episode.mkv"}
+            ]"#,
+            )
+            .create();
+
+        let client = TorrentClient::new(ServerConfig {
+            qbit_url: server.url(),
+            ..Default::default()
+        })?;
+        let detection = detect_paths(&client, &[dir.path().to_path_buf()]).await?;
+
+        assert_eq!(
+            detection.mappings,
+            vec![PathMapping {
+                remote: String::from("/qbt-test/downloads"),
+                local: local_downloads.to_string_lossy().into_owned(),
+            }]
+        );
+        assert!(detection
+            .notes
+            .iter()
+            .any(|note| note.contains("Default save path") && note.contains("is available")));
+        assert!(detection
+            .notes
+            .iter()
+            .any(|note| note.contains("/qbt-test/incomplete is not covered")));
+        Ok(())
+    }
+}
@@ -0,0 +1,176 @@
+/*
+qBittorrent Mover - A tool to automatically move torrents to different categories based on their state.
+Copyright (C) 2023 Harrison Chin
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use super::retry::HttpStatusError;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+
+/// What a failure was about, coarse enough to count in a cycle report and to
+/// decide what to look at first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    Auth,         // qBittorrent rejected the credentials
+    Connectivity, // The server could not be reached or failed to answer
+    ApiSchema,    // The server answered with something the mover does not understand
+    PathMapping,  // A torrent's path could not be mapped to a safe local path
+    Io,           // Reading, copying or deleting files failed
+    Conflict,     // The move would clash with a destination or another path
+    Other,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ErrorKind::Auth => "authentication",
+            ErrorKind::Connectivity => "connectivity",
+            ErrorKind::ApiSchema => "API schema",
+            ErrorKind::PathMapping => "path mapping",
+            ErrorKind::Io => "I/O",
+            ErrorKind::Conflict => "conflict",
+            ErrorKind::Other => "other",
+        })
+    }
+}
+
+/// An error raised where its kind is known up front. Errors coming from
+/// reqwest, serde or the filesystem are sorted by [`error_kind`] instead.
+#[derive(Debug)]
+pub struct MoverError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl fmt::Display for MoverError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for MoverError {}
+
+pub fn path_mapping(message: String) -> anyhow::Error {
+    MoverError {
+        kind: ErrorKind::PathMapping,
+        message,
+    }
+    .into()
+}
+
+pub fn api_schema(message: String) -> anyhow::Error {
+    MoverError {
+        kind: ErrorKind::ApiSchema,
+        message,
+    }
+    .into()
+}
+
+pub fn conflict(message: String) -> anyhow::Error {
+    MoverError {
+        kind: ErrorKind::Conflict,
+        message,
+    }
+    .into()
+}
+
+fn classify_status(status: StatusCode) -> ErrorKind {
+    match status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => ErrorKind::Auth,
+        StatusCode::CONFLICT => ErrorKind::Conflict,
+        status if status.is_client_error() => ErrorKind::ApiSchema,
+        _ => ErrorKind::Connectivity,
+    }
+}
+
+/// Sorts an error by the first cause in its chain that says what it is.
+/// Whether to retry it is up to [`crate::retry::retry_class`].
+pub fn error_kind(error: &anyhow::Error) -> ErrorKind {
+    for cause in error.chain() {
+        if let Some(e) = cause.downcast_ref::<MoverError>() {
+            return e.kind;
+        }
+        if let Some(e) = cause.downcast_ref::<HttpStatusError>() {
+            return classify_status(e.status);
+        }
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            return match e.status() {
+                Some(status) => classify_status(status),
+                None if e.is_decode() => ErrorKind::ApiSchema,
+                None => ErrorKind::Connectivity,
+            };
+        }
+        if cause.is::<serde_json::Error>() {
+            return ErrorKind::ApiSchema;
+        }
+        if cause.is::<io::Error>() {
+            return ErrorKind::Io;
+        }
+    }
+    ErrorKind::Other
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status_error(status: StatusCode) -> anyhow::Error {
+        HttpStatusError {
+            url: String::from("http://qbittorrent"),
+            status,
+            retry_after: None,
+        }
+        .into()
+    }
+
+    #[test]
+    fn test_error_kind() {
+        assert_eq!(
+            error_kind(&status_error(StatusCode::FORBIDDEN)),
+            ErrorKind::Auth
+        );
+        assert_eq!(
+            error_kind(&status_error(StatusCode::NOT_FOUND)),
+            ErrorKind::ApiSchema
+        );
+        assert_eq!(
+            error_kind(&status_error(StatusCode::BAD_GATEWAY)),
+            ErrorKind::Connectivity
+        );
+        assert_eq!(
+            error_kind(&serde_json::from_str::<u32>("[]").unwrap_err().into()),
+            ErrorKind::ApiSchema
+        );
+        assert_eq!(
+            error_kind(
+                &anyhow::Error::from(io::Error::from(io::ErrorKind::NotFound)).context("Copying")
+            ),
+            ErrorKind::Io
+        );
+        assert_eq!(
+            error_kind(&path_mapping(String::from("No path mapping covers /x"))),
+            ErrorKind::PathMapping
+        );
+        assert_eq!(
+            error_kind(&conflict(String::from("Refusing"))),
+            ErrorKind::Conflict
+        );
+        assert_eq!(error_kind(&anyhow::anyhow!("Something")), ErrorKind::Other);
+    }
+}
@@ -0,0 +1,277 @@
+/*
+qBittorrent Mover - A tool to automatically move torrents to different categories based on their state.
+Copyright (C) 2023 Harrison Chin
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use super::notifier::Notifier;
+use super::torrent::Torrent;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Failed moves of one torrent since its last success.
+#[derive(Debug, Deserialize, Clone, Serialize, PartialEq)]
+pub struct FailureEntry {
+    pub name: String,
+    pub server: String,
+    pub failures: u32,
+    pub last_error: String,
+    pub last_failed_at: u64, // Seconds since the Unix epoch
+    #[serde(default)]
+    pub quarantined: bool, // Skipped until `retry` clears it
+    #[serde(default)]
+    pub ignored: bool, // Skipped for good
+}
+
+impl FailureEntry {
+    fn skipped(&self) -> bool {
+        self.quarantined || self.ignored
+    }
+}
+
+/// Failure counters keyed by torrent hash, kept in `failures_file` so that
+/// quarantined torrents stay quarantined across restarts.
+///
+/// Every change reads and rewrites the file, which lets the `retry` and
+/// `ignore` commands edit it while the daemon is running.
+#[derive(Clone)]
+pub struct FailureLog {
+    filename: Arc<String>,
+    max_failures: u32,
+    notifier: Notifier,
+    lock: Arc<Mutex<()>>,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+impl FailureLog {
+    /// `max_failures` of 0 counts failures without ever quarantining.
+    pub fn new(filename: &str, max_failures: u32, notifier: Notifier) -> Self {
+        Self {
+            filename: Arc::new(filename.to_string()),
+            max_failures,
+            notifier,
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    pub fn load(&self) -> Result<BTreeMap<String, FailureEntry>> {
+        match File::open(self.filename.as_str()) {
+            Ok(file) => serde_json::from_reader(file).map_err(|e| {
+                anyhow::anyhow!("Unable to parse failures file {}: {}", self.filename, e)
+            }),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(e) => Err(anyhow::anyhow!(
+                "Unable to read failures file {}: {}",
+                self.filename,
+                e
+            )),
+        }
+    }
+
+    /// Writes the entries atomically so that readers never see a partial file.
+    fn save(&self, entries: &BTreeMap<String, FailureEntry>) -> Result<()> {
+        let tmp_filename = format!("{}.tmp", self.filename);
+        let file = File::create(&tmp_filename)?;
+        serde_json::to_writer_pretty(&file, entries)?;
+        fs::rename(&tmp_filename, self.filename.as_str())?;
+        Ok(())
+    }
+
+    /// Loads the entries, lets `change` edit them and saves them if it
+    /// returns true.
+    fn update<T>(
+        &self,
+        change: impl FnOnce(&mut BTreeMap<String, FailureEntry>) -> Result<(T, bool)>,
+    ) -> Result<T> {
+        let _lock = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let mut entries = self.load()?;
+        let (result, changed) = change(&mut entries)?;
+        if changed {
+            self.save(&entries)?;
+        }
+        Ok(result)
+    }
+
+    /// Hashes of the torrents that are quarantined or ignored.
+    pub fn skipped(&self) -> Result<HashSet<String>> {
+        Ok(self
+            .load()?
+            .into_iter()
+            .filter(|(_, entry)| entry.skipped())
+            .map(|(hash, _)| hash)
+            .collect())
+    }
+
+    /// Counts a failed move and quarantines the torrent once it has failed
+    /// `max_failures` times in a row.
+    pub fn record_failure(
+        &self,
+        server: &str,
+        torrent: &Torrent,
+        error: &str,
+    ) -> Result<FailureEntry> {
+        let entry = self.update(|entries| {
+            let entry = entries
+                .entry(torrent.hash.clone())
+                .or_insert_with(|| FailureEntry {
+                    name: torrent.name.clone(),
+                    server: server.to_string(),
+                    failures: 0,
+                    last_error: String::new(),
+                    last_failed_at: 0,
+                    quarantined: false,
+                    ignored: false,
+                });
+            entry.failures = entry.failures.saturating_add(1);
+            entry.last_error = error.to_string();
+            entry.last_failed_at = now();
+            entry.quarantined = self.max_failures > 0 && entry.failures >= self.max_failures;
+            Ok((entry.clone(), true))
+        })?;
+        if entry.quarantined {
+            self.notifier.notify(
+                "torrent_quarantined",
+                &format!(
+                    "Quarantined {} ({}) after {} failures: {}",
+                    torrent.name, torrent.hash, entry.failures, error
+                ),
+            );
+        }
+        Ok(entry)
+    }
+
+    /// Forgets the failures of a torrent that was moved.
+    pub fn record_success(&self, hash: &str) -> Result<()> {
+        self.update(|entries| Ok(((), entries.remove(hash).is_some())))
+    }
+
+    /// Clears a torrent's failures so that the next poll tries it again.
+    pub fn retry(&self, hash: &str) -> Result<FailureEntry> {
+        self.update(|entries| {
+            let entry = entries
+                .remove(&hash.to_lowercase())
+                .ok_or_else(|| anyhow::anyhow!("No failures recorded for {}", hash))?;
+            Ok((entry, true))
+        })
+    }
+
+    /// Stops trying to move a torrent until `retry` is run for it.
+    pub fn ignore(&self, hash: &str) -> Result<FailureEntry> {
+        self.update(|entries| {
+            let entry = entries
+                .get_mut(&hash.to_lowercase())
+                .ok_or_else(|| anyhow::anyhow!("No failures recorded for {}", hash))?;
+            entry.ignored = true;
+            Ok((entry.clone(), true))
+        })
+    }
+}
+
+/// Lists the quarantined and ignored torrents for the `failed` command.
+pub fn render(entries: &BTreeMap<String, FailureEntry>) -> String {
+    let skipped: Vec<_> = entries.iter().filter(|(_, e)| e.skipped()).collect();
+    if skipped.is_empty() {
+        return String::from("No quarantined torrents\n");
+    }
+
+    let now = now();
+    let mut out = String::new();
+    for (hash, entry) in skipped {
+        out.push_str(&format!(
+            "{} {} [{}]{}\n  failed {} times, last {}s ago: {}\n",
+            hash,
+            entry.name,
+            entry.server,
+            if entry.ignored { " (ignored)" } else { "" },
+            entry.failures,
+            now.saturating_sub(entry.last_failed_at),
+            entry.last_error
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::test_torrent;
+
+    #[test]
+    fn test_quarantine_after_max_failures() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let filename = dir.path().join("failures.json");
+        let log = FailureLog::new(filename.to_str().unwrap(), 2, Notifier::default());
+        assert!(log.skipped()?.is_empty());
+
+        let entry = log.record_failure("http://server-a", &test_torrent("abc"), "missing")?;
+        assert_eq!(entry.failures, 1);
+        assert!(!entry.quarantined);
+        let entry = log.record_failure("http://server-a", &test_torrent("abc"), "still missing")?;
+        assert!(entry.quarantined);
+        assert_eq!(entry.last_error, "still missing");
+
+        // The counters survive a restart
+        let reloaded = FailureLog::new(filename.to_str().unwrap(), 2, Notifier::default());
+        assert_eq!(reloaded.skipped()?, HashSet::from([String::from("abc")]));
+        assert!(render(&reloaded.load()?).contains("abc test_torrent [http://server-a]"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_success_clears_failures() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let filename = dir.path().join("failures.json");
+        let log = FailureLog::new(filename.to_str().unwrap(), 0, Notifier::default());
+        for _ in 0..10 {
+            log.record_failure("http://server-a", &test_torrent("abc"), "busy")?;
+        }
+        assert!(log.skipped()?.is_empty());
+        log.record_success("abc")?;
+        assert!(log.load()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_retry_and_ignore() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let filename = dir.path().join("failures.json");
+        let log = FailureLog::new(filename.to_str().unwrap(), 1, Notifier::default());
+        log.record_failure("http://server-a", &test_torrent("abc"), "missing")?;
+        log.record_failure("http://server-a", &test_torrent("def"), "missing")?;
+
+        log.retry("ABC")?;
+        assert!(log.retry("abc").is_err());
+        assert!(log.ignore("abc").is_err());
+        assert!(log.ignore("def")?.ignored);
+        assert!(render(&log.load()?).contains("(ignored)"));
+        assert_eq!(log.skipped()?, HashSet::from([String::from("def")]));
+        assert_eq!(
+            render(&BTreeMap::new()),
+            "No quarantined torrents\n".to_string()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,297 @@
+/*
+qBittorrent Mover - A tool to automatically move torrents to different categories based on their state.
+Copyright (C) 2023 Harrison Chin
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use super::config::parse_config;
+use super::secrets::write_private;
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde_yaml::Value;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// File extensions of the supported config formats.
+pub const EXTENSIONS: &[&str] = &["yaml", "yml", "toml", "json"];
+
+/// A config file format, picked by the file extension. Every format is read
+/// into the same YAML document, so merging, defaults and validation do not
+/// depend on it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Yaml,
+    Toml,
+    Json,
+}
+
+/// A parse error with the line and column it refers to, when known.
+#[derive(Debug)]
+pub struct ParseError {
+    pub position: Option<(usize, usize)>,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.position {
+            Some((line, column)) => write!(f, "{}:{}: {}", line, column, self.message),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Format::Yaml => "YAML",
+            Format::Toml => "TOML",
+            Format::Json => "JSON",
+        })
+    }
+}
+
+/// Splits a YAML error into its line and column, when known, and the message
+/// without the position serde_yaml appends to it.
+pub fn yaml_error_position(error: &serde_yaml::Error) -> (Option<(usize, usize)>, String) {
+    let message = error.to_string();
+    match error.location() {
+        Some(location) => {
+            let suffix = format!(" at line {} column {}", location.line(), location.column());
+            let message = message
+                .strip_suffix(&suffix)
+                .unwrap_or(&message)
+                .to_string();
+            (Some((location.line(), location.column())), message)
+        }
+        None => (None, message),
+    }
+}
+
+/// Line and column, both starting at 1, of a byte offset into `contents`.
+fn line_column(contents: &str, offset: usize) -> (usize, usize) {
+    let before = &contents[..offset.min(contents.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.len() - before.rfind('\n').map_or(0, |newline| newline + 1) + 1;
+    (line, column)
+}
+
+/// Removes null values, which TOML cannot represent. A missing key reads
+/// back the same way.
+fn remove_nulls(value: &mut Value) {
+    match value {
+        Value::Mapping(mapping) => {
+            mapping.retain(|_, item| !item.is_null());
+            for (_, item) in mapping.iter_mut() {
+                remove_nulls(item);
+            }
+        }
+        Value::Sequence(sequence) => {
+            for item in sequence {
+                remove_nulls(item);
+            }
+        }
+        Value::Tagged(tagged) => remove_nulls(&mut tagged.value),
+        Value::Null | Value::Bool(_) | Value::Number(_) | Value::String(_) => {}
+    }
+}
+
+impl Format {
+    pub fn from_path(path: &Path) -> Result<Self> {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("yaml" | "yml") => Ok(Format::Yaml),
+            Some("toml") => Ok(Format::Toml),
+            Some("json") => Ok(Format::Json),
+            _ => Err(anyhow::anyhow!(
+                "{}: unknown config format; use one of the extensions {}",
+                path.display(),
+                EXTENSIONS.join(", ")
+            )),
+        }
+    }
+
+    /// Reads `contents` straight into `T`, so errors point at the line
+    /// they come from.
+    pub fn deserialize<T: DeserializeOwned>(
+        self,
+        contents: &str,
+    ) -> std::result::Result<T, ParseError> {
+        match self {
+            Format::Yaml => serde_yaml::from_str(contents).map_err(|e| {
+                let (position, message) = yaml_error_position(&e);
+                ParseError { position, message }
+            }),
+            Format::Toml => toml::from_str(contents).map_err(|e| ParseError {
+                position: e.span().map(|span| line_column(contents, span.start)),
+                message: e.message().to_string(),
+            }),
+            Format::Json => serde_json::from_str(contents).map_err(|e| {
+                let message = e.to_string();
+                let suffix = format!(" at line {} column {}", e.line(), e.column());
+                ParseError {
+                    position: (e.line() > 0).then(|| (e.line(), e.column())),
+                    message: message
+                        .strip_suffix(&suffix)
+                        .unwrap_or(&message)
+                        .to_string(),
+                }
+            }),
+        }
+    }
+
+    pub fn parse(self, contents: &str) -> std::result::Result<Value, ParseError> {
+        self.deserialize(contents)
+    }
+
+    pub fn render(self, document: &Value) -> Result<String> {
+        Ok(match self {
+            Format::Yaml => serde_yaml::to_string(document)?,
+            Format::Toml => {
+                let mut document = document.clone();
+                remove_nulls(&mut document);
+                toml::to_string_pretty(&document)?
+            }
+            Format::Json => serde_json::to_string_pretty(document)? + "\n",
+        })
+    }
+}
+
+/// Writes `input` to `output` in the format `output`'s extension names,
+/// after validating it the way loading does. `${VAR}` references are kept as
+/// written; comments are lost. The output may hold a password, so only its
+/// owner can read it. Refuses to replace `output` unless `force` is set.
+pub fn convert(
+    input: &str,
+    output: &str,
+    force: bool,
+    lookup: &dyn Fn(&str) -> Option<String>,
+) -> Result<()> {
+    let from = Format::from_path(Path::new(input))?;
+    let to = Format::from_path(Path::new(output))?;
+    if Path::new(output).exists() && !force {
+        return Err(anyhow::anyhow!(
+            "{} already exists; pass --force to replace it",
+            output
+        ));
+    }
+
+    parse_config(input, lookup)?;
+    let contents = fs::read_to_string(input)
+        .map_err(|e| anyhow::anyhow!("Unable to read {}: {}", input, e))?;
+    let document = from
+        .parse(&contents)
+        .map_err(|e| anyhow::anyhow!("{}:{}", input, e))?;
+    write_private(output, &to.render(&document)?)?;
+    println!("Converted {} ({}) to {} ({})", input, from, output, to);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const YAML: &str = "\
+servers:
+  - qbit_url: http://localhost:8080
+    categories:
+      movies: /media/movies
+    password_file: null
+rate_limit_delay: 5
+";
+
+    #[test]
+    fn test_from_path() {
+        assert_eq!(
+            Format::from_path(Path::new("a/config.yml")).unwrap(),
+            Format::Yaml
+        );
+        assert_eq!(
+            Format::from_path(Path::new("config.toml")).unwrap(),
+            Format::Toml
+        );
+        assert_eq!(
+            Format::from_path(Path::new("config.json")).unwrap(),
+            Format::Json
+        );
+        assert!(Format::from_path(Path::new("config.ini")).is_err());
+        assert!(Format::from_path(Path::new("config")).is_err());
+    }
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        let document = Format::Yaml.parse(YAML).unwrap();
+        let json = Format::Json.render(&document)?;
+        assert_eq!(Format::Json.parse(&json).unwrap(), document);
+
+        // TOML has no null, so such keys are left out
+        let mut without_nulls = document.clone();
+        remove_nulls(&mut without_nulls);
+        let toml = Format::Toml.render(&document)?;
+        assert_eq!(Format::Toml.parse(&toml).unwrap(), without_nulls);
+        Ok(())
+    }
+
+    #[test]
+    fn test_convert() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let input = dir.path().join("config.yaml");
+        let output = dir.path().join("config.toml");
+        let (input, output) = (input.to_str().unwrap(), output.to_str().unwrap());
+        let lookup = |name: &str| (name == "QBIT_PASSWORD").then(|| String::from("secret"));
+        fs::write(
+            input,
+            "servers:\n  - qbit_url: http://nas:8080\n    username: admin\n    password: ${QBIT_PASSWORD}\n    categories: {}\nrate_limit_delay: 5\nlog_file: mover.log\nmax_log_file_size: 10M\n",
+        )?;
+
+        convert(input, output, false, &lookup)?;
+        let converted = fs::read_to_string(output)?;
+        assert!(converted.contains("[[servers]]"), "{}", converted);
+        assert!(converted.contains("${QBIT_PASSWORD}"), "{}", converted);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(output)?.permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        assert!(convert(input, output, false, &lookup).is_err());
+        convert(input, output, true, &lookup)?;
+
+        // A config that would not load is not converted
+        let broken = dir.path().join("broken.json");
+        let broken = broken.to_str().unwrap();
+        fs::write(
+            input,
+            "servers:\n  - qbit_url: http://nas:8080\nrate_limit_delay: soon\n",
+        )?;
+        assert!(convert(input, broken, false, &lookup).is_err());
+        assert!(!Path::new(broken).exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_positions() {
+        let toml = Format::Toml
+            .parse("rate_limit_delay = 5\nservers = [\n")
+            .unwrap_err();
+        assert_eq!(toml.position.map(|(line, _)| line), Some(3));
+
+        let json = Format::Json
+            .parse("{\n  \"servers\": [,]\n}\n")
+            .unwrap_err();
+        assert_eq!(json.position, Some((2, 15)));
+        assert!(!json.message.contains("line"), "{}", json.message);
+    }
+}
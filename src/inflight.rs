@@ -0,0 +1,170 @@
+/*
+qBittorrent Mover - A tool to automatically move torrents to different categories based on their state.
+Copyright (C) 2023 Harrison Chin
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use super::copier::CopyProgress;
+use super::torrent::Torrent;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Deserialize, Clone, Serialize, PartialEq)]
+pub struct InFlightEntry {
+    pub hash: String,
+    pub name: String,
+    pub server: String,
+    pub started_at: u64, // Seconds since the Unix epoch
+    #[serde(default)]
+    pub progress: Option<CopyProgress>,
+}
+
+/// Registry of torrents that currently have a move in progress, keyed by hash.
+///
+/// It is shared across loop iterations and servers so that a torrent is only
+/// ever handled by one pipeline at a time, even when the same hash shows up on
+/// more than one qBittorrent instance.
+#[derive(Clone, Default)]
+pub struct InFlight {
+    entries: Arc<Mutex<HashMap<String, InFlightEntry>>>,
+}
+
+/// Releases the torrent from the registry when dropped.
+pub struct InFlightGuard {
+    entries: Arc<Mutex<HashMap<String, InFlightEntry>>>,
+    hash: String,
+}
+
+impl InFlight {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Claims the torrent for this caller, or returns `None` if another
+    /// pipeline is already working on the same hash.
+    pub fn try_acquire(&self, server: &str, torrent: &Torrent) -> Option<InFlightGuard> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if entries.contains_key(&torrent.hash) {
+            return None;
+        }
+
+        let started_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        entries.insert(
+            torrent.hash.clone(),
+            InFlightEntry {
+                hash: torrent.hash.clone(),
+                name: torrent.name.clone(),
+                server: server.to_string(),
+                started_at,
+                progress: None,
+            },
+        );
+
+        Some(InFlightGuard {
+            entries: Arc::clone(&self.entries),
+            hash: torrent.hash.clone(),
+        })
+    }
+
+    pub fn set_progress(&self, hash: &str, progress: CopyProgress) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(entry) = entries.get_mut(hash) {
+            entry.progress = Some(progress);
+        }
+    }
+
+    /// Returns the in-flight entries ordered by start time.
+    pub fn snapshot(&self) -> Vec<InFlightEntry> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let mut snapshot: Vec<InFlightEntry> = entries.values().cloned().collect();
+        snapshot.sort_by(|a, b| (a.started_at, &a.hash).cmp(&(b.started_at, &b.hash)));
+        snapshot
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.remove(&self.hash);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::test_torrent;
+
+    #[test]
+    fn test_try_acquire_rejects_duplicates() {
+        let in_flight = InFlight::new();
+        let guard = in_flight.try_acquire("http://server-a", &test_torrent("abc"));
+        assert!(guard.is_some());
+
+        // The same hash on another server is still considered in flight
+        assert!(in_flight
+            .try_acquire("http://server-b", &test_torrent("abc"))
+            .is_none());
+        assert!(in_flight
+            .try_acquire("http://server-a", &test_torrent("def"))
+            .is_some());
+    }
+
+    #[test]
+    fn test_guard_releases_on_drop() {
+        let in_flight = InFlight::new();
+        let guard = in_flight.try_acquire("http://server-a", &test_torrent("abc"));
+        assert_eq!(in_flight.snapshot().len(), 1);
+
+        drop(guard);
+        assert!(in_flight.snapshot().is_empty());
+        assert!(in_flight
+            .try_acquire("http://server-a", &test_torrent("abc"))
+            .is_some());
+    }
+
+    #[test]
+    fn test_snapshot_contents() {
+        let in_flight = InFlight::new();
+        let _guard = in_flight.try_acquire("http://server-a", &test_torrent("abc"));
+        let snapshot = in_flight.snapshot();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0].hash, "abc");
+        assert_eq!(snapshot[0].name, "test_torrent");
+        assert_eq!(snapshot[0].server, "http://server-a");
+        assert_eq!(snapshot[0].progress, None);
+    }
+
+    #[test]
+    fn test_set_progress() {
+        let in_flight = InFlight::new();
+        let _guard = in_flight.try_acquire("http://server-a", &test_torrent("abc"));
+        let progress = CopyProgress {
+            bytes_copied: 10,
+            total_bytes: 20,
+            ..Default::default()
+        };
+        in_flight.set_progress("abc", progress.clone());
+        in_flight.set_progress("unknown", progress.clone());
+
+        let snapshot = in_flight.snapshot();
+        assert_eq!(snapshot.len(), 1);
+        assert_eq!(snapshot[0].progress, Some(progress));
+    }
+}
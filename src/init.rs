@@ -0,0 +1,475 @@
+/*
+qBittorrent Mover - A tool to automatically move torrents to different categories based on their state.
+Copyright (C) 2023 Harrison Chin
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use super::config::{Config, ServerConfig};
+use super::format::Format;
+use super::migrate::CURRENT_VERSION;
+use super::secrets::{read_secret_file, write_private, Secret};
+use super::torrent::{self, TorrentClient};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::path::Path;
+
+/// Asks `question` and returns the trimmed answer, or `default` when the
+/// answer is empty.
+fn prompt(
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+    question: &str,
+    default: &str,
+) -> Result<String> {
+    if default.is_empty() {
+        write!(output, "{}: ", question)?;
+    } else {
+        write!(output, "{} [{}]: ", question, default)?;
+    }
+    output.flush()?;
+
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+        return Err(anyhow::anyhow!("Unexpected end of input"));
+    }
+    let answer = line.trim();
+    Ok(if answer.is_empty() { default } else { answer }.to_string())
+}
+
+/// Turns off terminal echo on stdin until dropped, so a typed password is not
+/// shown. Does nothing when stdin is not a terminal.
+struct EchoOff {
+    #[cfg(unix)]
+    saved: Option<libc::termios>,
+}
+
+impl EchoOff {
+    #[cfg(unix)]
+    fn new() -> Self {
+        // SAFETY: termios is plain data and only read after tcgetattr filled it.
+        let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+        let is_terminal = unsafe {
+            libc::isatty(libc::STDIN_FILENO) == 1
+                && libc::tcgetattr(libc::STDIN_FILENO, &mut saved) == 0
+        };
+        if !is_terminal {
+            return Self { saved: None };
+        }
+        let mut quiet = saved;
+        quiet.c_lflag &= !libc::ECHO;
+        quiet.c_lflag |= libc::ECHONL;
+        // SAFETY: `quiet` is a copy of the terminal's own settings.
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &quiet) } != 0 {
+            return Self { saved: None };
+        }
+        Self { saved: Some(saved) }
+    }
+
+    #[cfg(not(unix))]
+    fn new() -> Self {
+        Self {}
+    }
+}
+
+impl Drop for EchoOff {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Some(saved) = &self.saved {
+            // SAFETY: restores the settings tcgetattr returned.
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, saved) };
+        }
+    }
+}
+
+/// Asks for a password without echoing it.
+fn prompt_password(
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+    question: &str,
+) -> Result<Secret> {
+    write!(output, "{}: ", question)?;
+    output.flush()?;
+
+    let _echo_off = EchoOff::new();
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+        return Err(anyhow::anyhow!("Unexpected end of input"));
+    }
+    Ok(Secret::new(line.trim_end_matches(['\r', '\n'])))
+}
+
+fn confirm(input: &mut impl BufRead, output: &mut impl Write, question: &str) -> Result<bool> {
+    let answer = prompt(input, output, &format!("{} [y/N]", question), "")?;
+    Ok(answer.eq_ignore_ascii_case("y") || answer.eq_ignore_ascii_case("yes"))
+}
+
+/// Quotes a string as a YAML double-quoted scalar, escaping `${` so that
+/// loading the file does not take it for an environment variable reference.
+fn quote(value: &str) -> String {
+    serde_json::to_string(&value.replace("${", "$${")).unwrap_or_default()
+}
+
+/// Writes the config file by hand so it can carry comments explaining each
+/// setting.
+pub fn render_config(server: &ServerConfig) -> String {
+    let defaults = Config::default();
+    let mut out = String::new();
+    out.push_str("# qBittorrent Mover configuration, written by `qbittorrent_mover init`.\n");
+    out.push_str("# Run `qbittorrent_mover check-config` after editing it.\n\n");
+    out.push_str("# Layout version of this file; `qbittorrent_mover migrate-config` upgrades it\n");
+    out.push_str(&format!("version: {}\n\n", CURRENT_VERSION));
+    out.push_str("servers:\n");
+    out.push_str(&format!("  - qbit_url: {}\n", quote(&server.qbit_url)));
+    out.push_str(&format!("    username: {}\n", quote(&server.username)));
+    if let Some(name) = &server.password_env {
+        out.push_str("    # The password is read from this environment variable\n");
+        out.push_str(&format!("    password_env: {}\n", quote(name)));
+    } else if let Some(path) = &server.password_file {
+        out.push_str("    # The password is read from this file\n");
+        out.push_str(&format!("    password_file: {}\n", quote(path)));
+    } else {
+        out.push_str(&format!(
+            "    password: {}\n",
+            quote(server.password.expose())
+        ));
+    }
+    out.push_str("    # qBittorrent category -> directory its completed torrents are moved to\n");
+    if server.categories.is_empty() {
+        out.push_str("    categories: {}\n");
+        out.push_str("    # categories:\n");
+        out.push_str("    #   movies: \"/media/movies\"\n");
+    } else {
+        out.push_str("    categories:\n");
+        let mut categories: Vec<_> = server.categories.iter().collect();
+        categories.sort();
+        for (name, dest) in categories {
+            out.push_str(&format!("      {}: {}\n", quote(name), quote(dest)));
+        }
+    }
+    out.push_str("    # Only torrents saved inside this directory are moved\n");
+    match &server.download_root {
+        Some(root) => out.push_str(&format!("    download_root: {}\n", quote(root))),
+        None => out.push_str("    # download_root: \"/downloads\"\n"),
+    }
+    out.push_str("    # If qBittorrent runs in a container, translate its paths to the mover's:\n");
+    out.push_str("    # path_mappings:\n");
+    out.push_str("    #   - remote: \"/downloads\"\n");
+    out.push_str("    #     local: \"/srv/qbittorrent/downloads\"\n\n");
+    out.push_str("# Time to wait between checks for completed torrents, like \"30s\" or \"5m\"\n");
+    out.push_str(&format!(
+        "rate_limit_delay: {}\n",
+        quote(&defaults.rate_limit_delay.to_string())
+    ));
+    out.push_str(&format!("log_file: {}\n", quote(&defaults.log_file)));
+    out.push_str("# Size at which the log file is rotated\n");
+    out.push_str(&format!(
+        "max_log_file_size: {}\n",
+        quote(&defaults.max_log_file_size.to_string())
+    ));
+    out.push_str("# Space to leave free on every destination\n");
+    out.push_str(&format!(
+        "free_space_reserve: {}\n",
+        quote(&defaults.free_space_reserve.to_string())
+    ));
+    out
+}
+
+/// Asks where the password is kept, preferring an environment variable or a
+/// file over the config file, and fills in `password` for the login test.
+fn ask_password(
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+    server: &mut ServerConfig,
+) -> Result<()> {
+    loop {
+        let storage = prompt(
+            input,
+            output,
+            "Keep the password in an environment variable, a file or the config file (env/file/config)",
+            "env",
+        )?;
+        match storage.as_str() {
+            "env" => {
+                let name = prompt(
+                    input,
+                    output,
+                    "Environment variable holding the password",
+                    "QBIT_PASSWORD",
+                )?;
+                server.password = match std::env::var(&name) {
+                    Ok(value) => Secret::new(value),
+                    Err(_) => prompt_password(
+                        input,
+                        output,
+                        &format!("{} is not set; password to test the login with", name),
+                    )?,
+                };
+                server.password_env = Some(name);
+            }
+            "file" => {
+                let path = prompt(
+                    input,
+                    output,
+                    "File holding the password",
+                    "/run/secrets/qbit_password",
+                )?;
+                server.password = if Path::new(&path).exists() {
+                    read_secret_file(&path)?
+                } else {
+                    prompt_password(input, output, &format!("Password, saved to {}", path))?
+                };
+                server.password_file = Some(path);
+            }
+            "config" => {
+                server.password = prompt_password(
+                    input,
+                    output,
+                    "Password, saved in plain text in the config file",
+                )?;
+            }
+            _ => {
+                writeln!(output, "Answer env, file or config")?;
+                continue;
+            }
+        }
+        return Ok(());
+    }
+}
+
+/// Asks for the server details until the login works or the user gives up.
+/// Returns the server's categories when the login succeeded.
+async fn ask_server(
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+) -> Result<(ServerConfig, Option<Vec<torrent::Category>>)> {
+    let defaults = ServerConfig::default();
+    loop {
+        let mut server = ServerConfig {
+            qbit_url: prompt(input, output, "qBittorrent Web UI URL", &defaults.qbit_url)?,
+            username: prompt(input, output, "Username", &defaults.username)?,
+            ..Default::default()
+        };
+        ask_password(input, output, &mut server)?;
+
+        let client = TorrentClient::new(server.clone())?;
+        let result = match torrent::get_version(&client).await {
+            Ok(version) => torrent::get_categories(&client)
+                .await
+                .map(|categories| (version, categories)),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok((version, categories)) => {
+                writeln!(output, "Logged in to qBittorrent {}", version.trim())?;
+                let mut categories: Vec<_> = categories.into_values().collect();
+                categories.sort_by(|a, b| a.name.cmp(&b.name));
+                return Ok((server, Some(categories)));
+            }
+            Err(e) => {
+                writeln!(output, "Unable to log in to {}: {}", server.qbit_url, e)?;
+                if !confirm(input, output, "Try again?")? {
+                    return Ok((server, None));
+                }
+            }
+        }
+    }
+}
+
+/// Interactively creates `config_file`. Refuses to overwrite an existing
+/// file unless `force` is set.
+pub async fn run(
+    config_file: &str,
+    force: bool,
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+) -> Result<()> {
+    if Path::new(config_file).exists() && !force {
+        return Err(anyhow::anyhow!(
+            "{} already exists; pass --force to replace it",
+            config_file
+        ));
+    }
+
+    let (mut server, categories) = ask_server(input, output).await?;
+    let mut mapped = HashMap::new();
+    match categories {
+        Some(categories) if categories.is_empty() => {
+            writeln!(output, "The server has no categories yet")?;
+        }
+        Some(categories) => {
+            writeln!(
+                output,
+                "Enter the directory each category should be moved to, or leave it empty to skip it"
+            )?;
+            for category in categories {
+                let question = if category.save_path.is_empty() {
+                    category.name.clone()
+                } else {
+                    format!("{} (saved in {})", category.name, category.save_path)
+                };
+                let dest = prompt(input, output, &question, "")?;
+                if !dest.is_empty() {
+                    mapped.insert(category.name, dest);
+                }
+            }
+        }
+        None => writeln!(output, "Skipping categories; add them to the file by hand")?,
+    }
+    server.categories = mapped;
+    let root = prompt(
+        input,
+        output,
+        "Directory qBittorrent saves downloads in; only torrents inside it are moved",
+        "",
+    )?;
+    server.download_root = (!root.is_empty()).then_some(root);
+
+    if let Some(path) = &server.password_file {
+        if !Path::new(path).exists() {
+            write_private(path, server.password.expose())?;
+        }
+    }
+
+    // Only YAML keeps the explanatory comments
+    let mut contents = render_config(&server);
+    let format = Format::from_path(Path::new(config_file))?;
+    if format != Format::Yaml {
+        contents = format.render(&serde_yaml::from_str(&contents)?)?;
+    }
+    write_private(config_file, &contents)?;
+    writeln!(
+        output,
+        "Wrote {}; run `qbittorrent_mover check-config` to validate it",
+        config_file
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{load_config, parse_config};
+    use mockito::Server;
+    use std::fs;
+
+    #[tokio::test]
+    async fn test_init_writes_config() -> Result<()> {
+        let mut server = Server::new();
+        let _version = server
+            .mock("GET", "/api/v2/app/version")
+            .with_status(200)
+            .with_body("v4.6.0")
+            .create();
+        let _categories = server
+            .mock("GET", "/api/v2/torrents/categories")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"movies": {"name": "movies", "savePath": "/downloads/movies"}, "tv": {"name": "tv", "savePath": ""}}"#)
+            .create();
+
+        let dir = tempfile::tempdir()?;
+        let config_file = dir.path().join("config.yaml");
+        let config_file = config_file.to_str().unwrap();
+        // The password is kept in an unset environment variable by default,
+        // so it is asked for the login test only
+        let mut input = format!(
+            "{}\n\n\nQBIT_MOVER_TEST_INIT_PASSWORD\nsecret \"pass\"\n/media/movies\n\n/downloads\n",
+            server.url()
+        );
+        let mut output = Vec::new();
+        run(config_file, false, &mut input.as_bytes(), &mut output).await?;
+
+        let config = parse_config(config_file, &|_| None)?.config;
+        assert_eq!(config.servers.len(), 1);
+        assert_eq!(config.servers[0].qbit_url, server.url());
+        assert_eq!(config.servers[0].username, "admin");
+        assert_eq!(
+            config.servers[0].password_env.as_deref(),
+            Some("QBIT_MOVER_TEST_INIT_PASSWORD")
+        );
+        assert!(config.servers[0].password.is_empty());
+        assert!(!fs::read_to_string(config_file)?.contains("secret"));
+        assert_eq!(
+            config.servers[0].categories,
+            HashMap::from([(String::from("movies"), String::from("/media/movies"))])
+        );
+        assert_eq!(
+            config.servers[0].download_root.as_deref(),
+            Some("/downloads")
+        );
+        assert!(fs::read_to_string(config_file)?.contains("# qBittorrent category"));
+
+        // An existing file is left alone without --force
+        input = String::new();
+        assert!(run(config_file, false, &mut input.as_bytes(), &mut output)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_config_escapes_variable_references() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config_file = dir.path().join("config.yaml");
+        let config_file = config_file.to_str().unwrap();
+        let server = ServerConfig {
+            qbit_url: String::from("http://localhost:8080"),
+            password: Secret::new("pa${HOME}ss$${x}$"),
+            ..Default::default()
+        };
+        fs::write(config_file, render_config(&server))?;
+
+        let config = load_config(config_file)?.config;
+        assert_eq!(config.servers[0].password.expose(), "pa${HOME}ss$${x}$");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_init_without_login() -> Result<()> {
+        let mut server = Server::new();
+        let _version = server
+            .mock("GET", "/api/v2/app/version")
+            .with_status(403)
+            .create();
+
+        let dir = tempfile::tempdir()?;
+        let config_file = dir.path().join("config.yaml");
+        let config_file = config_file.to_str().unwrap();
+        let password_file = dir.path().join("password");
+        let input = format!(
+            "{}\nuser\nfile\n{}\npass\nn\n\n",
+            server.url(),
+            password_file.display()
+        );
+        let mut output = Vec::new();
+        run(config_file, false, &mut input.as_bytes(), &mut output).await?;
+
+        // The password went to its own file, not the config file
+        let config = load_config(config_file)?.config;
+        assert_eq!(config.servers[0].password.expose(), "pass");
+        assert!(!fs::read_to_string(config_file)?.contains("pass\""));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&password_file)?.permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        assert!(config.servers[0].categories.is_empty());
+        assert_eq!(config.servers[0].download_root, None);
+        assert!(String::from_utf8(output)?.contains("Unable to log in"));
+        Ok(())
+    }
+}
@@ -16,32 +16,19 @@ You should have received a copy of the GNU Affero General Public License
 along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
+use super::units::ByteSize;
 use anyhow::Result;
 use log::LevelFilter;
-use log4rs;
 use log4rs::append::rolling_file::policy::compound::roll::fixed_window::FixedWindowRoller;
 use log4rs::append::rolling_file::policy::compound::trigger::size::SizeTrigger;
 use log4rs::append::rolling_file::policy::compound::CompoundPolicy;
 use log4rs::append::rolling_file::RollingFileAppender;
 use log4rs::config::{Appender, Config as LogConfig, Root};
 use log4rs::encode::pattern::PatternEncoder;
-use std::num::ParseIntError;
 
 const MAX_ARCHIVED_LOGS: u32 = 1;
 
-fn parse_size(size: &str) -> Result<u64, ParseIntError> {
-    let mut iter = size.chars().rev();
-    let unit = iter.next();
-    let number: String = iter.collect::<String>().chars().rev().collect();
-
-    match unit {
-        Some('M') => number.parse::<u64>().map(|n| n * 1024 * 1024),
-        Some('G') => number.parse::<u64>().map(|n| n * 1024 * 1024 * 1024),
-        _ => number.parse::<u64>(),
-    }
-}
-
-pub fn setup_logger(log_file: &str, max_log_size: &str) -> Result<()> {
+pub fn setup_logger(log_file: &str, max_log_size: ByteSize) -> Result<()> {
     if log::log_enabled!(log::Level::Info) {
         return Ok(());
     }
@@ -50,8 +37,7 @@ pub fn setup_logger(log_file: &str, max_log_size: &str) -> Result<()> {
 
     // Set up rolling file appender
     let roller = FixedWindowRoller::builder().build("{}.{}", MAX_ARCHIVED_LOGS)?;
-    let max_log_size = parse_size(max_log_size)?;
-    let trigger = SizeTrigger::new(max_log_size);
+    let trigger = SizeTrigger::new(max_log_size.bytes());
     let policy = CompoundPolicy::new(Box::new(trigger), Box::new(roller));
 
     let file_appender = RollingFileAppender::builder()
@@ -79,7 +65,7 @@ mod tests {
     #[test]
     fn test_setup_logger() -> Result<()> {
         let log_file = "test_logger.log";
-        let max_log_size = "10M";
+        let max_log_size = ByteSize(10 << 20);
         setup_logger(log_file, max_log_size)?;
 
         // Check if the log file was created
@@ -16,33 +16,152 @@ You should have received a copy of the GNU Affero General Public License
 along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
+mod api;
+mod backoff;
+mod categories;
+mod check;
+mod cli;
 mod config;
+mod copier;
+mod detect;
+mod errors;
+mod failures;
+mod format;
+mod inflight;
+mod init;
 mod logger;
+mod merge;
+mod migrate;
+mod notifier;
+mod paths;
+mod preflight;
+mod queue;
+mod report;
+mod retry;
+mod secrets;
+mod status;
+mod throttle;
+mod tls;
 mod torrent;
+mod units;
 
 use anyhow::{Error, Result};
+use api::ApiVersion;
+use backoff::Backoff;
+use cli::Command;
 use config::{ServerConfig, CONFIG_FILE};
+use copier::Copier;
+use errors::ErrorKind;
+use failures::FailureLog;
 use futures::future::join_all;
-use log::{error, info};
+use inflight::InFlight;
+use log::{debug, error, info, warn};
 use logger::setup_logger;
+use notifier::Notifier;
+use paths::PathGuard;
+use preflight::{Deferrals, Deferred, Preflight};
+use queue::MoveQueue;
+use report::{CycleReport, Outcome, Outcomes, ReportedError, Reports, TorrentOutcome};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use std::time::Duration;
-use tokio;
+use throttle::Bandwidth;
 use tokio::sync::oneshot::channel as oneshot_channel;
 use tokio::sync::oneshot::Receiver as OneshotReceiver;
+use tokio::sync::watch::{channel as watch_channel, Receiver as WatchReceiver};
 use tokio::time::sleep;
 
-use crate::torrent::TorrentClient;
+use crate::torrent::{MoveContext, MoveResult, TorrentClient};
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    info!("Starting qBittorrent Mover");
+    let args = cli::parse_args(std::env::args())?;
+    let command = args.command;
+    if command == Command::Help {
+        print!("{}", cli::USAGE);
+        return Ok(());
+    }
+    if let Command::Init { force } = command {
+        let config_file = config::config_override(args.config.as_deref(), &config::env_lookup)
+            .map_or_else(|| PathBuf::from(CONFIG_FILE), |(path, _)| path);
+        let stdin = std::io::stdin();
+        return init::run(
+            &config_file.to_string_lossy(),
+            force,
+            &mut stdin.lock(),
+            &mut std::io::stdout(),
+        )
+        .await;
+    }
+
+    let (config_path, found_by) = config::find_config(args.config.as_deref(), &config::env_lookup)?;
+    let config_file = config_path.to_string_lossy().into_owned();
+    match &command {
+        Command::CheckConfig => return check::run(&config_file).await,
+        Command::MigrateConfig => return migrate::run(&config_file),
+        Command::ConvertConfig { output, force } => {
+            return format::convert(&config_file, output, *force, &config::env_lookup)
+        }
+        _ => {}
+    }
 
-    let config = config::load_config(CONFIG_FILE).map_err(|e| {
+    let loaded = config::load_config(&config_file).map_err(|e| {
         error!("Failed to load configuration: {}", e);
-        anyhow::Error::from(e)
+        e
     })?;
+    let config = loaded.config;
+
+    let failure_log = FailureLog::new(
+        &config.failures_file,
+        config.max_failures,
+        Notifier::new(config.notify_command.clone()),
+    );
+    match command {
+        Command::Status => {
+            let report = status::read_status(&config.status_file)?;
+            print!("{}", report.render());
+            return Ok(());
+        }
+        Command::Failed => {
+            print!("{}", failures::render(&failure_log.load()?));
+            return Ok(());
+        }
+        Command::Retry { hash } => {
+            let entry = failure_log.retry(&hash)?;
+            println!("{} will be tried again on the next poll", entry.name);
+            return Ok(());
+        }
+        Command::Ignore { hash } => {
+            let entry = failure_log.ignore(&hash)?;
+            println!(
+                "{} will no longer be moved; use `retry {}` to undo",
+                entry.name, hash
+            );
+            return Ok(());
+        }
+        Command::DetectPaths { write, search } => {
+            return detect::run(&config_file, config, write, &search).await;
+        }
+        Command::Run
+        | Command::Init { .. }
+        | Command::CheckConfig
+        | Command::MigrateConfig
+        | Command::ConvertConfig { .. }
+        | Command::Help => {}
+    }
+
+    setup_logger(&config.log_file, config.max_log_file_size)?;
+
+    info!("Starting qBittorrent Mover");
+    info!("Using config file {} (from {})", config_file, found_by);
+    for setting in &loaded.settings {
+        info!("{} = {} ({})", setting.key, setting.value, setting.source);
+    }
+    for warning in &loaded.warnings {
+        warn!("{}", warning);
+    }
 
-    setup_logger(&config.log_file, &config.max_log_file_size)?;
+    check_api_versions(&config.servers).await?;
 
     let (shutdown_sender, shutdown_receiver) = oneshot_channel();
 
@@ -58,53 +177,351 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-async fn process_single_server(server: ServerConfig) -> Result<(), Error> {
-    let torrent_client = TorrentClient::new(server);
-    let is_online = torrent::is_server_online(&torrent_client).await?;
-    if is_online {
-        let torrents = torrent::get_completed_torrents(&torrent_client).await?;
-        for torrent in torrents {
-            let torrent_client = torrent_client.clone();
-            tokio::spawn(async move {
-                if let Err(e) =
-                    torrent::move_and_clean_torrent_files(&torrent_client, &torrent).await
-                {
-                    error!("Error moving and cleaning torrent files: {}", e);
-                }
-            });
+/// Starts moving the server's completed torrents, adding to `report` what
+/// happened. Each move reports to the context's `outcomes` once it begins
+/// copying and when it ends.
+async fn process_single_server(
+    torrent_client: &TorrentClient,
+    context: &MoveContext,
+    report: &mut CycleReport,
+) -> Result<(), Error> {
+    let server_url = &torrent_client.server().qbit_url;
+    let skipped = context.failures.skipped().unwrap_or_else(|e| {
+        error!("{}", e);
+        Default::default()
+    });
+    let torrents = match retry::retry(&context.retry.api, "Listing completed torrents", || {
+        torrent::get_completed_torrents(torrent_client)
+    })
+    .await
+    {
+        Ok(torrents) => torrents,
+        Err(e) => {
+            error!("Unable to list completed torrents on {}: {}", server_url, e);
+            report.error = Some(ReportedError::from(&e));
+            return Ok(());
         }
+    };
+    let server = torrent_client.server();
+    for torrent in torrents {
+        if !torrent::wants_move(server, &torrent) {
+            continue;
+        }
+        if skipped.contains(&torrent.hash) {
+            debug!("Torrent {} is quarantined, skipping", torrent.name);
+            continue;
+        }
+        let Some(guard) = context.in_flight.try_acquire(server_url, &torrent) else {
+            debug!("Torrent {} is already being moved, skipping", torrent.name);
+            continue;
+        };
+        let torrent_client = torrent_client.clone();
+        let context = context.clone();
+        let server_url = server_url.clone();
+        tokio::spawn(async move {
+            let result =
+                torrent::move_and_clean_torrent_files(&torrent_client, &torrent, &context).await;
+            let deferred = matches!(&result, Err(e) if e.is::<Deferred>());
+            if !deferred {
+                context.deferrals.clear(&torrent.hash);
+            }
+            let (outcome, recorded) = match result {
+                Ok(MoveResult::NothingToDo) => return,
+                // A deferral lasts until the destination frees up, so only
+                // its start is logged and reported
+                Err(e) if deferred => {
+                    if !context.deferrals.defer(&torrent.hash) {
+                        debug!("Still deferring {}: {}", torrent.name, e);
+                        return;
+                    }
+                    warn!("Deferring {}: {}", torrent.name, e);
+                    (
+                        Outcome::Deferred {
+                            reason: e.to_string(),
+                        },
+                        Ok(()),
+                    )
+                }
+                Err(e) => (
+                    Outcome::Failed(ReportedError::from(&e)),
+                    record_failure(&context.failures, &server_url, &torrent, &e),
+                ),
+                Ok(MoveResult::Moved) => (
+                    Outcome::Moved,
+                    context.failures.record_success(&torrent.hash),
+                ),
+            };
+            if let Err(e) = recorded {
+                error!("Error updating the failures file: {}", e);
+            }
+            context
+                .outcomes
+                .push(TorrentOutcome::new(&torrent, outcome));
+            drop(guard);
+        });
     }
     Ok(())
 }
 
-async fn process_all_servers(servers: &[ServerConfig]) -> Result<(), Error> {
-    let tasks = servers
-        .iter()
-        .map(|server| process_single_server(server.clone()));
-    let results: Vec<_> = join_all(tasks).await;
+/// Counts a failed move and logs it, saying so once the torrent is
+/// quarantined.
+fn record_failure(
+    failures: &FailureLog,
+    server_url: &str,
+    torrent: &torrent::Torrent,
+    e: &Error,
+) -> Result<()> {
+    let entry = failures.record_failure(server_url, torrent, &e.to_string())?;
+    if entry.quarantined {
+        error!(
+            "Quarantined {} ({}) after {} failures, run `retry {}` once fixed: {} error: {}",
+            torrent.name,
+            torrent.hash,
+            entry.failures,
+            torrent.hash,
+            errors::error_kind(e),
+            e
+        );
+    } else {
+        error!(
+            "Error moving {} (failure {}): {} error: {}",
+            torrent.name,
+            entry.failures,
+            errors::error_kind(e),
+            e
+        );
+    }
+    Ok(())
+}
 
-    let errors: Vec<Error> = results.into_iter().filter_map(|res| res.err()).collect();
-    if !errors.is_empty() {
-        return Err(anyhow::anyhow!("Encountered {} errors", errors.len()));
+/// Replaces the server's categories with the synced ones, in `server` and in
+/// the client. Returns false when the sync failed and should be retried on
+/// the next poll.
+async fn sync_server_categories(
+    server: &mut ServerConfig,
+    torrent_client: &mut TorrentClient,
+    path_guard: &PathGuard,
+) -> bool {
+    match categories::sync_categories(torrent_client).await {
+        Ok(effective) => {
+            for dest in effective.values() {
+                path_guard.protect(Path::new(dest));
+            }
+            torrent_client.set_categories(effective.clone());
+            server.categories = effective;
+            true
+        }
+        Err(e) => {
+            warn!("Unable to sync categories for {}: {}", server.qbit_url, e);
+            false
+        }
     }
+}
 
+/// Logs a summary of the poll and passes it to the notify command when
+/// something failed, then keeps it for the status file. Polls during an
+/// outage that was already reported are only kept.
+fn publish_report(context: &MoveContext, report: CycleReport, outage_reported: bool) {
+    if !report.is_quiet() && !outage_reported {
+        if report.errors().next().is_some() {
+            warn!("Poll of {}", report.summary());
+            context.notifier.notify("poll_failed", &report.summary());
+        } else {
+            info!("Poll of {}", report.summary());
+        }
+    }
+    context.reports.record(report);
+}
+
+/// Refuses to start when a server runs a Web API the mover does not support.
+/// Servers that cannot be reached yet are left to their poll loops.
+async fn check_api_versions(servers: &[ServerConfig]) -> Result<()> {
+    let detected = join_all(servers.iter().map(|server| async {
+        let torrent_client = TorrentClient::new(server.clone())?;
+        torrent::get_api_version(&torrent_client).await
+    }))
+    .await;
+    for (server, version) in servers.iter().zip(detected) {
+        match version {
+            Ok(version) => {
+                version.check_supported().map_err(|e| {
+                    error!("Refusing to start: {}: {}", server.qbit_url, e);
+                    anyhow::anyhow!("{}: {}", server.qbit_url, e)
+                })?;
+                info!("{} runs Web API {}", server.qbit_url, version);
+            }
+            Err(e) => warn!(
+                "Unable to detect the Web API version of {}: {}",
+                server.qbit_url, e
+            ),
+        }
+    }
     Ok(())
 }
 
-async fn main_loop(config: config::Config, mut shutdown_signal: OneshotReceiver<()>) -> Result<()> {
+/// Builds the server's client and detects its Web API version, logging when
+/// the version differs from the one seen before.
+async fn connect(
+    server: &ServerConfig,
+    api_version: &mut Option<ApiVersion>,
+) -> Result<TorrentClient> {
+    let torrent_client = TorrentClient::connect(server.clone()).await?;
+    let detected = Some(torrent_client.api_version());
+    if api_version.is_some() && *api_version != detected {
+        info!(
+            "{} now runs Web API {}",
+            server.qbit_url,
+            torrent_client.api_version()
+        );
+    }
+    *api_version = detected;
+    Ok(torrent_client)
+}
+
+/// Polls one server every `interval` until shutdown. While the server is
+/// unreachable the polls back off exponentially up to `max_backoff`; the
+/// outage and the recovery are each logged once.
+async fn server_loop(
+    mut server: ServerConfig,
+    context: MoveContext,
+    interval: Duration,
+    max_backoff: Duration,
+    mut shutdown: WatchReceiver<bool>,
+) {
+    let mut synced = !categories::wants_sync(&server);
+    let mut backoff = Backoff::new(interval, max_backoff);
+    let context = MoveContext {
+        outcomes: Outcomes::default(),
+        ..context
+    };
+    let mut torrent_client: Option<TorrentClient> = None;
+    let mut api_version = None;
     loop {
-        if let Err(e) = process_all_servers(&config.servers).await {
-            error!("Error processing servers: {}", e);
+        let mut report = CycleReport::new(&server.qbit_url, context.outcomes.take());
+        report.started = context.outcomes.take_started();
+        // The client keeps its connections and login between polls. After a
+        // connection or login error it is built again, which detects the Web
+        // API version anew in case qBittorrent was restarted or upgraded.
+        let connected = match torrent_client.take() {
+            Some(client) => Ok(client),
+            None => connect(&server, &mut api_version).await,
+        };
+        let result = match connected {
+            Ok(client) => {
+                let result = process_single_server(&client, &context, &mut report).await;
+                let lost = report.error.as_ref().is_some_and(|error| {
+                    matches!(error.kind, ErrorKind::Connectivity | ErrorKind::Auth)
+                });
+                if !lost {
+                    torrent_client = Some(client);
+                }
+                result
+            }
+            Err(e) => Err(e),
+        };
+        let delay = match result {
+            Ok(()) => {
+                if backoff.succeed() {
+                    info!("{} is reachable again", server.qbit_url);
+                }
+                if let (false, Some(client)) = (synced, torrent_client.as_mut()) {
+                    synced = sync_server_categories(&mut server, client, &context.path_guard).await;
+                }
+                interval
+            }
+            Err(e) => {
+                report.error = Some(ReportedError::from(&e));
+                let delay = backoff.fail();
+                if backoff.failures() == 1 {
+                    warn!("{} is unreachable, backing off: {}", server.qbit_url, e);
+                } else {
+                    debug!(
+                        "{} is still unreachable after {} attempts, retrying in {}: {}",
+                        server.qbit_url,
+                        backoff.failures(),
+                        units::format_duration(delay),
+                        e
+                    );
+                }
+                delay
+            }
+        };
+        publish_report(&context, report, backoff.failures() > 1);
+
+        tokio::select! {
+            _ = shutdown.changed() => break,
+            _ = sleep(delay) => {}
         }
+    }
+}
+
+async fn main_loop(config: config::Config, mut shutdown_signal: OneshotReceiver<()>) -> Result<()> {
+    let progress_interval =
+        (!config.progress_interval.is_zero()).then(|| config.progress_interval.get());
+    let context = MoveContext {
+        queue: MoveQueue::new(config.max_concurrent_moves, config.max_moves_per_device),
+        copier: Copier::new(
+            Bandwidth::from_config(&config.bandwidth)?,
+            progress_interval,
+        ),
+        preflight: Arc::new(Preflight::from_config(
+            &config,
+            Notifier::new(config.notify_command.clone()),
+        )),
+        deferrals: Deferrals::default(),
+        path_guard: Arc::new(PathGuard::from_config(&config)),
+        in_flight: InFlight::new(),
+        retry: config.retry,
+        failures: FailureLog::new(
+            &config.failures_file,
+            config.max_failures,
+            Notifier::new(config.notify_command.clone()),
+        ),
+        reports: Reports::default(),
+        outcomes: Outcomes::default(),
+        notifier: Notifier::new(config.notify_command.clone()),
+    };
+
+    // Each server polls on its own, so a slow or offline one does not hold
+    // up the others
+    let (stop_servers, stopped) = watch_channel(false);
+    let server_loops: Vec<_> = config
+        .servers
+        .iter()
+        .map(|server| {
+            let interval = server.poll_interval.unwrap_or(config.rate_limit_delay);
+            tokio::spawn(server_loop(
+                server.clone(),
+                context.clone(),
+                interval.get(),
+                config.max_offline_backoff.get(),
+                stopped.clone(),
+            ))
+        })
+        .collect();
+
+    let write_status = || {
+        let report = status::StatusReport::collect(&context.in_flight, &context.reports);
+        if let Err(e) = status::write_status(&config.status_file, &report) {
+            error!("Error writing status file: {}", e);
+        }
+    };
+    loop {
+        write_status();
 
         tokio::select! {
             Ok(_) = &mut shutdown_signal => {
                 info!("Received shutdown signal. Exiting...");
                 break;
             }
-            _ = sleep(Duration::from_secs(config.rate_limit_delay)) => {}
+            _ = sleep(config.rate_limit_delay.get()) => {}
         }
     }
+
+    let _ = stop_servers.send(true);
+    join_all(server_loops).await;
+    // Keep the last reports of every server for `status`
+    write_status();
     Ok(())
 }
 
@@ -113,7 +530,6 @@ mod tests {
     use super::*;
     use anyhow::Result;
     use mockito::Server;
-    use tokio;
 
     #[tokio::test]
     async fn test_main_loop() -> Result<()> {
@@ -123,8 +539,9 @@ mod tests {
         // Start the mock server
         let mut server = Server::new();
         let m1 = server
-            .mock("GET", "/api/v2/app/version")
+            .mock("GET", "/api/v2/app/webapiVersion")
             .with_status(200)
+            .with_body("2.11.2")
             .expect(1)
             .create();
         let m2 = server
@@ -135,7 +552,13 @@ mod tests {
             .create();
 
         // Update the config to use the mock server
+        let status_dir = tempfile::tempdir()?;
         let mut config = config::Config::default();
+        config.status_file = status_dir
+            .path()
+            .join("status.json")
+            .to_string_lossy()
+            .into_owned();
         config.servers = vec![config::ServerConfig {
             qbit_url: server.url(),
             ..Default::default()
@@ -154,7 +577,269 @@ mod tests {
         // Verify the mock expectations
         m1.assert();
         m2.assert();
+        assert!(status_dir.path().join("status.json").exists());
+
+        Ok(())
+    }
 
+    #[tokio::test]
+    async fn test_offline_server_backs_off_alone() -> Result<()> {
+        let (shutdown_sender, shutdown_receiver) = oneshot_channel();
+        let mut online = Server::new_async().await;
+        let online_version = online
+            .mock("GET", "/api/v2/app/webapiVersion")
+            .with_status(200)
+            .with_body("2.11.2")
+            .expect(1)
+            .create();
+        let online_torrents = online
+            .mock("GET", "/api/v2/torrents/info?filter=completed")
+            .with_status(200)
+            .with_body("[]")
+            .expect_at_least(5)
+            .create();
+        let mut offline = Server::new_async().await;
+        let offline_version = offline
+            .mock("GET", "/api/v2/app/webapiVersion")
+            .with_status(503)
+            .expect_at_most(5)
+            .create();
+
+        let status_dir = tempfile::tempdir()?;
+        let mut config = config::Config::default();
+        config.status_file = status_dir
+            .path()
+            .join("status.json")
+            .to_string_lossy()
+            .into_owned();
+        config.servers = [online.url(), offline.url()]
+            .into_iter()
+            .map(|qbit_url| config::ServerConfig {
+                qbit_url,
+                poll_interval: Some(units::HumanDuration(Duration::from_millis(100))),
+                ..Default::default()
+            })
+            .collect();
+
+        let main_loop_future = tokio::spawn(main_loop(config, shutdown_receiver));
+        sleep(Duration::from_secs(1)).await;
+        let _ = shutdown_sender.send(());
+        main_loop_future.await??;
+
+        // The online server is connected to once and then only polled
+        online_version.assert();
+        online_torrents.assert();
+        offline_version.assert();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_server_loop_reconnects_after_login_error() -> Result<()> {
+        let (shutdown_sender, shutdown_receiver) = oneshot_channel();
+        let mut server = Server::new_async().await;
+        let version = server
+            .mock("GET", "/api/v2/app/webapiVersion")
+            .with_status(200)
+            .with_body("2.11.2")
+            .expect_at_least(2)
+            .create();
+        let _torrents = server
+            .mock("GET", "/api/v2/torrents/info?filter=completed")
+            .with_status(403)
+            .create();
+
+        let status_dir = tempfile::tempdir()?;
+        let mut config = config::Config::default();
+        config.status_file = status_dir
+            .path()
+            .join("status.json")
+            .to_string_lossy()
+            .into_owned();
+        config.servers = vec![config::ServerConfig {
+            qbit_url: server.url(),
+            poll_interval: Some(units::HumanDuration(Duration::from_millis(100))),
+            ..Default::default()
+        }];
+
+        let main_loop_future = tokio::spawn(main_loop(config, shutdown_receiver));
+        sleep(Duration::from_millis(500)).await;
+        let _ = shutdown_sender.send(());
+        main_loop_future.await??;
+
+        version.assert();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failing_torrent_is_quarantined() -> Result<()> {
+        let (shutdown_sender, shutdown_receiver) = oneshot_channel();
+        let mut server = Server::new_async().await;
+        let _version = server
+            .mock("GET", "/api/v2/app/webapiVersion")
+            .with_status(200)
+            .with_body("2.11.2")
+            .create();
+        let dir = tempfile::tempdir()?;
+        let downloads = dir.path().join("downloads");
+        std::fs::create_dir(&downloads)?;
+        let _torrents = server
+            .mock("GET", "/api/v2/torrents/info?filter=completed")
+            .with_status(200)
+            .with_body(format!(
+                r#"[{{"save_path": {:?}, "name": "missing", "category": "movies", "hash": "abc"}}]"#,
+                downloads
+            ))
+            .create();
+
+        let mut config = config::Config::default();
+        config.status_file = dir
+            .path()
+            .join("status.json")
+            .to_string_lossy()
+            .into_owned();
+        config.failures_file = dir
+            .path()
+            .join("failures.json")
+            .to_string_lossy()
+            .into_owned();
+        config.max_failures = 2;
+        config.servers = vec![config::ServerConfig {
+            qbit_url: server.url(),
+            categories: [(
+                String::from("movies"),
+                dir.path().to_string_lossy().into_owned(),
+            )]
+            .into(),
+            download_root: Some(dir.path().to_string_lossy().into_owned()),
+            poll_interval: Some(units::HumanDuration(Duration::from_millis(100))),
+            ..Default::default()
+        }];
+        let failures = FailureLog::new(&config.failures_file, 2, Notifier::default());
+        let config_status_file = config.status_file.clone();
+
+        let main_loop_future = tokio::spawn(main_loop(config, shutdown_receiver));
+        sleep(Duration::from_secs(1)).await;
+        let _ = shutdown_sender.send(());
+        main_loop_future.await??;
+
+        // Quarantined torrents are no longer tried
+        let entry = &failures.load()?["abc"];
+        assert!(entry.quarantined);
+        assert_eq!(entry.failures, 2);
+        assert!(
+            entry.last_error.contains("does not exist"),
+            "{}",
+            entry.last_error
+        );
+
+        // The failures show up in the server's cycle report
+        let status = status::read_status(&config_status_file)?;
+        assert_eq!(status.servers.len(), 1);
+        assert!(
+            status.render().contains("path mapping error"),
+            "{}",
+            status.render()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_torrents_with_nothing_to_move_are_not_reported() -> Result<()> {
+        let (shutdown_sender, shutdown_receiver) = oneshot_channel();
+        let dir = tempfile::tempdir()?;
+        let movies = dir.path().join("movies");
+        std::fs::create_dir_all(&movies)?;
+        let mut server = Server::new_async().await;
+        let _api = server
+            .mock("GET", "/api/v2/app/webapiVersion")
+            .with_status(200)
+            .with_body("2.11.2")
+            .create();
+        let _torrents = server
+            .mock("GET", "/api/v2/torrents/info?filter=completed")
+            .with_status(200)
+            .with_body(format!(
+                r#"[{{"save_path": "/downloads", "name": "distro", "category": "unmapped", "hash": "abc"}},
+                    {{"save_path": {:?}, "name": "movie", "category": "movies", "hash": "def"}}]"#,
+                movies.to_string_lossy()
+            ))
+            .create();
+
+        let mut config = config::Config::default();
+        config.status_file = dir
+            .path()
+            .join("status.json")
+            .to_string_lossy()
+            .into_owned();
+        config.failures_file = dir
+            .path()
+            .join("failures.json")
+            .to_string_lossy()
+            .into_owned();
+        config.servers = vec![config::ServerConfig {
+            qbit_url: server.url(),
+            categories: [(
+                String::from("movies"),
+                movies.to_string_lossy().into_owned(),
+            )]
+            .into(),
+            poll_interval: Some(units::HumanDuration(Duration::from_millis(100))),
+            ..Default::default()
+        }];
+        let config_status_file = config.status_file.clone();
+        let config_failures_file = config.failures_file.clone();
+
+        let main_loop_future = tokio::spawn(main_loop(config, shutdown_receiver));
+        sleep(Duration::from_millis(500)).await;
+        let _ = shutdown_sender.send(());
+        main_loop_future.await??;
+
+        let status = status::read_status(&config_status_file)?;
+        assert_eq!(status.servers.len(), 1);
+        assert_eq!(status.servers[0].started, 0);
+        assert!(status.servers[0].torrents.is_empty());
+        assert!(!Path::new(&config_failures_file).exists());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_check_api_versions() -> Result<()> {
+        let mut supported = Server::new_async().await;
+        let _api = supported
+            .mock("GET", "/api/v2/app/webapiVersion")
+            .with_status(200)
+            .with_body("2.9.3")
+            .create();
+        let offline = config::ServerConfig {
+            qbit_url: String::from("http://127.0.0.1:1"),
+            ..Default::default()
+        };
+        let servers = vec![
+            config::ServerConfig {
+                qbit_url: supported.url(),
+                ..Default::default()
+            },
+            offline,
+        ];
+        check_api_versions(&servers).await?;
+
+        let mut unsupported = Server::new_async().await;
+        let _api = unsupported
+            .mock("GET", "/api/v2/app/webapiVersion")
+            .with_status(200)
+            .with_body("1.0")
+            .create();
+        let err = check_api_versions(&[config::ServerConfig {
+            qbit_url: unsupported.url(),
+            ..Default::default()
+        }])
+        .await
+        .unwrap_err();
+        assert!(
+            err.to_string().contains("Web API 1.0.0 is not supported"),
+            "{}",
+            err
+        );
         Ok(())
     }
 }
@@ -0,0 +1,237 @@
+/*
+qBittorrent Mover - A tool to automatically move torrents to different categories based on their state.
+Copyright (C) 2023 Harrison Chin
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use anyhow::Result;
+use serde_yaml::{Mapping, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Directory next to the config file whose files are merged into it.
+pub const INCLUDE_DIR: &str = "conf.d";
+
+/// Merges `overlay` into `base`. Mappings are merged key by key, anything
+/// else in `overlay` replaces what `base` had.
+pub fn merge_values(base: &mut Value, overlay: Value) {
+    match (base, overlay) {
+        (Value::Mapping(base), Value::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_values(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
+/// Merges an included document into the main one. Its `servers` are appended
+/// to the existing list; every other key is merged with [`merge_values`].
+pub fn merge_document(base: &mut Value, mut overlay: Value) {
+    // An empty file parses as null and contributes nothing
+    if overlay.is_null() {
+        return;
+    }
+    let extra_servers = overlay
+        .as_mapping_mut()
+        .and_then(|overlay| overlay.remove("servers"));
+    if let (Some(Value::Sequence(extra)), Some(base)) = (extra_servers, base.as_mapping_mut()) {
+        match base.get_mut("servers") {
+            Some(Value::Sequence(servers)) => servers.extend(extra),
+            _ => {
+                base.insert("servers".into(), Value::Sequence(extra));
+            }
+        }
+    }
+    merge_values(base, overlay);
+}
+
+/// Files in the include directory, sorted by name so they merge in a
+/// predictable order. `extensions` limits them to formats that can be read.
+pub fn include_files(dir: &Path, extensions: &[&str]) -> Result<Vec<PathBuf>> {
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .map_err(|e| anyhow::anyhow!("Unable to read {}: {}", dir.display(), e))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.is_file()
+                && path
+                    .extension()
+                    .and_then(|extension| extension.to_str())
+                    .is_some_and(|extension| extensions.contains(&extension))
+        })
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+fn template_names(server: &mut Value) -> Result<Vec<String>> {
+    let Some(template) = server
+        .as_mapping_mut()
+        .and_then(|server| server.remove("template"))
+    else {
+        return Ok(Vec::new());
+    };
+    let names = match template {
+        Value::String(name) => vec![Value::String(name)],
+        Value::Sequence(names) => names,
+        other => vec![other],
+    };
+    names
+        .into_iter()
+        .map(|name| match name {
+            Value::String(name) => Ok(name),
+            _ => Err(anyhow::anyhow!(
+                "template must be a template name or a list of names"
+            )),
+        })
+        .collect()
+}
+
+/// Expands every server from `server_defaults`, then the templates it names
+/// with `template` (a name or a list, applied in order), then its own keys.
+/// The `templates` and `server_defaults` sections are removed afterwards.
+pub fn apply_templates(document: &mut Value) -> Result<()> {
+    let Some(mapping) = document.as_mapping_mut() else {
+        return Ok(());
+    };
+    let templates = match mapping.remove("templates") {
+        None | Some(Value::Null) => Mapping::new(),
+        Some(Value::Mapping(templates)) => templates,
+        Some(_) => return Err(anyhow::anyhow!("templates must be a mapping of names")),
+    };
+    let defaults = mapping
+        .remove("server_defaults")
+        .unwrap_or(Value::Mapping(Mapping::new()));
+    let Some(Value::Sequence(servers)) = mapping.get_mut("servers") else {
+        return Ok(());
+    };
+
+    for (index, server) in servers.iter_mut().enumerate() {
+        let mut merged = defaults.clone();
+        for name in
+            template_names(server).map_err(|e| anyhow::anyhow!("servers[{}]: {}", index, e))?
+        {
+            let template = templates.get(name.as_str()).ok_or_else(|| {
+                let known: Vec<_> = templates.keys().filter_map(|key| key.as_str()).collect();
+                anyhow::anyhow!(
+                    "servers[{}]: unknown template {:?} (defined: {:?})",
+                    index,
+                    name,
+                    known
+                )
+            })?;
+            merge_values(&mut merged, template.clone());
+        }
+        merge_values(&mut merged, std::mem::take(server));
+        *server = merged;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn yaml(text: &str) -> Value {
+        serde_yaml::from_str(text).unwrap()
+    }
+
+    #[test]
+    fn test_merge_document_appends_servers() {
+        let mut base =
+            yaml("servers: [{qbit_url: a}]\nrate_limit_delay: 5\nbandwidth: {limit: 1M}\n");
+        merge_document(
+            &mut base,
+            yaml("servers: [{qbit_url: b}]\nbandwidth: {schedule: []}\n"),
+        );
+        assert_eq!(
+            base,
+            yaml("servers: [{qbit_url: a}, {qbit_url: b}]\nrate_limit_delay: 5\nbandwidth: {limit: 1M, schedule: []}\n")
+        );
+    }
+
+    #[test]
+    fn test_apply_templates() -> Result<()> {
+        let mut document = yaml(
+            r#"
+server_defaults:
+  username: admin
+  categories:
+    movies: /media/movies
+templates:
+  tv:
+    categories:
+      tv: /media/tv
+  seedbox:
+    username: seed
+servers:
+  - qbit_url: a
+  - qbit_url: b
+    template: [tv, seedbox]
+    categories:
+      movies: /media/other-movies
+"#,
+        );
+        apply_templates(&mut document)?;
+        assert_eq!(
+            document,
+            yaml(
+                r#"
+servers:
+  - username: admin
+    categories:
+      movies: /media/movies
+    qbit_url: a
+  - username: seed
+    categories:
+      movies: /media/other-movies
+      tv: /media/tv
+    qbit_url: b
+"#
+            )
+        );
+
+        let mut unknown = yaml("servers: [{qbit_url: a, template: missing}]\n");
+        assert!(apply_templates(&mut unknown)
+            .unwrap_err()
+            .to_string()
+            .contains("unknown template \"missing\""));
+        Ok(())
+    }
+
+    #[test]
+    fn test_include_files_sorted() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        for name in ["20-b.yaml", "10-a.yml", "notes.txt", "30-c.yaml"] {
+            fs::write(dir.path().join(name), "")?;
+        }
+        let files = include_files(dir.path(), &["yaml", "yml"])?;
+        let names: Vec<_> = files
+            .iter()
+            .map(|path| path.file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(names, vec!["10-a.yml", "20-b.yaml", "30-c.yaml"]);
+        assert!(include_files(&dir.path().join("missing"), &["yaml"])?.is_empty());
+        Ok(())
+    }
+}
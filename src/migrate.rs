@@ -0,0 +1,267 @@
+/*
+qBittorrent Mover - A tool to automatically move torrents to different categories based on their state.
+Copyright (C) 2023 Harrison Chin
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use super::config::config_files;
+use super::format::Format;
+use anyhow::Result;
+use serde_yaml::{Mapping, Value};
+use std::fs;
+use std::path::Path;
+
+/// Layout version written by this build. Files without a `version` key are
+/// version 1.
+pub const CURRENT_VERSION: u64 = 2;
+
+/// One step of the migration chain, upgrading a document from `from` to
+/// `from + 1`.
+struct Migration {
+    from: u64,
+    description: &'static str,
+    apply: fn(&mut Mapping) -> Result<()>,
+}
+
+/// Each layout change adds a step here. Categories are still bare directory
+/// strings, so there is no step turning them into rule objects yet.
+const MIGRATIONS: &[Migration] = &[Migration {
+    from: 1,
+    description: "moved path_prefix and root_path into path_mappings",
+    apply: path_prefix_to_mappings,
+}];
+
+/// Every mapping that holds server settings: the servers themselves, the
+/// templates and `server_defaults`.
+fn server_settings(document: &mut Mapping) -> Vec<&mut Mapping> {
+    let mut settings = Vec::new();
+    for (key, value) in document.iter_mut() {
+        match (key.as_str(), value) {
+            (Some("servers"), Value::Sequence(servers)) => {
+                settings.extend(servers.iter_mut().filter_map(Value::as_mapping_mut))
+            }
+            (Some("templates"), Value::Mapping(templates)) => settings.extend(
+                templates
+                    .iter_mut()
+                    .filter_map(|(_, template)| template.as_mapping_mut()),
+            ),
+            (Some("server_defaults"), Value::Mapping(defaults)) => settings.push(defaults),
+            _ => {}
+        }
+    }
+    settings
+}
+
+/// Version 1 had a single `path_prefix` replaced by `root_path`; it becomes
+/// one more entry of `path_mappings`. A `root_path` without a prefix was
+/// never used and is dropped.
+fn path_prefix_to_mappings(document: &mut Mapping) -> Result<()> {
+    for server in server_settings(document) {
+        let root = server.remove("root_path");
+        let prefix = match server.remove("path_prefix") {
+            None | Some(Value::Null) => continue,
+            Some(Value::String(prefix)) => prefix,
+            Some(_) => return Err(anyhow::anyhow!("path_prefix must be a string")),
+        };
+        let local = match root {
+            Some(Value::String(root)) => root,
+            _ => String::new(),
+        };
+
+        let mut mapping = Mapping::new();
+        mapping.insert("remote".into(), prefix.into());
+        mapping.insert("local".into(), local.into());
+        match server.get_mut("path_mappings") {
+            Some(Value::Sequence(mappings)) => mappings.push(Value::Mapping(mapping)),
+            _ => {
+                server.insert(
+                    "path_mappings".into(),
+                    Value::Sequence(vec![Value::Mapping(mapping)]),
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The layout version a document declares.
+pub fn document_version(document: &Value) -> Result<u64> {
+    match document.get("version") {
+        None | Some(Value::Null) => Ok(1),
+        Some(version) => version
+            .as_u64()
+            .filter(|version| *version >= 1)
+            .ok_or_else(|| anyhow::anyhow!("version must be a positive whole number")),
+    }
+}
+
+/// Upgrades `document` to [`CURRENT_VERSION`] in place and sets its
+/// `version`. Returns a description of each step that changed something.
+pub fn migrate(document: &mut Value) -> Result<Vec<String>> {
+    let version = document_version(document)?;
+    if version > CURRENT_VERSION {
+        return Err(anyhow::anyhow!(
+            "Config version {} is newer than this build supports ({}); upgrade qbittorrent_mover",
+            version,
+            CURRENT_VERSION
+        ));
+    }
+    let Some(mapping) = document.as_mapping_mut() else {
+        return Ok(Vec::new());
+    };
+
+    let mut applied = Vec::new();
+    for migration in MIGRATIONS
+        .iter()
+        .filter(|migration| migration.from >= version)
+    {
+        let before = mapping.clone();
+        (migration.apply)(mapping).map_err(|e| {
+            anyhow::anyhow!("Migrating from version {} failed: {}", migration.from, e)
+        })?;
+        if *mapping == before {
+            continue;
+        }
+        applied.push(format!(
+            "version {} to {}: {}",
+            migration.from,
+            migration.from + 1,
+            migration.description
+        ));
+    }
+    mapping.insert("version".into(), CURRENT_VERSION.into());
+    Ok(applied)
+}
+
+/// Upgrades the config file and its `conf.d` files in place. Each file that
+/// changes is first copied to `<file>.v<old version>.bak`.
+pub fn run(config_file: &str) -> Result<()> {
+    for file in config_files(config_file)? {
+        let contents = fs::read_to_string(&file)
+            .map_err(|e| anyhow::anyhow!("Unable to read {}: {}", file, e))?;
+        let format = Format::from_path(Path::new(&file))?;
+        let mut document = format
+            .parse(&contents)
+            .map_err(|e| anyhow::anyhow!("Unable to parse {}:{}", file, e))?;
+        let version = document_version(&document)?;
+        let applied = migrate(&mut document).map_err(|e| anyhow::anyhow!("{}: {}", file, e))?;
+        if applied.is_empty() {
+            println!("{} is up to date", file);
+            continue;
+        }
+
+        let backup = format!("{}.v{}.bak", file, version);
+        fs::copy(&file, &backup)?;
+        fs::write(&file, format.render(&document)?)?;
+        println!(
+            "Upgraded {} to version {}, backup in {}:",
+            file, CURRENT_VERSION, backup
+        );
+        for step in applied {
+            println!("  {}", step);
+        }
+        println!("  Comments were not kept; copy any you need from the backup");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn yaml(text: &str) -> Value {
+        serde_yaml::from_str(text).unwrap()
+    }
+
+    #[test]
+    fn test_migrate_path_prefix() -> Result<()> {
+        let mut document = yaml(
+            r#"
+templates:
+  nas:
+    path_prefix: /data
+servers:
+  - qbit_url: a
+    path_prefix: /downloads
+    root_path: /srv/downloads
+    path_mappings:
+      - remote: /incomplete
+        local: /srv/incomplete
+  - qbit_url: b
+    root_path: /unused
+"#,
+        );
+        let applied = migrate(&mut document)?;
+        assert_eq!(applied.len(), 1);
+        assert_eq!(
+            document,
+            yaml(
+                r#"
+templates:
+  nas:
+    path_mappings:
+      - remote: /data
+        local: ""
+servers:
+  - qbit_url: a
+    path_mappings:
+      - remote: /incomplete
+        local: /srv/incomplete
+      - remote: /downloads
+        local: /srv/downloads
+  - qbit_url: b
+version: 2
+"#
+            )
+        );
+
+        // Already current documents are left alone
+        assert!(migrate(&mut document)?.is_empty());
+        let mut unversioned = yaml("servers: [{qbit_url: a}]\n");
+        assert!(migrate(&mut unversioned)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_writes_backup() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config_file = dir.path().join("config.yaml");
+        let original = "servers:\n  - qbit_url: a\n    path_prefix: /downloads\n";
+        fs::write(&config_file, original)?;
+
+        run(config_file.to_str().unwrap())?;
+        assert_eq!(
+            fs::read_to_string(dir.path().join("config.yaml.v1.bak"))?,
+            original
+        );
+        let upgraded: Value = serde_yaml::from_str(&fs::read_to_string(&config_file)?)?;
+        assert_eq!(upgraded["version"], Value::from(CURRENT_VERSION));
+        assert_eq!(
+            upgraded["servers"][0]["path_mappings"][0]["remote"],
+            Value::from("/downloads")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_rejects_newer_versions() {
+        let mut document = yaml("version: 99\nservers: []\n");
+        assert!(migrate(&mut document)
+            .unwrap_err()
+            .to_string()
+            .contains("newer"));
+        assert!(migrate(&mut yaml("version: zero\n")).is_err());
+    }
+}
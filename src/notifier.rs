@@ -0,0 +1,89 @@
+/*
+qBittorrent Mover - A tool to automatically move torrents to different categories based on their state.
+Copyright (C) 2023 Harrison Chin
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use log::error;
+use std::process::Command;
+
+/// Runs the configured `notify_command` through `sh -c` for events that need
+/// an operator's attention. The event name and message are passed in the
+/// `QBIT_MOVER_EVENT` and `QBIT_MOVER_MESSAGE` environment variables.
+#[derive(Clone, Default)]
+pub struct Notifier {
+    command: Option<String>,
+}
+
+impl Notifier {
+    pub fn new(command: Option<String>) -> Self {
+        Self { command }
+    }
+
+    /// Starts the notification command without waiting for it to finish.
+    pub fn notify(&self, event: &str, message: &str) {
+        let Some(command) = &self.command else {
+            return;
+        };
+
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .env("QBIT_MOVER_EVENT", event)
+            .env("QBIT_MOVER_MESSAGE", message)
+            .spawn();
+        match child {
+            Ok(mut child) => {
+                // Reap the child in the background so it does not linger as a zombie
+                std::thread::spawn(move || child.wait());
+            }
+            Err(e) => error!("Failed to run notify_command for {}: {}", event, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_notify_runs_command() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let output = dir.path().join("notification");
+        let notifier = Notifier::new(Some(format!(
+            "echo \"$QBIT_MOVER_EVENT: $QBIT_MOVER_MESSAGE\" > '{}'",
+            output.display()
+        )));
+        notifier.notify("test_event", "something happened");
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !output.exists() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(
+            fs::read_to_string(&output)?.trim(),
+            "test_event: something happened"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_notify_without_command_is_noop() {
+        Notifier::default().notify("test_event", "nothing to see");
+    }
+}
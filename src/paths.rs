@@ -0,0 +1,402 @@
+/*
+qBittorrent Mover - A tool to automatically move torrents to different categories based on their state.
+Copyright (C) 2023 Harrison Chin
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use super::config::{Config, ServerConfig};
+use super::errors::{conflict, path_mapping};
+use anyhow::Result;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+
+/// Rejects torrent names that are not a single plain path component, such as
+/// "..", absolute paths or names containing separators.
+pub fn validate_torrent_name(name: &str) -> Result<()> {
+    let mut components = Path::new(name).components();
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(_)), None) => Ok(()),
+        _ => Err(path_mapping(format!(
+            "Refusing unsafe torrent name: {:?}",
+            name
+        ))),
+    }
+}
+
+/// Canonicalizes `path` and `root` and checks that the former lies inside the
+/// latter. Returns the canonical path.
+pub fn ensure_within(path: &Path, root: &Path) -> Result<PathBuf> {
+    let canonical_root = root
+        .canonicalize()
+        .map_err(|e| path_mapping(format!("Unable to resolve {:?}: {}", root, e)))?;
+    let canonical_path = path
+        .canonicalize()
+        .map_err(|e| path_mapping(format!("Unable to resolve {:?}: {}", path, e)))?;
+    if !canonical_path.starts_with(&canonical_root) {
+        return Err(path_mapping(format!(
+            "Refusing to touch {:?}: it resolves to {:?}, outside of {:?}",
+            path, canonical_path, canonical_root
+        )));
+    }
+    Ok(canonical_path)
+}
+
+/// Canonicalizes the closest existing ancestor of `path` and appends the
+/// remaining components, so paths that do not exist yet can be compared.
+fn resolve_existing(path: &Path) -> PathBuf {
+    let mut missing = Vec::new();
+    let mut current = path;
+    loop {
+        if let Ok(canonical) = current.canonicalize() {
+            return missing.iter().rev().fold(canonical, |acc, c| acc.join(c));
+        }
+        match (current.parent(), current.file_name()) {
+            (Some(parent), Some(name)) => {
+                missing.push(name.to_os_string());
+                current = parent;
+            }
+            _ => return path.to_path_buf(),
+        }
+    }
+}
+
+fn is_symlink(path: &Path) -> bool {
+    fs::symlink_metadata(path)
+        .map(|metadata| metadata.file_type().is_symlink())
+        .unwrap_or(false)
+}
+
+/// A path translated through a [`PathMapper`], along with the local root it
+/// must stay inside: that of the mapping that produced it, or the server's
+/// `download_root` when it has no mappings.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MappedPath {
+    pub root: Option<PathBuf>,
+    pub path: PathBuf,
+}
+
+/// Ordered list of remote-to-local path translations for one server.
+pub struct PathMapper {
+    mappings: Vec<(PathBuf, PathBuf)>,
+    download_root: Option<PathBuf>,
+}
+
+impl PathMapper {
+    pub fn from_server(server: &ServerConfig) -> Self {
+        let mappings = server
+            .path_mappings
+            .iter()
+            .map(|mapping| {
+                (
+                    PathBuf::from(&mapping.remote),
+                    PathBuf::from(&mapping.local),
+                )
+            })
+            .collect();
+        Self {
+            mappings,
+            download_root: server.download_root.as_ref().map(PathBuf::from),
+        }
+    }
+
+    /// Translates `remote` using the mapping with the longest matching
+    /// prefix. Without any mappings paths are used unchanged, under the
+    /// server's `download_root`.
+    pub fn map(&self, remote: &str) -> Result<MappedPath> {
+        let remote_path = Path::new(remote);
+        if remote_path
+            .components()
+            .any(|component| component == Component::ParentDir)
+        {
+            return Err(path_mapping(format!(
+                "Refusing path containing \"..\": {:?}",
+                remote
+            )));
+        }
+        if self.mappings.is_empty() {
+            return Ok(MappedPath {
+                root: self.download_root.clone(),
+                path: remote_path.to_path_buf(),
+            });
+        }
+
+        let (remote_prefix, local) = self
+            .mappings
+            .iter()
+            .filter(|(prefix, _)| remote_path.starts_with(prefix))
+            .max_by_key(|(prefix, _)| prefix.components().count())
+            .ok_or_else(|| {
+                let prefixes: Vec<_> = self.mappings.iter().map(|(prefix, _)| prefix).collect();
+                path_mapping(format!(
+                    "No path mapping covers {:?}; add a path_mappings entry whose remote prefix matches it (configured: {:?})",
+                    remote,
+                    prefixes
+                ))
+            })?;
+
+        let relative = remote_path.strip_prefix(remote_prefix)?;
+        Ok(MappedPath {
+            root: (!local.as_os_str().is_empty()).then(|| local.clone()),
+            path: local.join(relative),
+        })
+    }
+}
+
+/// Safety checks applied to every move before anything is copied or deleted.
+pub struct PathGuard {
+    protected: Mutex<Vec<PathBuf>>,
+}
+
+impl PathGuard {
+    pub fn from_config(config: &Config) -> Self {
+        let protected = config
+            .servers
+            .iter()
+            .flat_map(|server| server.categories.values())
+            .chain(config.destinations.keys())
+            .map(PathBuf::from)
+            .collect();
+        Self {
+            protected: Mutex::new(protected),
+        }
+    }
+
+    /// Adds a destination learned at runtime, such as a discovered category.
+    pub fn protect(&self, path: &Path) {
+        let mut protected = self.protected.lock().unwrap_or_else(|e| e.into_inner());
+        if !protected.iter().any(|existing| existing == path) {
+            protected.push(path.to_path_buf());
+        }
+    }
+
+    /// Requires `src` to be a real file or directory (not a symlink) inside
+    /// `source_root`, and `dest` to not be a symlink or lie inside `src`.
+    pub fn check_move(&self, source_root: &Path, src: &Path, dest: &Path) -> Result<()> {
+        if is_symlink(src) {
+            return Err(path_mapping(format!("Refusing to move symlink {:?}", src)));
+        }
+        let canonical_src = ensure_within(src, source_root)?;
+        if resolve_existing(dest).starts_with(&canonical_src) {
+            return Err(conflict(format!(
+                "Refusing to move {:?} into itself ({:?})",
+                src, dest
+            )));
+        }
+
+        if is_symlink(dest) {
+            return Err(conflict(format!(
+                "Refusing to write through symlink {:?}",
+                dest
+            )));
+        }
+        Ok(())
+    }
+
+    /// Refuses to delete `path` if it is, or contains, a configured
+    /// destination.
+    pub fn check_removable(&self, path: &Path) -> Result<()> {
+        let canonical_path = path.canonicalize()?;
+        let protected_paths = self.protected.lock().unwrap_or_else(|e| e.into_inner());
+        for protected in protected_paths.iter() {
+            let Ok(canonical_protected) = protected.canonicalize() else {
+                continue;
+            };
+            if canonical_protected.starts_with(&canonical_path) {
+                return Err(conflict(format!(
+                    "Refusing to delete {:?}: it contains the configured destination {:?}",
+                    path, protected
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PathMapping;
+    use std::collections::HashMap;
+
+    fn mapping(remote: &str, local: &str) -> PathMapping {
+        PathMapping {
+            remote: remote.to_string(),
+            local: local.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_path_mapper_longest_prefix() -> Result<()> {
+        let server = ServerConfig {
+            path_mappings: vec![
+                mapping("/downloads", "/srv/downloads"),
+                mapping("/downloads/incomplete", "/mnt/scratch"),
+                mapping("/media", "/mnt/media"),
+            ],
+            ..Default::default()
+        };
+        let mapper = PathMapper::from_server(&server);
+
+        let mapped = mapper.map("/downloads/tv/show")?;
+        assert_eq!(mapped.path, Path::new("/srv/downloads/tv/show"));
+        assert_eq!(mapped.root.as_deref(), Some(Path::new("/srv/downloads")));
+        assert_eq!(
+            mapper.map("/downloads/incomplete/file.mkv")?.path,
+            Path::new("/mnt/scratch/file.mkv")
+        );
+        assert_eq!(mapper.map("/media")?.path, Path::new("/mnt/media"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_path_mapper_rejects_uncovered_paths() {
+        let server = ServerConfig {
+            path_mappings: vec![mapping("/downloads", "/srv/downloads")],
+            ..Default::default()
+        };
+        let mapper = PathMapper::from_server(&server);
+
+        // Prefixes match whole components only
+        let err = mapper.map("/downloads2/file").unwrap_err();
+        assert!(err.to_string().contains("No path mapping covers"));
+        assert!(mapper.map("/downloads/../etc").is_err());
+    }
+
+    #[test]
+    fn test_path_mapper_without_mappings() -> Result<()> {
+        let unmapped = PathMapper::from_server(&ServerConfig::default());
+        let mapped = unmapped.map("/data/movies")?;
+        assert_eq!(mapped.path, Path::new("/data/movies"));
+        assert_eq!(mapped.root, None);
+
+        let rooted = PathMapper::from_server(&ServerConfig {
+            download_root: Some(String::from("/data")),
+            ..Default::default()
+        });
+        assert_eq!(
+            rooted.map("/data/movies")?.root.as_deref(),
+            Some(Path::new("/data"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_path_mapper_empty_local_prefix() -> Result<()> {
+        let server = ServerConfig {
+            path_mappings: vec![mapping("/data", "")],
+            ..Default::default()
+        };
+        let mapped = PathMapper::from_server(&server).map("/data/movies")?;
+        assert_eq!(mapped.path, Path::new("movies"));
+        assert_eq!(mapped.root, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_torrent_name() {
+        assert!(validate_torrent_name("Some.Linux.ISO").is_ok());
+        assert!(validate_torrent_name("name with spaces").is_ok());
+        assert!(validate_torrent_name("").is_err());
+        assert!(validate_torrent_name("..").is_err());
+        assert!(validate_torrent_name(".").is_err());
+        assert!(validate_torrent_name("/etc").is_err());
+        assert!(validate_torrent_name("../../etc").is_err());
+        assert!(validate_torrent_name("nested/name").is_err());
+    }
+
+    #[test]
+    fn test_ensure_within() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path().join("root");
+        fs::create_dir_all(root.join("inside"))?;
+        fs::create_dir_all(dir.path().join("outside"))?;
+
+        assert!(ensure_within(&root.join("inside"), &root).is_ok());
+        assert!(ensure_within(&root.join("..").join("outside"), &root).is_err());
+        assert!(ensure_within(&root.join("missing"), &root).is_err());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_check_move_rejects_symlinks() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let root = dir.path().join("downloads");
+        let outside = dir.path().join("outside");
+        fs::create_dir_all(&root)?;
+        fs::create_dir_all(&outside)?;
+        std::os::unix::fs::symlink(&outside, root.join("escape"))?;
+        fs::create_dir_all(root.join("real"))?;
+
+        let guard = PathGuard::from_config(&Config::default());
+        let dest = dir.path().join("dest");
+        assert!(guard
+            .check_move(&root, &root.join("escape"), &dest.join("escape"))
+            .is_err());
+        assert!(guard
+            .check_move(&root, &root.join("real"), &dest.join("real"))
+            .is_ok());
+
+        // A category whose destination is the download directory itself
+        assert!(guard
+            .check_move(&root, &root.join("real"), &root.join("real"))
+            .is_err());
+        assert!(guard
+            .check_move(
+                &root,
+                &root.join("real"),
+                &root.join("real").join("sub").join("real")
+            )
+            .is_err());
+
+        fs::create_dir_all(&dest)?;
+        std::os::unix::fs::symlink(&outside, dest.join("real"))?;
+        assert!(guard
+            .check_move(&root, &root.join("real"), &dest.join("real"))
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_removable_protects_destinations() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let media = dir.path().join("media");
+        let movies = media.join("movies");
+        let download = dir.path().join("downloads").join("torrent");
+        fs::create_dir_all(&movies)?;
+        fs::create_dir_all(&download)?;
+
+        let config = Config {
+            servers: vec![ServerConfig {
+                categories: HashMap::from([(
+                    String::from("movies"),
+                    movies.to_string_lossy().into_owned(),
+                )]),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let guard = PathGuard::from_config(&config);
+
+        assert!(guard.check_removable(&download).is_ok());
+        assert!(guard.check_removable(&movies).is_err());
+        assert!(guard.check_removable(&media).is_err());
+
+        guard.protect(&download);
+        assert!(guard.check_removable(&download).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,528 @@
+/*
+qBittorrent Mover - A tool to automatically move torrents to different categories based on their state.
+Copyright (C) 2023 Harrison Chin
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use super::config::Config;
+use super::copier::measure;
+use super::notifier::Notifier;
+use super::queue::device_id;
+use super::units::{format_size, ByteSize};
+use anyhow::Result;
+use log::{error, info};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a measured quota usage is trusted. Moves add to it as they
+/// finish; the tree is only walked again to pick up changes made by others.
+const USAGE_TTL: Duration = Duration::from_secs(600);
+
+/// Returned when a move cannot start yet. The torrent is left alone and tried
+/// again on the next cycle.
+#[derive(Debug)]
+pub struct Deferred(pub String);
+
+impl fmt::Display for Deferred {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Deferred {}
+
+/// Torrents whose moves are deferred, so that a deferral is logged when it
+/// starts rather than on every poll while it lasts.
+#[derive(Clone, Default)]
+pub struct Deferrals {
+    torrents: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Deferrals {
+    /// Returns true if the torrent was not deferred already.
+    pub fn defer(&self, hash: &str) -> bool {
+        let mut torrents = self.torrents.lock().unwrap_or_else(|e| e.into_inner());
+        torrents.insert(hash.to_string())
+    }
+
+    /// Forgets the deferral of a torrent whose move went ahead.
+    pub fn clear(&self, hash: &str) {
+        let mut torrents = self.torrents.lock().unwrap_or_else(|e| e.into_inner());
+        torrents.remove(hash);
+    }
+}
+
+struct DestinationLimits {
+    max_bytes: Option<u64>,
+    reserve: Option<u64>,
+    require_mountpoint: bool,
+    sentinel_file: Option<PathBuf>,
+}
+
+/// Checks that a destination can take a torrent before any data is copied.
+pub struct Preflight {
+    reserve: u64,
+    destinations: Vec<(PathBuf, DestinationLimits)>,
+    unavailable: Mutex<HashSet<PathBuf>>,
+    usage: Mutex<HashMap<PathBuf, (u64, Instant)>>, // Bytes below each quota root, when measured
+    reserved: Mutex<Reserved>,
+    notifier: Notifier,
+}
+
+/// Bytes promised to moves that passed the checks but are still copying.
+#[derive(Default)]
+struct Reserved {
+    devices: HashMap<u64, u64>,
+    roots: HashMap<PathBuf, u64>,
+}
+
+/// Space set aside for one move, released when the move ends.
+pub struct Reservation<'a> {
+    preflight: &'a Preflight,
+    device: u64,
+    root: Option<PathBuf>,
+    bytes: u64,
+}
+
+impl fmt::Debug for Reservation<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Reservation of {} on {:?}", self.bytes, self.root)
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        let mut reserved = self
+            .preflight
+            .reserved
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        release(&mut reserved.devices, self.device, self.bytes);
+        if let Some(root) = self.root.take() {
+            release(&mut reserved.roots, root, self.bytes);
+        }
+    }
+}
+
+fn release<K: std::hash::Hash + Eq>(map: &mut HashMap<K, u64>, key: K, bytes: u64) {
+    if let Some(total) = map.get_mut(&key) {
+        *total = total.saturating_sub(bytes);
+        if *total == 0 {
+            map.remove(&key);
+        }
+    }
+}
+
+/// Returns true if `path` is the root of a mounted filesystem.
+fn is_mountpoint(path: &Path) -> bool {
+    let Ok(path) = path.canonicalize() else {
+        return false;
+    };
+    match path.parent() {
+        Some(parent) => device_id(&path) != device_id(parent),
+        None => true,
+    }
+}
+
+impl Preflight {
+    pub fn from_config(config: &Config, notifier: Notifier) -> Self {
+        let destinations = config
+            .destinations
+            .iter()
+            .map(|(path, destination)| {
+                let root = PathBuf::from(path);
+                let limits = DestinationLimits {
+                    max_bytes: destination.max_bytes.map(ByteSize::bytes),
+                    reserve: destination.reserve.map(ByteSize::bytes),
+                    require_mountpoint: destination.require_mountpoint,
+                    sentinel_file: destination
+                        .sentinel_file
+                        .as_ref()
+                        .map(|sentinel| root.join(sentinel)),
+                };
+                (root, limits)
+            })
+            .collect();
+
+        Self {
+            reserve: config.free_space_reserve.bytes(),
+            destinations,
+            unavailable: Mutex::new(HashSet::new()),
+            usage: Mutex::new(HashMap::new()),
+            reserved: Mutex::new(Reserved::default()),
+            notifier,
+        }
+    }
+
+    /// Refuses to write into a destination whose filesystem is not mounted,
+    /// which would otherwise silently fill the empty mountpoint directory on
+    /// the root filesystem. Failures are reported loudly once per outage.
+    fn check_mounted(&self, root: &Path, limits: &DestinationLimits) -> Result<()> {
+        let problem = if limits.require_mountpoint && !is_mountpoint(root) {
+            Some(format!("{:?} is not a mountpoint", root))
+        } else {
+            match &limits.sentinel_file {
+                Some(sentinel) if !sentinel.exists() => {
+                    Some(format!("sentinel file {:?} is missing", sentinel))
+                }
+                _ => None,
+            }
+        };
+
+        let mut unavailable = self.unavailable.lock().unwrap_or_else(|e| e.into_inner());
+        match problem {
+            Some(problem) => {
+                if unavailable.insert(root.to_path_buf()) {
+                    let message = format!(
+                        "Destination {:?} looks unmounted ({}), pausing moves to it",
+                        root, problem
+                    );
+                    error!("{}", message);
+                    self.notifier.notify("destination_unavailable", &message);
+                }
+                Err(Deferred(format!(
+                    "Destination {:?} is unavailable: {}",
+                    root, problem
+                ))
+                .into())
+            }
+            None => {
+                if unavailable.remove(root) {
+                    let message =
+                        format!("Destination {:?} is available again, resuming moves", root);
+                    info!("{}", message);
+                    self.notifier.notify("destination_available", &message);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Bytes used below a quota root, walking the tree only when the cached
+    /// figure is missing or older than [`USAGE_TTL`].
+    fn used(&self, root: &Path) -> Result<u64> {
+        {
+            let usage = self.usage.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some((used, measured_at)) = usage.get(root) {
+                if measured_at.elapsed() < USAGE_TTL {
+                    return Ok(*used);
+                }
+            }
+        }
+        let used = if root.exists() { measure(root)?.0 } else { 0 };
+        let mut usage = self.usage.lock().unwrap_or_else(|e| e.into_inner());
+        usage.insert(root.to_path_buf(), (used, Instant::now()));
+        Ok(used)
+    }
+
+    /// Adds the bytes of a finished move to the cached usage of its quota
+    /// root, so that the next check sees them without walking the tree.
+    pub fn record_moved(&self, dest: &Path, bytes: u64) {
+        let Some((root, _)) = self.destination(dest) else {
+            return;
+        };
+        let mut usage = self.usage.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some((used, _)) = usage.get_mut(root) {
+            *used = used.saturating_add(bytes);
+        }
+    }
+
+    fn destination(&self, dest: &Path) -> Option<(&Path, &DestinationLimits)> {
+        self.destinations
+            .iter()
+            .filter(|(path, _)| dest.starts_with(path))
+            .max_by_key(|(path, _)| path.components().count())
+            .map(|(path, limits)| (path.as_path(), limits))
+    }
+
+    /// Verifies that the destination of `dest` is mounted and that `needed`
+    /// more bytes fit below it, both on the filesystem (keeping the reserve
+    /// free) and within any quota, counting the space set aside for moves
+    /// still copying. Sets `needed` bytes aside until the returned
+    /// reservation is dropped.
+    pub fn check(&self, dest: &Path, needed: u64) -> Result<Reservation<'_>> {
+        let destination = self.destination(dest);
+        if let Some((root, limits)) = destination {
+            self.check_mounted(root, limits)?;
+        }
+
+        let reserve = destination
+            .and_then(|(_, limits)| limits.reserve)
+            .unwrap_or(self.reserve);
+        let quota = destination.and_then(|(root, limits)| Some((root, limits.max_bytes?)));
+        let used = match quota {
+            Some((root, _)) => self.used(root)?,
+            None => 0,
+        };
+        let available = available_space(dest)?;
+        let device = device_id(dest);
+
+        // Checking and reserving under one lock keeps concurrent moves from
+        // all passing on the same free space
+        let mut reserved = self.reserved.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(available) = available {
+            let available =
+                available.saturating_sub(reserved.devices.get(&device).copied().unwrap_or(0));
+            if available < needed.saturating_add(reserve) {
+                return Err(Deferred(format!(
+                    "Not enough free space for {:?}: need {} plus a {} reserve, {} available",
+                    dest,
+                    format_size(needed),
+                    format_size(reserve),
+                    format_size(available)
+                ))
+                .into());
+            }
+        }
+
+        if let Some((root, max_bytes)) = quota {
+            let used = used.saturating_add(reserved.roots.get(root).copied().unwrap_or(0));
+            if used.saturating_add(needed) > max_bytes {
+                return Err(Deferred(format!(
+                    "Quota exceeded for {:?}: {} used of {}, {} more needed",
+                    root,
+                    format_size(used),
+                    format_size(max_bytes),
+                    format_size(needed)
+                ))
+                .into());
+            }
+        }
+
+        *reserved.devices.entry(device).or_default() += needed;
+        let root = quota.map(|(root, _)| root.to_path_buf());
+        if let Some(root) = &root {
+            *reserved.roots.entry(root.clone()).or_default() += needed;
+        }
+        Ok(Reservation {
+            preflight: self,
+            device,
+            root,
+            bytes: needed,
+        })
+    }
+}
+
+/// Returns the space available to unprivileged users on the filesystem that
+/// holds `path`, or of its closest existing ancestor.
+#[cfg(unix)]
+pub fn available_space(path: &Path) -> Result<Option<u64>> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let Some(existing) = path.ancestors().find(|ancestor| ancestor.exists()) else {
+        return Ok(None);
+    };
+    let existing = if existing.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        existing
+    };
+
+    let c_path = CString::new(existing.as_os_str().as_bytes())?;
+    // SAFETY: `c_path` is a valid NUL-terminated string and `stat` is a
+    // plain-old-data struct that statvfs fills in.
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+
+    #[allow(clippy::unnecessary_cast)]
+    Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+}
+
+#[cfg(not(unix))]
+pub fn available_space(_path: &Path) -> Result<Option<u64>> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DestinationConfig;
+    use std::fs;
+
+    fn preflight(dest: &Path, destination: DestinationConfig) -> Preflight {
+        let mut config = Config::default();
+        config
+            .destinations
+            .insert(dest.to_string_lossy().into_owned(), destination);
+        Preflight::from_config(&config, Notifier::default())
+    }
+
+    #[test]
+    fn test_available_space() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let space = available_space(&dir.path().join("missing").join("child"))?;
+        assert!(space.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_allows_small_moves() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let preflight = preflight(dir.path(), DestinationConfig::default());
+        preflight.check(&dir.path().join("torrent"), 1024)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_defers_when_reserve_exceeds_free_space() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let preflight = preflight(
+            dir.path(),
+            DestinationConfig {
+                reserve: Some(ByteSize(1 << 60)),
+                ..Default::default()
+            },
+        );
+        let err = preflight
+            .check(&dir.path().join("torrent"), 1024)
+            .unwrap_err();
+        assert!(err.is::<Deferred>());
+        assert!(err.to_string().contains("Not enough free space"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_enforces_quota() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("existing.mkv"), vec![0u8; 600])?;
+        let preflight = preflight(
+            dir.path(),
+            DestinationConfig {
+                max_bytes: Some(ByteSize(1024)),
+                ..Default::default()
+            },
+        );
+
+        preflight.check(&dir.path().join("small"), 400)?;
+        let err = preflight.check(&dir.path().join("big"), 500).unwrap_err();
+        assert!(err.is::<Deferred>());
+        assert!(err.to_string().contains("Quota exceeded"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_moves_reserve_quota() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let preflight = preflight(
+            dir.path(),
+            DestinationConfig {
+                max_bytes: Some(ByteSize(1024)),
+                ..Default::default()
+            },
+        );
+
+        let first = preflight.check(&dir.path().join("first"), 600)?;
+        let err = preflight
+            .check(&dir.path().join("second"), 600)
+            .unwrap_err();
+        assert!(err.to_string().contains("600 B used"), "{}", err);
+
+        // The space comes back once the first move ends
+        drop(first);
+        preflight.check(&dir.path().join("second"), 600)?;
+        assert!(preflight.reserved.lock().unwrap().roots.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_quota_usage_is_cached() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        fs::write(dir.path().join("existing.mkv"), vec![0u8; 600])?;
+        let preflight = preflight(
+            dir.path(),
+            DestinationConfig {
+                max_bytes: Some(ByteSize(1024)),
+                ..Default::default()
+            },
+        );
+        preflight.check(&dir.path().join("small"), 400)?;
+
+        // Files written since are not walked again...
+        fs::write(dir.path().join("other.mkv"), vec![0u8; 400])?;
+        preflight.check(&dir.path().join("small"), 400)?;
+
+        // ...but finished moves are counted
+        preflight.record_moved(&dir.path().join("small"), 400);
+        preflight.check(&dir.path().join("small"), 24)?;
+        let err = preflight.check(&dir.path().join("small"), 400).unwrap_err();
+        assert!(err.to_string().contains("1000 B used"), "{}", err);
+        Ok(())
+    }
+
+    #[test]
+    fn test_deferrals_are_reported_once() {
+        let deferrals = Deferrals::default();
+        assert!(deferrals.defer("abc"));
+        assert!(!deferrals.defer("abc"));
+        deferrals.clear("abc");
+        assert!(deferrals.defer("abc"));
+    }
+
+    #[test]
+    fn test_is_mountpoint() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        assert!(is_mountpoint(Path::new("/")));
+        assert!(!is_mountpoint(&dir.path().join("missing")));
+        fs::create_dir(dir.path().join("plain"))?;
+        assert!(!is_mountpoint(&dir.path().join("plain")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_pauses_unmounted_destination() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dest = dir.path().join("usb");
+        fs::create_dir(&dest)?;
+        let preflight = preflight(
+            &dest,
+            DestinationConfig {
+                require_mountpoint: true,
+                ..Default::default()
+            },
+        );
+
+        let err = preflight.check(&dest.join("torrent"), 1).unwrap_err();
+        assert!(err.is::<Deferred>());
+        assert!(err.to_string().contains("is not a mountpoint"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_requires_sentinel_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let preflight = preflight(
+            dir.path(),
+            DestinationConfig {
+                sentinel_file: Some(String::from(".mounted")),
+                ..Default::default()
+            },
+        );
+
+        let err = preflight.check(&dir.path().join("torrent"), 1).unwrap_err();
+        assert!(err.to_string().contains("sentinel file"));
+        assert!(preflight.unavailable.lock().unwrap().contains(dir.path()));
+
+        fs::write(dir.path().join(".mounted"), b"")?;
+        preflight.check(&dir.path().join("torrent"), 1)?;
+        assert!(preflight.unavailable.lock().unwrap().is_empty());
+        Ok(())
+    }
+}
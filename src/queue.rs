@@ -0,0 +1,166 @@
+/*
+qBittorrent Mover - A tool to automatically move torrents to different categories based on their state.
+Copyright (C) 2023 Harrison Chin
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use anyhow::Result;
+use log::debug;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Runs blocking file moves on the blocking thread pool.
+///
+/// `max_concurrent_moves` bounds the number of moves running at once, and
+/// `max_moves_per_device` bounds how many of them may touch the same source or
+/// destination device (grouped by `st_dev`), so copies do not compete for one
+/// spindle. A per-device limit of 0 disables device scheduling.
+#[derive(Clone)]
+pub struct MoveQueue {
+    workers: Arc<Semaphore>,
+    devices: Arc<Mutex<HashMap<u64, Arc<Semaphore>>>>,
+    max_moves_per_device: usize,
+}
+
+impl MoveQueue {
+    pub fn new(max_concurrent_moves: usize, max_moves_per_device: usize) -> Self {
+        Self {
+            workers: Arc::new(Semaphore::new(max_concurrent_moves.max(1))),
+            devices: Arc::new(Mutex::new(HashMap::new())),
+            max_moves_per_device,
+        }
+    }
+
+    fn device_slot(&self, device: u64) -> Arc<Semaphore> {
+        let mut devices = self.devices.lock().unwrap_or_else(|e| e.into_inner());
+        Arc::clone(
+            devices
+                .entry(device)
+                .or_insert_with(|| Arc::new(Semaphore::new(self.max_moves_per_device))),
+        )
+    }
+
+    async fn acquire_devices(&self, src: &Path, dest: &Path) -> Result<Vec<OwnedSemaphorePermit>> {
+        if self.max_moves_per_device == 0 {
+            return Ok(Vec::new());
+        }
+
+        // Always lock devices in the same order so two jobs moving in
+        // opposite directions cannot deadlock each other.
+        let mut devices = vec![device_id(src), device_id(dest)];
+        devices.sort_unstable();
+        devices.dedup();
+
+        let mut permits = Vec::with_capacity(devices.len());
+        for device in devices {
+            permits.push(self.device_slot(device).acquire_owned().await?);
+        }
+        Ok(permits)
+    }
+
+    /// Waits for a free worker and free device slots, then runs `job` on the
+    /// blocking thread pool.
+    pub async fn run<F, T>(&self, src: &Path, dest: &Path, job: F) -> Result<T>
+    where
+        F: FnOnce() -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        debug!("Queueing move of {:?} to {:?}", src, dest);
+        let _device_permits = self.acquire_devices(src, dest).await?;
+        let _worker_permit = Arc::clone(&self.workers).acquire_owned().await?;
+        debug!("Starting move of {:?} to {:?}", src, dest);
+        tokio::task::spawn_blocking(job).await?
+    }
+}
+
+/// Returns the device of `path`, or of its closest existing ancestor when the
+/// path itself has not been created yet.
+#[cfg(unix)]
+pub fn device_id(path: &Path) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+
+    path.ancestors()
+        .find_map(|ancestor| ancestor.metadata().ok())
+        .map(|metadata| metadata.dev())
+        .unwrap_or_default()
+}
+
+#[cfg(not(unix))]
+pub fn device_id(_path: &Path) -> u64 {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::join_all;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    async fn max_parallel_jobs(queue: MoveQueue, jobs: usize) -> Result<usize> {
+        let dir = tempfile::tempdir()?;
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+
+        let tasks = (0..jobs).map(|i| {
+            let queue = queue.clone();
+            let running = Arc::clone(&running);
+            let peak = Arc::clone(&peak);
+            let src = dir.path().join(format!("src{}", i));
+            let dest = dir.path().join(format!("dest{}", i));
+            async move {
+                queue
+                    .run(&src, &dest, move || {
+                        let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                        peak.fetch_max(now, Ordering::SeqCst);
+                        std::thread::sleep(Duration::from_millis(50));
+                        running.fetch_sub(1, Ordering::SeqCst);
+                        Ok(())
+                    })
+                    .await
+            }
+        });
+        for result in join_all(tasks).await {
+            result?;
+        }
+
+        Ok(peak.load(Ordering::SeqCst))
+    }
+
+    #[tokio::test]
+    async fn test_global_limit() -> Result<()> {
+        let peak = max_parallel_jobs(MoveQueue::new(2, 0), 6).await?;
+        assert_eq!(peak, 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_per_device_limit() -> Result<()> {
+        // Every job uses the same temporary directory, so they share a device
+        let peak = max_parallel_jobs(MoveQueue::new(4, 1), 4).await?;
+        assert_eq!(peak, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_device_id_of_missing_path_uses_ancestor() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let missing = dir.path().join("does").join("not").join("exist");
+        assert_eq!(device_id(&missing), device_id(dir.path()));
+        Ok(())
+    }
+}
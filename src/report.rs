@@ -0,0 +1,263 @@
+/*
+qBittorrent Mover - A tool to automatically move torrents to different categories based on their state.
+Copyright (C) 2023 Harrison Chin
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use super::errors::{error_kind, ErrorKind};
+use super::torrent::Torrent;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// An error kept for the report, with its kind.
+#[derive(Debug, Deserialize, Clone, Serialize, PartialEq)]
+pub struct ReportedError {
+    pub kind: ErrorKind,
+    pub message: String,
+}
+
+impl From<&anyhow::Error> for ReportedError {
+    fn from(error: &anyhow::Error) -> Self {
+        Self {
+            kind: error_kind(error),
+            message: error.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Serialize, PartialEq)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum Outcome {
+    Moved,
+    Deferred { reason: String },
+    Failed(ReportedError),
+}
+
+/// How one move ended.
+#[derive(Debug, Deserialize, Clone, Serialize, PartialEq)]
+pub struct TorrentOutcome {
+    pub hash: String,
+    pub name: String,
+    #[serde(flatten)]
+    pub outcome: Outcome,
+}
+
+impl TorrentOutcome {
+    pub fn new(torrent: &Torrent, outcome: Outcome) -> Self {
+        Self {
+            hash: torrent.hash.clone(),
+            name: torrent.name.clone(),
+            outcome,
+        }
+    }
+}
+
+/// What one poll of a server did. Moves run in the background, so the
+/// outcomes are those of the moves that ended since the previous poll.
+#[derive(Debug, Deserialize, Clone, Serialize, PartialEq, Default)]
+pub struct CycleReport {
+    pub server: String,
+    pub polled_at: u64, // Seconds since the Unix epoch
+    #[serde(default)]
+    pub error: Option<ReportedError>, // Why the server could not be polled
+    #[serde(default)]
+    pub started: usize, // Moves that began copying since the previous poll
+    #[serde(default)]
+    pub torrents: Vec<TorrentOutcome>,
+}
+
+impl CycleReport {
+    pub fn new(server: &str, torrents: Vec<TorrentOutcome>) -> Self {
+        Self {
+            server: server.to_string(),
+            polled_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default(),
+            torrents,
+            ..Default::default()
+        }
+    }
+
+    /// Every error in the report, the server's first.
+    pub fn errors(&self) -> impl Iterator<Item = &ReportedError> {
+        self.error
+            .iter()
+            .chain(self.torrents.iter().filter_map(|t| match &t.outcome {
+                Outcome::Failed(error) => Some(error),
+                _ => None,
+            }))
+    }
+
+    /// True when nothing happened worth logging.
+    pub fn is_quiet(&self) -> bool {
+        self.error.is_none() && self.started == 0 && self.torrents.is_empty()
+    }
+
+    /// One line like "http://host:8080: 2 started, 1 moved, 1 failed
+    /// (path mapping: 1)".
+    pub fn summary(&self) -> String {
+        let count = |wanted: fn(&Outcome) -> bool| {
+            self.torrents.iter().filter(|t| wanted(&t.outcome)).count()
+        };
+        let mut parts = vec![
+            format!("{} started", self.started),
+            format!("{} moved", count(|o| matches!(o, Outcome::Moved))),
+        ];
+        let deferred = count(|o| matches!(o, Outcome::Deferred { .. }));
+        if deferred > 0 {
+            parts.push(format!("{} deferred", deferred));
+        }
+
+        let mut kinds = BTreeMap::new();
+        for error in self.errors() {
+            *kinds.entry(error.kind).or_insert(0) += 1;
+        }
+        if !kinds.is_empty() {
+            let kinds: Vec<_> = kinds
+                .iter()
+                .map(|(kind, count)| format!("{}: {}", kind, count))
+                .collect();
+            parts.push(format!(
+                "{} failed ({})",
+                self.errors().count(),
+                kinds.join(", ")
+            ));
+        }
+        format!("{}: {}", self.server, parts.join(", "))
+    }
+}
+
+/// Outcomes of a server's background moves, and how many of them began
+/// copying, waiting for its next poll.
+#[derive(Clone, Default)]
+pub struct Outcomes {
+    pending: Arc<Mutex<Vec<TorrentOutcome>>>,
+    started: Arc<AtomicUsize>,
+}
+
+impl Outcomes {
+    /// Counts a move that passed its checks and began copying.
+    pub fn start(&self) {
+        self.started.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn take_started(&self) -> usize {
+        self.started.swap(0, Ordering::Relaxed)
+    }
+
+    pub fn push(&self, outcome: TorrentOutcome) {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        pending.push(outcome);
+    }
+
+    pub fn take(&self) -> Vec<TorrentOutcome> {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        std::mem::take(&mut *pending)
+    }
+}
+
+/// The latest report of every server that did something, written to the
+/// status file.
+#[derive(Clone, Default)]
+pub struct Reports {
+    latest: Arc<Mutex<BTreeMap<String, CycleReport>>>,
+}
+
+impl Reports {
+    /// Keeps `report` unless it is quiet and would hide the moves of an
+    /// earlier one. A quiet report still replaces an outage, which it shows
+    /// to be over.
+    pub fn record(&self, report: CycleReport) {
+        let mut latest = self.latest.lock().unwrap_or_else(|e| e.into_inner());
+        let keep_previous = report.is_quiet()
+            && latest
+                .get(&report.server)
+                .is_some_and(|previous| previous.error.is_none());
+        if !keep_previous {
+            latest.insert(report.server.clone(), report);
+        }
+    }
+
+    pub fn snapshot(&self) -> Vec<CycleReport> {
+        let latest = self.latest.lock().unwrap_or_else(|e| e.into_inner());
+        latest.values().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::path_mapping;
+    use crate::torrent::test_torrent;
+
+    #[test]
+    fn test_summary() {
+        let outcomes = Outcomes::default();
+        outcomes.push(TorrentOutcome::new(&test_torrent("abc"), Outcome::Moved));
+        let error = path_mapping(String::from("No path mapping covers \"/x\""));
+        outcomes.push(TorrentOutcome::new(
+            &test_torrent("def"),
+            Outcome::Failed(ReportedError::from(&error)),
+        ));
+
+        outcomes.start();
+        outcomes.start();
+
+        let mut report = CycleReport::new("http://server-a", outcomes.take());
+        report.started = outcomes.take_started();
+        assert!(outcomes.take().is_empty());
+        assert_eq!(outcomes.take_started(), 0);
+        assert!(!report.is_quiet());
+        assert_eq!(
+            report.summary(),
+            "http://server-a: 2 started, 1 moved, 1 failed (path mapping: 1)"
+        );
+        assert_eq!(report.errors().count(), 1);
+        assert!(CycleReport::new("http://server-a", Vec::new()).is_quiet());
+    }
+
+    #[test]
+    fn test_reports_keep_latest_per_server() {
+        let reports = Reports::default();
+        reports.record(CycleReport::new("http://server-b", Vec::new()));
+        let mut report = CycleReport::new("http://server-a", Vec::new());
+        report.error = Some(ReportedError::from(&anyhow::anyhow!("offline")));
+        reports.record(report.clone());
+        report.error = None;
+        reports.record(report.clone());
+        assert_eq!(reports.snapshot()[0], report);
+
+        // Quiet polls keep the last one that did something
+        report.started = 1;
+        reports.record(report.clone());
+        reports.record(CycleReport::new("http://server-a", Vec::new()));
+        let snapshot = reports.snapshot();
+        assert_eq!(snapshot.len(), 2);
+        assert_eq!(snapshot[0], report);
+
+        let json = serde_json::to_string(&TorrentOutcome::new(
+            &test_torrent("abc"),
+            Outcome::Deferred {
+                reason: String::from("destination unavailable"),
+            },
+        ))
+        .unwrap();
+        assert!(json.contains("\"status\":\"deferred\""), "{}", json);
+    }
+}
@@ -0,0 +1,354 @@
+/*
+qBittorrent Mover - A tool to automatically move torrents to different categories based on their state.
+Copyright (C) 2023 Harrison Chin
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use super::backoff::Backoff;
+use super::config::RetryPolicy;
+use super::units::HumanDuration;
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use log::warn;
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+use reqwest::{Response, StatusCode};
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::time::Duration;
+
+/// A response with an error status, turned into an error so that retries can
+/// tell a busy server from a rejected request.
+#[derive(Debug)]
+pub struct HttpStatusError {
+    pub url: String,
+    pub status: StatusCode,
+    pub retry_after: Option<Duration>,
+}
+
+impl fmt::Display for HttpStatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} returned HTTP {}", self.url, self.status)
+    }
+}
+
+impl std::error::Error for HttpStatusError {}
+
+/// Parses `Retry-After`, given either in seconds or as an HTTP date.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (date.with_timezone(&Utc) - Utc::now())
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+/// Passes successful responses through and turns any other status into an
+/// [`HttpStatusError`].
+pub fn check_status(response: Response) -> Result<Response> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    Err(HttpStatusError {
+        url: response.url().to_string(),
+        status,
+        retry_after: retry_after(response.headers()),
+    }
+    .into())
+}
+
+/// Whether an error is worth retrying.
+#[derive(Debug, PartialEq)]
+pub enum ErrorClass {
+    /// Likely to go away by itself, such as a timeout or a busy server.
+    /// The server may say how long to wait.
+    Transient { retry_after: Option<Duration> },
+    /// Fails the same way every time, such as a missing file or a refused
+    /// permission.
+    Permanent,
+}
+
+fn classify_io(error: &io::Error) -> ErrorClass {
+    #[cfg(unix)]
+    if let Some(code) = error.raw_os_error() {
+        match code {
+            libc::EAGAIN | libc::ESTALE | libc::EBUSY | libc::EINTR | libc::ETIMEDOUT => {
+                return ErrorClass::Transient { retry_after: None }
+            }
+            libc::ENOENT | libc::EACCES | libc::EPERM | libc::ENOSPC | libc::EROFS => {
+                return ErrorClass::Permanent
+            }
+            _ => {}
+        }
+    }
+    match error.kind() {
+        io::ErrorKind::TimedOut
+        | io::ErrorKind::Interrupted
+        | io::ErrorKind::WouldBlock
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted => ErrorClass::Transient { retry_after: None },
+        _ => ErrorClass::Permanent,
+    }
+}
+
+fn classify_status(status: StatusCode, retry_after: Option<Duration>) -> ErrorClass {
+    if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+        ErrorClass::Transient { retry_after }
+    } else {
+        ErrorClass::Permanent
+    }
+}
+
+/// Sorts an error by whether retrying it can help, where
+/// [`crate::errors::error_kind`] says what went wrong. Anything unrecognized
+/// is treated as permanent so that it is not retried blindly.
+pub fn retry_class(error: &anyhow::Error) -> ErrorClass {
+    for cause in error.chain() {
+        if let Some(e) = cause.downcast_ref::<HttpStatusError>() {
+            return classify_status(e.status, e.retry_after);
+        }
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            if let Some(status) = e.status() {
+                return classify_status(status, None);
+            }
+            return if e.is_builder() || e.is_decode() || e.is_redirect() {
+                ErrorClass::Permanent
+            } else {
+                ErrorClass::Transient { retry_after: None }
+            };
+        }
+        if let Some(e) = cause.downcast_ref::<io::Error>() {
+            return classify_io(e);
+        }
+    }
+    ErrorClass::Permanent
+}
+
+/// Records a failed attempt and returns how long to wait before the next
+/// one, or `None` when the error is permanent or the attempts are used up.
+fn next_delay(
+    policy: &RetryPolicy,
+    backoff: &mut Backoff,
+    what: &str,
+    error: &anyhow::Error,
+) -> Option<Duration> {
+    let ErrorClass::Transient { retry_after } = retry_class(error) else {
+        return None;
+    };
+    if backoff.failures() + 1 >= policy.attempts {
+        return None;
+    }
+    // The server's Retry-After is honored up to the policy's longest delay
+    let retry_after = retry_after.map(|d| d.min(policy.max_delay.get()));
+    let delay = retry_after.unwrap_or_else(|| backoff.fail());
+    if retry_after.is_some() {
+        backoff.fail();
+    }
+    warn!(
+        "{} failed (attempt {} of {}), retrying in {}: {}",
+        what,
+        backoff.failures(),
+        policy.attempts,
+        HumanDuration(delay),
+        error
+    );
+    Some(delay)
+}
+
+fn backoff(policy: &RetryPolicy) -> Backoff {
+    Backoff::new(policy.initial_delay.get(), policy.max_delay.get())
+}
+
+/// Runs `operation` until it succeeds, fails permanently or has been tried
+/// `policy.attempts` times.
+pub async fn retry<T, F, Fut>(policy: &RetryPolicy, what: &str, mut operation: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut backoff = backoff(policy);
+    loop {
+        match operation().await {
+            Ok(value) => return Ok(value),
+            Err(e) => match next_delay(policy, &mut backoff, what, &e) {
+                Some(delay) => tokio::time::sleep(delay).await,
+                None => return Err(e),
+            },
+        }
+    }
+}
+
+/// Like [`retry`], for filesystem work running on a blocking thread.
+pub fn retry_blocking<T>(
+    policy: &RetryPolicy,
+    what: &str,
+    mut operation: impl FnMut() -> Result<T>,
+) -> Result<T> {
+    let mut backoff = backoff(policy);
+    loop {
+        match operation() {
+            Ok(value) => return Ok(value),
+            Err(e) => match next_delay(policy, &mut backoff, what, &e) {
+                Some(delay) => std::thread::sleep(delay),
+                None => return Err(e),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    fn policy(attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            attempts,
+            initial_delay: HumanDuration(Duration::from_millis(1)),
+            max_delay: HumanDuration(Duration::from_millis(5)),
+        }
+    }
+
+    fn os_error(code: i32) -> anyhow::Error {
+        anyhow::Error::from(io::Error::from_raw_os_error(code))
+    }
+
+    #[test]
+    fn test_retry_class() {
+        let transient = ErrorClass::Transient { retry_after: None };
+        assert_eq!(retry_class(&os_error(libc::EAGAIN)), transient);
+        assert_eq!(retry_class(&os_error(libc::ESTALE)), transient);
+        assert_eq!(retry_class(&os_error(libc::ENOENT)), ErrorClass::Permanent);
+        assert_eq!(retry_class(&os_error(libc::EACCES)), ErrorClass::Permanent);
+        assert_eq!(
+            retry_class(&anyhow::anyhow!("Source path does not exist")),
+            ErrorClass::Permanent
+        );
+
+        let busy = anyhow::Error::from(HttpStatusError {
+            url: String::from("http://qbittorrent"),
+            status: StatusCode::SERVICE_UNAVAILABLE,
+            retry_after: Some(Duration::from_secs(7)),
+        });
+        assert_eq!(
+            retry_class(&busy.context("Removing torrent")),
+            ErrorClass::Transient {
+                retry_after: Some(Duration::from_secs(7))
+            }
+        );
+        let forbidden = anyhow::Error::from(HttpStatusError {
+            url: String::from("http://qbittorrent"),
+            status: StatusCode::FORBIDDEN,
+            retry_after: None,
+        });
+        assert_eq!(retry_class(&forbidden), ErrorClass::Permanent);
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(retry_after(&headers), None);
+        headers.insert(RETRY_AFTER, HeaderValue::from_static("120"));
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(120)));
+        headers.insert(
+            RETRY_AFTER,
+            HeaderValue::from_static("Wed, 21 Oct 2015 07:28:00 GMT"),
+        );
+        assert_eq!(retry_after(&headers), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_retry_after_is_capped() {
+        let busy = anyhow::Error::from(HttpStatusError {
+            url: String::from("http://qbittorrent"),
+            status: StatusCode::SERVICE_UNAVAILABLE,
+            retry_after: Some(Duration::from_secs(86400)),
+        });
+        let policy = policy(3);
+        let mut backoff = backoff(&policy);
+        assert_eq!(
+            next_delay(&policy, &mut backoff, "Removing torrent", &busy),
+            Some(Duration::from_millis(5))
+        );
+        assert_eq!(backoff.failures(), 1);
+    }
+
+    #[test]
+    fn test_retry_blocking_stops_on_permanent_errors() {
+        let mut attempts = 0;
+        let result: Result<()> = retry_blocking(&policy(5), "Deleting", || {
+            attempts += 1;
+            Err(os_error(libc::ENOENT))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn test_retry_blocking_retries_transient_errors() {
+        let mut attempts = 0;
+        let result = retry_blocking(&policy(3), "Copying", || {
+            attempts += 1;
+            if attempts < 3 {
+                Err(os_error(libc::EAGAIN))
+            } else {
+                Ok(attempts)
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        attempts = 0;
+        let result: Result<()> = retry_blocking(&policy(2), "Copying", || {
+            attempts += 1;
+            Err(os_error(libc::ESTALE))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 2);
+    }
+
+    #[tokio::test]
+    async fn test_retry_api_calls() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let busy = server
+            .mock("GET", "/busy")
+            .with_status(503)
+            .with_header("Retry-After", "0")
+            .expect(3)
+            .create();
+        let missing = server
+            .mock("GET", "/missing")
+            .with_status(404)
+            .expect(1)
+            .create();
+        let client = reqwest::Client::new();
+
+        for (path, mock) in [("/busy", busy), ("/missing", missing)] {
+            let url = format!("{}{}", server.url(), path);
+            let result = retry(&policy(3), "Fetching", || async {
+                check_status(client.get(&url).send().await?)
+            })
+            .await;
+            assert!(result.is_err());
+            mock.assert();
+        }
+        Ok(())
+    }
+}
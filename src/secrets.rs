@@ -0,0 +1,204 @@
+/*
+qBittorrent Mover - A tool to automatically move torrents to different categories based on their state.
+Copyright (C) 2023 Harrison Chin
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize, Serializer};
+use serde_yaml::Value;
+use std::fmt;
+use std::io::Write;
+
+/// Printed in place of secrets and of values that may hold one.
+pub const REDACTED: &str = "<redacted>";
+
+/// A string, such as a password, that must never end up in logs or in
+/// serialized output. Debug, Display and Serialize all print a placeholder;
+/// use [`Secret::expose`] where the real value is needed.
+#[derive(Clone, Default, PartialEq, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Self(value.into())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl fmt::Display for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(REDACTED)
+    }
+}
+
+/// Replaces every `${VAR}` in `input` with the value `lookup` returns for
+/// `VAR`. `$${` stands for a literal `${`.
+pub fn interpolate(input: &str, lookup: &dyn Fn(&str) -> Option<String>) -> Result<String> {
+    let mut output = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(start) = rest.find('$') {
+        output.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        if let Some(escaped) = after.strip_prefix("${") {
+            output.push_str("${");
+            rest = escaped;
+        } else if let Some(reference) = after.strip_prefix('{') {
+            let end = reference
+                .find('}')
+                .ok_or_else(|| anyhow::anyhow!("Unterminated ${{ in {:?}", input))?;
+            let name = &reference[..end];
+            if name.is_empty() {
+                return Err(anyhow::anyhow!("Empty ${{}} in {:?}", input));
+            }
+            let value = lookup(name).ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Environment variable {} is not set (referenced as ${{{}}})",
+                    name,
+                    name
+                )
+            })?;
+            output.push_str(&value);
+            rest = &reference[end + 1..];
+        } else {
+            output.push('$');
+            rest = after;
+        }
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+/// Applies [`interpolate`] to every string, including mapping keys, in a
+/// parsed YAML document.
+pub fn interpolate_value(value: &mut Value, lookup: &dyn Fn(&str) -> Option<String>) -> Result<()> {
+    match value {
+        Value::String(string) => *string = interpolate(string, lookup)?,
+        Value::Sequence(sequence) => {
+            for item in sequence {
+                interpolate_value(item, lookup)?;
+            }
+        }
+        Value::Mapping(mapping) => {
+            for (mut key, mut item) in std::mem::take(mapping) {
+                interpolate_value(&mut key, lookup)?;
+                interpolate_value(&mut item, lookup)?;
+                mapping.insert(key, item);
+            }
+        }
+        Value::Tagged(tagged) => interpolate_value(&mut tagged.value, lookup)?,
+        Value::Null | Value::Bool(_) | Value::Number(_) => {}
+    }
+    Ok(())
+}
+
+/// Reads a secret from a file, such as a Docker secret under /run/secrets,
+/// dropping the trailing newline most editors add.
+pub fn read_secret_file(path: &str) -> Result<Secret> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Unable to read secret file {}: {}", path, e))?;
+    Ok(Secret::new(contents.trim_end_matches(['\r', '\n'])))
+}
+
+/// Writes `contents` readable only by the owner, for files that may hold a
+/// password. A file that already exists loses any wider permissions.
+pub fn write_private(path: &str, contents: &str) -> Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "USER" => Some(String::from("alice")),
+            "PASS" => Some(String::from("p$ss\"word")),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_secret_is_redacted() {
+        let secret = Secret::new("hunter2");
+        assert_eq!(secret.expose(), "hunter2");
+        assert_eq!(format!("{:?}", secret), REDACTED);
+        assert_eq!(secret.to_string(), REDACTED);
+        assert!(!serde_yaml::to_string(&secret).unwrap().contains("hunter2"));
+    }
+
+    #[test]
+    fn test_interpolate() -> Result<()> {
+        assert_eq!(interpolate("${USER}:${PASS}", &lookup)?, "alice:p$ss\"word");
+        assert_eq!(interpolate("cost $5", &lookup)?, "cost $5");
+        assert_eq!(interpolate("$${USER}", &lookup)?, "${USER}");
+        assert!(interpolate("${MISSING}", &lookup)
+            .unwrap_err()
+            .to_string()
+            .contains("MISSING is not set"));
+        assert!(interpolate("${USER", &lookup).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_interpolate_value() -> Result<()> {
+        let mut value: Value = serde_yaml::from_str(
+            "servers:\n  - username: ${USER}\n    port: 8080\n\"/home/${USER}\": x\n",
+        )?;
+        interpolate_value(&mut value, &lookup)?;
+        assert_eq!(value["servers"][0]["username"], Value::from("alice"));
+        assert_eq!(value["servers"][0]["port"], Value::from(8080));
+        assert_eq!(value["/home/alice"], Value::from("x"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_secret_file() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("password");
+        std::fs::write(&path, "s3cret\n")?;
+        assert_eq!(read_secret_file(path.to_str().unwrap())?.expose(), "s3cret");
+        assert!(read_secret_file("/nonexistent/secret").is_err());
+        Ok(())
+    }
+}
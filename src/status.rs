@@ -0,0 +1,157 @@
+/*
+qBittorrent Mover - A tool to automatically move torrents to different categories based on their state.
+Copyright (C) 2023 Harrison Chin
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use super::inflight::{InFlight, InFlightEntry};
+use super::report::{CycleReport, Reports};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Snapshot of the running daemon, written to `status_file` after every
+/// cycle and read back by the `status` command.
+#[derive(Debug, Deserialize, Clone, Serialize, PartialEq, Default)]
+pub struct StatusReport {
+    pub updated_at: u64, // Seconds since the Unix epoch
+    pub in_flight: Vec<InFlightEntry>,
+    #[serde(default)]
+    pub servers: Vec<CycleReport>, // Latest poll of each server that did something
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+impl StatusReport {
+    pub fn collect(in_flight: &InFlight, reports: &Reports) -> Self {
+        Self {
+            updated_at: now(),
+            in_flight: in_flight.snapshot(),
+            servers: reports.snapshot(),
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let now = now();
+        let mut out = format!(
+            "Last updated {}s ago\n",
+            now.saturating_sub(self.updated_at)
+        );
+        for report in &self.servers {
+            out.push_str(&format!(
+                "Last activity {}s ago on {}\n",
+                now.saturating_sub(report.polled_at),
+                report.summary()
+            ));
+            for error in report.errors() {
+                out.push_str(&format!("  {} error: {}\n", error.kind, error.message));
+            }
+        }
+        if self.in_flight.is_empty() {
+            out.push_str("No torrents in flight\n");
+            return out;
+        }
+
+        out.push_str(&format!("In flight ({}):\n", self.in_flight.len()));
+        for entry in &self.in_flight {
+            out.push_str(&format!(
+                "  {} {} [{}] running for {}s\n",
+                entry.hash,
+                entry.name,
+                entry.server,
+                now.saturating_sub(entry.started_at)
+            ));
+            if let Some(progress) = &entry.progress {
+                out.push_str(&format!("    {}\n", progress));
+            }
+        }
+        out
+    }
+}
+
+/// Writes the report atomically so that readers never see a partial file.
+pub fn write_status(filename: &str, report: &StatusReport) -> Result<()> {
+    let tmp_filename = format!("{}.tmp", filename);
+    let file = File::create(&tmp_filename)?;
+    serde_json::to_writer_pretty(&file, report)?;
+    fs::rename(&tmp_filename, filename)?;
+    Ok(())
+}
+
+pub fn read_status(filename: &str) -> Result<StatusReport> {
+    let file = File::open(filename)
+        .map_err(|e| anyhow::anyhow!("Unable to read status file {}: {}", filename, e))?;
+    Ok(serde_json::from_reader(file)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::copier::CopyProgress;
+    use crate::errors::ErrorKind;
+    use crate::report::ReportedError;
+    use crate::torrent::test_torrent;
+
+    #[test]
+    fn test_write_and_read_status() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let filename = dir.path().join("status.json");
+        let filename = filename.to_str().unwrap();
+
+        let in_flight = InFlight::new();
+        let torrent = test_torrent("test_hash");
+        let _guard = in_flight.try_acquire("http://localhost:8080", &torrent);
+        in_flight.set_progress(
+            "test_hash",
+            CopyProgress {
+                bytes_copied: 1024,
+                total_bytes: 2048,
+                ..Default::default()
+            },
+        );
+
+        let reports = Reports::default();
+        let mut cycle = CycleReport::new("http://localhost:8080", Vec::new());
+        cycle.error = Some(ReportedError {
+            kind: ErrorKind::Auth,
+            message: String::from("HTTP 403 Forbidden"),
+        });
+        reports.record(cycle);
+
+        let report = StatusReport::collect(&in_flight, &reports);
+        write_status(filename, &report)?;
+        let read_back = read_status(filename)?;
+        assert_eq!(read_back, report);
+        assert!(read_back.render().contains("test_hash test_torrent"));
+        assert!(read_back.render().contains("1.0 KiB of 2.0 KiB"));
+        assert!(read_back
+            .render()
+            .contains("authentication error: HTTP 403 Forbidden"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_empty() {
+        let report = StatusReport::default();
+        assert!(report.render().contains("No torrents in flight"));
+    }
+}
@@ -0,0 +1,230 @@
+/*
+qBittorrent Mover - A tool to automatically move torrents to different categories based on their state.
+Copyright (C) 2023 Harrison Chin
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use super::config::{BandwidthConfig, RateLimitConfig};
+use super::units::ByteRate;
+use anyhow::Result;
+use chrono::{Local, NaiveTime};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+struct Window {
+    from: NaiveTime,
+    to: NaiveTime,
+    limit: Option<u64>,
+}
+
+impl Window {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.from <= self.to {
+            self.from <= time && time < self.to
+        } else {
+            time >= self.from || time < self.to
+        }
+    }
+}
+
+/// A bytes-per-second limit that can change with the time of day.
+/// `None` means unlimited.
+pub struct RateSchedule {
+    limit: Option<u64>,
+    windows: Vec<Window>,
+}
+
+fn limit(rate: Option<ByteRate>) -> Option<u64> {
+    rate.map(ByteRate::bytes_per_sec)
+}
+
+fn parse_time(time: &str) -> Result<NaiveTime> {
+    NaiveTime::parse_from_str(time.trim(), "%H:%M")
+        .map_err(|_| anyhow::anyhow!("Invalid time of day {:?}, expected HH:MM", time))
+}
+
+impl RateSchedule {
+    pub fn from_config(config: &RateLimitConfig) -> Result<Self> {
+        let windows = config
+            .schedule
+            .iter()
+            .map(|window| {
+                Ok(Window {
+                    from: parse_time(&window.from)?,
+                    to: parse_time(&window.to)?,
+                    limit: limit(window.limit),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            limit: limit(config.limit),
+            windows,
+        })
+    }
+
+    /// Returns the limit in effect at `time`; the first matching window wins.
+    pub fn limit_at(&self, time: NaiveTime) -> Option<u64> {
+        self.windows
+            .iter()
+            .find(|window| window.contains(time))
+            .map_or(self.limit, |window| window.limit)
+    }
+}
+
+/// Paces byte transfers to a [`RateSchedule`]. It is shared by every copy that
+/// goes through it, so the limit applies to their combined throughput.
+pub struct RateLimiter {
+    schedule: RateSchedule,
+    next_free: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(schedule: RateSchedule) -> Self {
+        Self {
+            schedule,
+            next_free: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Books `bytes` against the limit and returns the instant at which the
+    /// caller may continue, or `None` when currently unlimited.
+    pub fn reserve(&self, bytes: u64) -> Option<Instant> {
+        let rate = self.schedule.limit_at(Local::now().time())?;
+        let cost = Duration::from_secs_f64(bytes as f64 / rate as f64);
+
+        let mut next_free = self.next_free.lock().unwrap_or_else(|e| e.into_inner());
+        let start = (*next_free).max(Instant::now());
+        *next_free = start + cost;
+        Some(*next_free)
+    }
+}
+
+/// Global limiter plus optional per-destination limiters.
+pub struct Bandwidth {
+    global: RateLimiter,
+    destinations: Vec<(PathBuf, RateLimiter)>,
+}
+
+impl Bandwidth {
+    pub fn from_config(config: &BandwidthConfig) -> Result<Self> {
+        let destinations = config
+            .destinations
+            .iter()
+            .map(|(path, limit)| {
+                let schedule = RateSchedule::from_config(limit)
+                    .map_err(|e| anyhow::anyhow!("Bandwidth limit for {}: {}", path, e))?;
+                Ok((PathBuf::from(path), RateLimiter::new(schedule)))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            global: RateLimiter::new(RateSchedule::from_config(&config.global)?),
+            destinations,
+        })
+    }
+
+    fn destination_limiter(&self, dest: &Path) -> Option<&RateLimiter> {
+        self.destinations
+            .iter()
+            .filter(|(path, _)| dest.starts_with(path))
+            .max_by_key(|(path, _)| path.components().count())
+            .map(|(_, limiter)| limiter)
+    }
+
+    /// Blocks the calling thread until `bytes` written to `dest` fit within
+    /// both the global and the destination limit.
+    pub fn throttle(&self, dest: &Path, bytes: u64) {
+        let global = self.global.reserve(bytes);
+        let destination = self
+            .destination_limiter(dest)
+            .and_then(|limiter| limiter.reserve(bytes));
+
+        if let Some(wake) = global.max(destination) {
+            let now = Instant::now();
+            if wake > now {
+                std::thread::sleep(wake - now);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BandwidthWindow;
+    use std::collections::HashMap;
+
+    fn time(s: &str) -> NaiveTime {
+        parse_time(s).unwrap()
+    }
+
+    fn night_schedule() -> RateLimitConfig {
+        RateLimitConfig {
+            limit: Some("20MiB/s".parse().unwrap()),
+            schedule: vec![BandwidthWindow {
+                from: String::from("23:00"),
+                to: String::from("07:00"),
+                limit: None,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_schedule_limit_at() -> Result<()> {
+        let schedule = RateSchedule::from_config(&night_schedule())?;
+        assert_eq!(schedule.limit_at(time("12:00")), Some(20 * 1024 * 1024));
+        assert_eq!(schedule.limit_at(time("23:30")), None);
+        assert_eq!(schedule.limit_at(time("03:00")), None);
+        assert_eq!(schedule.limit_at(time("07:00")), Some(20 * 1024 * 1024));
+        Ok(())
+    }
+
+    #[test]
+    fn test_schedule_rejects_bad_values() {
+        let mut config = night_schedule();
+        config.schedule[0].from = String::from("25:00");
+        assert!(RateSchedule::from_config(&config).is_err());
+    }
+
+    #[test]
+    fn test_throttle_paces_transfers() -> Result<()> {
+        let config = BandwidthConfig {
+            global: RateLimitConfig::default(),
+            destinations: HashMap::from([(
+                String::from("/mnt/media"),
+                RateLimitConfig {
+                    limit: Some("100K/s".parse()?),
+                    schedule: Vec::new(),
+                },
+            )]),
+        };
+        let bandwidth = Bandwidth::from_config(&config)?;
+
+        // Unrelated destinations are not limited
+        let start = Instant::now();
+        bandwidth.throttle(Path::new("/mnt/other/file"), 1024 * 1024);
+        assert!(start.elapsed() < Duration::from_millis(100));
+
+        // 20 KiB at 100 KiB/s takes at least 200ms
+        let start = Instant::now();
+        for _ in 0..2 {
+            bandwidth.throttle(Path::new("/mnt/media/movies/file"), 10 * 1024);
+        }
+        assert!(start.elapsed() >= Duration::from_millis(190));
+        Ok(())
+    }
+}
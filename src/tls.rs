@@ -0,0 +1,368 @@
+/*
+qBittorrent Mover - A tool to automatically move torrents to different categories based on their state.
+Copyright (C) 2023 Harrison Chin
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use super::config::TlsConfig;
+use anyhow::Result;
+use reqwest::ClientBuilder;
+use rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
+use rustls::{Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerName};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+fn read_certificates(filename: &str) -> Result<Vec<Certificate>> {
+    let file = File::open(filename)
+        .map_err(|e| anyhow::anyhow!("Unable to read certificates from {}: {}", filename, e))?;
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(file))
+        .map_err(|e| anyhow::anyhow!("Unable to parse certificates in {}: {}", filename, e))?;
+    if certificates.is_empty() {
+        return Err(anyhow::anyhow!("No PEM certificates found in {}", filename));
+    }
+    Ok(certificates.into_iter().map(Certificate).collect())
+}
+
+/// Reads the first PKCS#8, PKCS#1 (RSA) or SEC1 (EC) private key in a PEM file.
+fn read_private_key(filename: &str) -> Result<PrivateKey> {
+    let file = File::open(filename)
+        .map_err(|e| anyhow::anyhow!("Unable to read private key from {}: {}", filename, e))?;
+    let mut reader = BufReader::new(file);
+    loop {
+        match rustls_pemfile::read_one(&mut reader)
+            .map_err(|e| anyhow::anyhow!("Unable to parse private key in {}: {}", filename, e))?
+        {
+            Some(rustls_pemfile::Item::PKCS8Key(key))
+            | Some(rustls_pemfile::Item::RSAKey(key))
+            | Some(rustls_pemfile::Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => return Err(anyhow::anyhow!("No PEM private key found in {}", filename)),
+        }
+    }
+}
+
+/// Parses a SHA-256 fingerprint written as hex, with or without colons.
+pub fn parse_fingerprint(fingerprint: &str) -> Result<[u8; 32]> {
+    let hex: String = fingerprint
+        .chars()
+        .filter(|c| *c != ':' && !c.is_whitespace())
+        .collect();
+    let invalid = || {
+        anyhow::anyhow!(
+            "Invalid fingerprint {:?}; expected the 64 hex digits of a SHA-256 hash",
+            fingerprint
+        )
+    };
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+    let mut bytes = [0; 32];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(bytes)
+}
+
+/// Trusts exactly the certificate with the pinned fingerprint, whoever
+/// signed it, which is how self-signed Web UI certificates are accepted.
+struct PinnedVerifier {
+    fingerprint: [u8; 32],
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if Sha256::digest(&end_entity.0).as_slice() == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(String::from(
+                "the server certificate does not match the pinned fingerprint",
+            )))
+        }
+    }
+}
+
+/// Accepts any certificate, for `insecure_skip_verify`.
+struct AnyCertificate;
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// The public web roots plus the certificates in `ca_file`, if any.
+fn root_store(ca_file: Option<&str>) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            anchor.subject,
+            anchor.spki,
+            anchor.name_constraints,
+        )
+    }));
+    if let Some(ca_file) = ca_file {
+        for certificate in read_certificates(ca_file)? {
+            roots
+                .add(&certificate)
+                .map_err(|e| anyhow::anyhow!("Unusable CA certificate in {}: {}", ca_file, e))?;
+        }
+    }
+    Ok(roots)
+}
+
+fn client_config(tls: &TlsConfig) -> Result<ClientConfig> {
+    let verifier: Arc<dyn ServerCertVerifier> = if tls.insecure_skip_verify {
+        Arc::new(AnyCertificate)
+    } else if let Some(fingerprint) = &tls.fingerprint {
+        Arc::new(PinnedVerifier {
+            fingerprint: parse_fingerprint(fingerprint)?,
+        })
+    } else {
+        Arc::new(WebPkiVerifier::new(
+            root_store(tls.ca_file.as_deref())?,
+            None,
+        ))
+    };
+
+    let builder = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(verifier);
+    match (&tls.client_cert, &tls.client_key) {
+        (Some(cert), Some(key)) => Ok(builder
+            .with_client_auth_cert(read_certificates(cert)?, read_private_key(key)?)
+            .map_err(|e| anyhow::anyhow!("Unusable client certificate {}: {}", cert, e))?),
+        (None, None) => Ok(builder.with_no_client_auth()),
+        _ => Err(anyhow::anyhow!(
+            "tls.client_cert and tls.client_key must be set together"
+        )),
+    }
+}
+
+/// Sets up a server's HTTP client for its TLS settings. Servers without any
+/// keep the default TLS backend; the others get rustls set up from them.
+pub fn configure(builder: ClientBuilder, tls: &TlsConfig) -> Result<ClientBuilder> {
+    if tls.is_default() {
+        return Ok(builder);
+    }
+    Ok(builder.use_preconfigured_tls(client_config(tls)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    /// A CA, a server certificate for localhost signed by it and a client
+    /// certificate, written as PEM files.
+    struct Pki {
+        dir: tempfile::TempDir,
+        server_der: Vec<u8>,
+        server_key_der: Vec<u8>,
+        ca_der: Vec<u8>,
+    }
+
+    impl Pki {
+        fn new() -> Result<Self> {
+            let dir = tempfile::tempdir()?;
+            let mut ca_params = rcgen::CertificateParams::new(Vec::new());
+            ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+            let ca = rcgen::Certificate::from_params(ca_params)?;
+            let server = rcgen::Certificate::from_params(rcgen::CertificateParams::new(vec![
+                String::from("localhost"),
+            ]))?;
+            let client = rcgen::Certificate::from_params(rcgen::CertificateParams::new(vec![
+                String::from("mover"),
+            ]))?;
+
+            fs::write(dir.path().join("ca.pem"), ca.serialize_pem()?)?;
+            fs::write(
+                dir.path().join("client.pem"),
+                client.serialize_pem_with_signer(&ca)?,
+            )?;
+            fs::write(
+                dir.path().join("client.key"),
+                client.serialize_private_key_pem(),
+            )?;
+            Ok(Self {
+                server_der: server.serialize_der_with_signer(&ca)?,
+                server_key_der: server.serialize_private_key_der(),
+                ca_der: ca.serialize_der()?,
+                dir,
+            })
+        }
+
+        fn path(&self, name: &str) -> Option<String> {
+            Some(self.dir.path().join(name).to_string_lossy().into_owned())
+        }
+
+        fn fingerprint(&self) -> String {
+            Sha256::digest(&self.server_der)
+                .iter()
+                .map(|byte| format!("{:02X}", byte))
+                .collect::<Vec<_>>()
+                .join(":")
+        }
+    }
+
+    /// Serves one canned HTTP response per connection over TLS, optionally
+    /// requiring a client certificate signed by the CA. Returns the base URL.
+    async fn serve(pki: &Pki, require_client_cert: bool) -> Result<String> {
+        let builder = rustls::ServerConfig::builder().with_safe_defaults();
+        let builder = if require_client_cert {
+            let mut roots = RootCertStore::empty();
+            roots.add(&Certificate(pki.ca_der.clone()))?;
+            builder.with_client_cert_verifier(Arc::new(
+                rustls::server::AllowAnyAuthenticatedClient::new(roots),
+            ))
+        } else {
+            builder.with_no_client_auth()
+        };
+        let config = builder.with_single_cert(
+            vec![Certificate(pki.server_der.clone())],
+            PrivateKey(pki.server_key_der.clone()),
+        )?;
+
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let port = listener.local_addr()?.port();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    if let Ok(mut stream) = acceptor.accept(stream).await {
+                        let _ = stream
+                            .write_all(
+                                b"HTTP/1.1 200 OK\r\ncontent-length: 6\r\nconnection: close\r\n\r\nv4.6.0",
+                            )
+                            .await;
+                        let _ = stream.shutdown().await;
+                    }
+                });
+            }
+        });
+        Ok(format!("https://localhost:{}/api/v2/app/version", port))
+    }
+
+    fn build_client(tls: &TlsConfig) -> Result<reqwest::Client> {
+        Ok(configure(reqwest::Client::builder(), tls)?.build()?)
+    }
+
+    async fn fetch(tls: &TlsConfig, url: &str) -> Result<String> {
+        Ok(build_client(tls)?
+            .get(url)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?)
+    }
+
+    #[tokio::test]
+    async fn test_ca_file_and_insecure_skip_verify() -> Result<()> {
+        let pki = Pki::new()?;
+        let url = serve(&pki, false).await?;
+
+        assert!(fetch(&TlsConfig::default(), &url).await.is_err());
+        let with_ca = TlsConfig {
+            ca_file: pki.path("ca.pem"),
+            ..Default::default()
+        };
+        assert_eq!(fetch(&with_ca, &url).await?, "v4.6.0");
+        let insecure = TlsConfig {
+            insecure_skip_verify: true,
+            ..Default::default()
+        };
+        assert_eq!(fetch(&insecure, &url).await?, "v4.6.0");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_pinned_fingerprint() -> Result<()> {
+        let pki = Pki::new()?;
+        let url = serve(&pki, false).await?;
+
+        let pinned = TlsConfig {
+            fingerprint: Some(pki.fingerprint()),
+            ..Default::default()
+        };
+        assert_eq!(fetch(&pinned, &url).await?, "v4.6.0");
+        let wrong = TlsConfig {
+            fingerprint: Some("00".repeat(32)),
+            ..Default::default()
+        };
+        assert!(fetch(&wrong, &url).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_client_certificate() -> Result<()> {
+        let pki = Pki::new()?;
+        let url = serve(&pki, true).await?;
+
+        let without = TlsConfig {
+            ca_file: pki.path("ca.pem"),
+            ..Default::default()
+        };
+        assert!(fetch(&without, &url).await.is_err());
+        let with = TlsConfig {
+            client_cert: pki.path("client.pem"),
+            client_key: pki.path("client.key"),
+            ..without
+        };
+        assert_eq!(fetch(&with, &url).await?, "v4.6.0");
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_settings() {
+        assert!(parse_fingerprint("ab:cd").is_err());
+        assert_eq!(parse_fingerprint(&"aB".repeat(32)).unwrap(), [0xab; 32]);
+        let missing = TlsConfig {
+            ca_file: Some(String::from("/nonexistent/ca.pem")),
+            ..Default::default()
+        };
+        assert!(build_client(&missing).is_err());
+        let half = TlsConfig {
+            client_cert: Some(String::from("client.pem")),
+            ..Default::default()
+        };
+        assert!(build_client(&half)
+            .unwrap_err()
+            .to_string()
+            .contains("set together"));
+    }
+}
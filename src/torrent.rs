@@ -16,12 +16,30 @@ You should have received a copy of the GNU Affero General Public License
 along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
+use super::api::ApiVersion;
+use super::config::RetryConfig;
 use super::config::ServerConfig;
+use super::copier::{measure, Copier, CopyProgress};
+use super::errors::{api_schema, error_kind, path_mapping, ErrorKind};
+use super::failures::FailureLog;
+use super::inflight::InFlight;
+use super::notifier::Notifier;
+use super::paths::{ensure_within, validate_torrent_name, PathGuard, PathMapper};
+use super::preflight::{Deferrals, Preflight};
+use super::queue::MoveQueue;
+use super::report::{Outcomes, Reports};
+use super::retry::{check_status, retry, retry_blocking};
+use super::secrets::Secret;
+use super::tls;
 use anyhow::Result;
-use reqwest::{Client, Method, Response};
+use log::{debug, error, info};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::{Client, Method, Proxy, Response};
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Torrent {
@@ -29,95 +47,463 @@ pub struct Torrent {
     pub name: String,
     pub category: String,
     pub hash: String,
+    #[serde(default)]
+    pub content_path: Option<String>,
+}
+
+/// A completed torrent in the test category, for the tests of the modules
+/// that keep track of torrents.
+#[cfg(test)]
+pub fn test_torrent(hash: &str) -> Torrent {
+    Torrent {
+        save_path: String::from("/downloads"),
+        name: String::from("test_torrent"),
+        category: String::from("test_category"),
+        hash: hash.to_string(),
+        content_path: None,
+    }
+}
+
+/// The subset of `/api/v2/app/preferences` the mover cares about.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct Preferences {
+    pub save_path: String,
+    #[serde(default)]
+    pub temp_path: String,
+    #[serde(default)]
+    pub temp_path_enabled: bool,
+}
+
+/// A category as returned by `/api/v2/torrents/categories`.
+#[derive(Debug, Deserialize, Clone)]
+pub struct Category {
+    pub name: String,
+    #[serde(rename = "savePath", default)]
+    pub save_path: String,
+}
+
+/// Shared machinery used by every move: the worker queue, the copier, the
+/// destination and path safety checks, the registry of torrents currently
+/// being moved, the failure counters and the per-server cycle reports.
+#[derive(Clone)]
+pub struct MoveContext {
+    pub queue: MoveQueue,
+    pub copier: Copier,
+    pub preflight: Arc<Preflight>,
+    pub deferrals: Deferrals,
+    pub path_guard: Arc<PathGuard>,
+    pub in_flight: InFlight,
+    pub retry: RetryConfig,
+    pub failures: FailureLog,
+    pub reports: Reports,
+    pub outcomes: Outcomes, // The server's own, replaced for each server loop
+    pub notifier: Notifier,
+}
+
+/// The static headers sent with every request to a server. Their values are
+/// marked sensitive so that they stay out of debug output.
+fn default_headers(headers: &HashMap<String, Secret>) -> Result<HeaderMap> {
+    let mut map = HeaderMap::new();
+    for (name, value) in headers {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| anyhow::anyhow!("Invalid header name {:?}", name))?;
+        let mut value = HeaderValue::from_str(value.expose())
+            .map_err(|_| anyhow::anyhow!("Invalid value for header {}", name))?;
+        value.set_sensitive(true);
+        map.insert(name, value);
+    }
+    Ok(map)
+}
+
+/// Builds the HTTP client for a server from its headers, proxy, timeouts and
+/// TLS settings.
+fn build_client(server: &ServerConfig) -> Result<Client> {
+    let mut builder = Client::builder().default_headers(default_headers(&server.headers)?);
+    if !server.connect_timeout.is_zero() {
+        builder = builder.connect_timeout(server.connect_timeout.get());
+    }
+    if !server.request_timeout.is_zero() {
+        builder = builder.timeout(server.request_timeout.get());
+    }
+    if let Some(proxy) = &server.proxy {
+        builder = builder.proxy(
+            Proxy::all(proxy.expose()).map_err(|e| anyhow::anyhow!("Invalid proxy: {}", e))?,
+        );
+    }
+    Ok(tls::configure(builder, &server.tls)?.build()?)
 }
 
 #[derive(Clone)]
 pub struct TorrentClient {
     client: Client,
     server: ServerConfig,
+    api: ApiVersion,
 }
 
 impl TorrentClient {
-    pub fn new(server: ServerConfig) -> Self {
-        Self {
-            client: Client::new(),
+    /// Fails when the server's connection settings cannot be used, such as
+    /// an unreadable CA file or a malformed header. The client assumes the
+    /// oldest supported Web API until [`TorrentClient::connect`] detects it.
+    pub fn new(server: ServerConfig) -> Result<Self> {
+        let client = build_client(&server)
+            .map_err(|e| anyhow::anyhow!("Connection settings of {}: {}", server.qbit_url, e))?;
+        Ok(Self {
+            client,
             server,
-        }
+            api: ApiVersion::MIN_SUPPORTED,
+        })
+    }
+
+    /// Like [`TorrentClient::new`], then detects the server's Web API
+    /// version. Fails when the server cannot be reached or runs a Web API
+    /// the mover does not support.
+    pub async fn connect(server: ServerConfig) -> Result<Self> {
+        let mut client = Self::new(server)?;
+        client.api = get_api_version(&client).await?;
+        client
+            .api
+            .check_supported()
+            .map_err(|e| api_schema(format!("{}: {}", client.server.qbit_url, e)))?;
+        Ok(client)
+    }
+
+    pub fn server(&self) -> &ServerConfig {
+        &self.server
+    }
+
+    /// Replaces the server's categories, e.g. once they are synced, so the
+    /// client does not have to be built again.
+    pub fn set_categories(&mut self, categories: HashMap<String, String>) {
+        self.server.categories = categories;
+    }
+
+    pub fn api_version(&self) -> ApiVersion {
+        self.api
+    }
+
+    /// The URL of a Web API endpoint such as "app/version". `qbit_url` may
+    /// include a base path, like "https://host/qbt/" behind a reverse proxy.
+    fn api_url(&self, endpoint: &str) -> String {
+        format!(
+            "{}/api/v2/{}",
+            self.server.qbit_url.trim_end_matches('/'),
+            endpoint
+        )
     }
 
     async fn make_request(&self, url: &str, method: Method) -> Result<Response> {
         let request = self
             .client
             .request(method, url)
-            .basic_auth(&self.server.username, Some(&self.server.password))
+            .basic_auth(&self.server.username, Some(self.server.password.expose()))
+            .build()?;
+        let response = self.client.execute(request).await?;
+        Ok(response)
+    }
+
+    async fn make_form_request(&self, url: &str, form: &[(&str, &str)]) -> Result<Response> {
+        let request = self
+            .client
+            .post(url)
+            .basic_auth(&self.server.username, Some(self.server.password.expose()))
+            .form(form)
             .build()?;
         let response = self.client.execute(request).await?;
         Ok(response)
     }
 }
 
-pub async fn is_server_online(client: &TorrentClient) -> Result<bool> {
-    let url = format!("{}/api/v2/app/version", client.server.qbit_url);
-    let response = client.make_request(&url, Method::GET).await?;
-    Ok(response.status().is_success())
+/// Fetches the qBittorrent version. Fails with the HTTP status, so rejected
+/// credentials can be told apart from an unreachable server.
+pub async fn get_version(client: &TorrentClient) -> Result<String> {
+    let url = client.api_url("app/version");
+    let response = client
+        .make_request(&url, Method::GET)
+        .await?
+        .error_for_status()?;
+    Ok(response.text().await?)
+}
+
+/// Fetches the Web API version, which tells which endpoints and torrent
+/// states the server knows.
+pub async fn get_api_version(client: &TorrentClient) -> Result<ApiVersion> {
+    let url = client.api_url("app/webapiVersion");
+    let response = check_status(client.make_request(&url, Method::GET).await?)?;
+    ApiVersion::parse(&response.text().await?)
+}
+
+pub async fn get_all_torrents(client: &TorrentClient) -> Result<Vec<Torrent>> {
+    let url = client.api_url("torrents/info");
+    let response = check_status(client.make_request(&url, Method::GET).await?)?;
+    let torrents = response.json::<Vec<Torrent>>().await?;
+    Ok(torrents)
 }
 
 pub async fn get_completed_torrents(client: &TorrentClient) -> Result<Vec<Torrent>> {
-    let url = format!(
-        "{}/api/v2/torrents/info?filter=completed",
-        client.server.qbit_url
-    );
-    let response = client.make_request(&url, Method::GET).await?;
+    let url = client.api_url("torrents/info?filter=completed");
+    let response = check_status(client.make_request(&url, Method::GET).await?)?;
     let torrents = response.json::<Vec<Torrent>>().await?;
     Ok(torrents)
 }
 
-pub async fn remove_torrent(client: &TorrentClient, hash: &str) -> Result<()> {
-    let url = format!(
-        "{}/api/v2/torrents/delete?hashes={}",
-        client.server.qbit_url, hash
-    );
-    client.make_request(&url, Method::DELETE).await?;
+pub async fn get_torrents(client: &TorrentClient, limit: usize) -> Result<Vec<Torrent>> {
+    let url = client.api_url(&format!("torrents/info?limit={}", limit));
+    let response = check_status(client.make_request(&url, Method::GET).await?)?;
+    let torrents = response.json::<Vec<Torrent>>().await?;
+    Ok(torrents)
+}
+
+pub async fn get_preferences(client: &TorrentClient) -> Result<Preferences> {
+    let url = client.api_url("app/preferences");
+    let response = check_status(client.make_request(&url, Method::GET).await?)?;
+    let preferences = response.json::<Preferences>().await?;
+    Ok(preferences)
+}
+
+pub async fn get_categories(client: &TorrentClient) -> Result<HashMap<String, Category>> {
+    let url = client.api_url("torrents/categories");
+    let response = check_status(client.make_request(&url, Method::GET).await?)?;
+    let categories = response.json::<HashMap<String, Category>>().await?;
+    Ok(categories)
+}
+
+pub async fn create_category(client: &TorrentClient, name: &str) -> Result<()> {
+    let url = client.api_url("torrents/createCategory");
+    let response = client
+        .make_form_request(&url, &[("category", name), ("savePath", "")])
+        .await?;
+    if !response.status().is_success() {
+        return Err(anyhow::anyhow!(
+            "Failed to create category {}: HTTP {}",
+            name,
+            response.status()
+        ));
+    }
     Ok(())
 }
 
-pub async fn move_and_clean_torrent_files(client: &TorrentClient, torrent: &Torrent) -> Result<()> {
-    if let Some(dest_path) = client.server.categories.get(&torrent.category) {
-        let save_path = PathBuf::from(&torrent.save_path);
-        let relative_path = match &client.server.path_prefix {
-            Some(prefix) => save_path.strip_prefix(prefix)?,
-            None => &save_path,
-        };
-        let root_path = PathBuf::from(client.server.root_path.as_deref().unwrap_or(""));
-        let src = root_path.join(relative_path).join(&torrent.name);
-        let dest = PathBuf::from(dest_path).join(&torrent.name);
+/// Removes a torrent, keeping its files, which have been moved by then.
+/// Recent servers only accept a POST with a form body, which every 2.x
+/// server understands.
+pub async fn remove_torrent(client: &TorrentClient, hash: &str) -> Result<()> {
+    let url = client.api_url("torrents/delete");
+    check_status(
+        client
+            .make_form_request(&url, &[("hashes", hash), ("deleteFiles", "false")])
+            .await?,
+    )?;
+    Ok(())
+}
 
-        if !src.exists() {
-            return Err(anyhow::anyhow!("Source path does not exist: {:?}", src));
+/// Copies `src` to `dest`, removing whatever was copied if the copy fails part
+/// way through so no partial tree is left behind.
+fn copy_or_clean_up(
+    copier: &Copier,
+    src: &Path,
+    dest: &Path,
+    report: &mut dyn FnMut(&CopyProgress),
+) -> Result<()> {
+    // Whatever was at `dest` before, a dangling symlink included, and
+    // whatever a conflict found there belongs to someone else
+    let existed = fs::symlink_metadata(dest).is_ok();
+    if let Err(e) = copier.copy(src, dest, report) {
+        let written = (!existed && error_kind(&e) != ErrorKind::Conflict)
+            .then(|| fs::symlink_metadata(dest).ok())
+            .flatten();
+        if let Some(metadata) = written {
+            let cleanup = if metadata.is_dir() {
+                fs::remove_dir_all(dest)
+            } else {
+                fs::remove_file(dest)
+            };
+            if let Err(cleanup_err) = cleanup {
+                error!("Failed to remove partial copy {:?}: {}", dest, cleanup_err);
+            }
         }
+        return Err(e);
+    }
+    Ok(())
+}
 
-        if src.is_file() {
-            fs::copy(&src, &dest)?;
-            fs::remove_file(src)?;
-        } else if src.is_dir() {
-            fs_extra::dir::copy(&src, &dest, &fs_extra::dir::CopyOptions::new())?;
-            fs::remove_dir_all(src)?;
-        } else {
-            return Err(anyhow::anyhow!(
-                "Source path is not a file or directory: {:?}",
-                src
-            ));
-        }
+fn move_files(
+    context: &MoveContext,
+    source_root: &Path,
+    src: &Path,
+    dest: &Path,
+    report: &mut dyn FnMut(&CopyProgress),
+) -> Result<()> {
+    if !src.exists() {
+        return Err(path_mapping(format!(
+            "Source path does not exist: {:?}",
+            src
+        )));
+    }
 
-        remove_torrent(client, &torrent.hash).await?;
+    context.path_guard.check_move(source_root, src, dest)?;
+    context.path_guard.check_removable(src)?;
+    let (needed, _) = measure(src)?;
+    let _reservation = context.preflight.check(dest, needed)?;
+    context.outcomes.start();
+
+    let copy = format!("Copying {:?}", src);
+    let delete = format!("Deleting {:?}", src);
+    if src.is_file() {
+        retry_blocking(&context.retry.copy, &copy, || {
+            copy_or_clean_up(&context.copier, src, dest, report)
+        })?;
+        retry_blocking(&context.retry.delete, &delete, || Ok(fs::remove_file(src)?))?;
+    } else if src.is_dir() {
+        retry_blocking(&context.retry.copy, &copy, || {
+            copy_or_clean_up(&context.copier, src, dest, report)
+        })?;
+        retry_blocking(&context.retry.delete, &delete, || {
+            Ok(fs::remove_dir_all(src)?)
+        })?;
+    } else {
+        return Err(anyhow::anyhow!(
+            "Source path is not a file or directory: {:?}",
+            src
+        ));
     }
+    context.preflight.record_moved(dest, needed);
     Ok(())
 }
 
+/// How a move that did not fail ended.
+#[derive(Debug, PartialEq)]
+pub enum MoveResult {
+    Moved,
+    NothingToDo, // No destination for the category, or the files already are there
+}
+
+/// Whether `source_root` is the destination itself. Discovered categories
+/// point at the save path, so torrents that are already there have nothing
+/// to move.
+fn already_in(source_root: &Path, dest_path: &Path) -> bool {
+    match (source_root.canonicalize(), dest_path.canonicalize()) {
+        (Ok(source), Ok(dest)) => source == dest,
+        _ => false,
+    }
+}
+
+/// Whether the torrent's category has a destination. Whether its files are
+/// already there takes the file system, so the move itself checks that.
+pub fn wants_move(server: &ServerConfig, torrent: &Torrent) -> bool {
+    server.categories.contains_key(&torrent.category)
+}
+
+pub async fn move_and_clean_torrent_files(
+    client: &TorrentClient,
+    torrent: &Torrent,
+    context: &MoveContext,
+) -> Result<MoveResult> {
+    if let Some(dest_path) = client.server.categories.get(&torrent.category) {
+        validate_torrent_name(&torrent.name)?;
+        let mapper = PathMapper::from_server(&client.server);
+        let save_dir = mapper.map(&torrent.save_path)?;
+        let source_root = save_dir.path;
+
+        // Resolving paths blocks on a hung network mount, so it stays off the
+        // runtime's threads. Files already in place need no root.
+        let (check_root, check_dest) = (source_root.clone(), PathBuf::from(dest_path));
+        let (root, name, save_path) = (
+            save_dir.root,
+            torrent.name.clone(),
+            torrent.save_path.clone(),
+        );
+        let in_place = tokio::task::spawn_blocking(move || -> Result<bool> {
+            if already_in(&check_root, &check_dest) {
+                return Ok(true);
+            }
+            let root = root.ok_or_else(|| {
+                path_mapping(format!(
+                    "Refusing to move {}: no path mapping with a local root or download_root covers {:?}",
+                    name, save_path
+                ))
+            })?;
+            ensure_within(&check_root, &root)?;
+            Ok(false)
+        })
+        .await??;
+        if in_place {
+            debug!("{} is already in {}, skipping", torrent.name, dest_path);
+            return Ok(MoveResult::NothingToDo);
+        }
+
+        // Prefer content_path, which follows renames, as long as it names an
+        // entry directly inside the save path
+        let content_path = torrent
+            .content_path
+            .as_deref()
+            .map(|content_path| mapper.map(content_path))
+            .transpose()?;
+        let src = match content_path {
+            Some(content) if content.path.parent() == Some(source_root.as_path()) => content.path,
+            _ => source_root.join(&torrent.name),
+        };
+        let file_name = src
+            .file_name()
+            .ok_or_else(|| path_mapping(format!("Source path has no file name: {:?}", src)))?;
+        let dest = PathBuf::from(dest_path).join(file_name);
+
+        let (job_src, job_dest) = (src.clone(), dest.clone());
+        let job_context = context.clone();
+        let (name, hash) = (torrent.name.clone(), torrent.hash.clone());
+        context
+            .queue
+            .run(&src, &dest, move || {
+                move_files(
+                    &job_context,
+                    &source_root,
+                    &job_src,
+                    &job_dest,
+                    &mut |progress| {
+                        info!("Moving {}: {}", name, progress);
+                        job_context.in_flight.set_progress(&hash, progress.clone());
+                    },
+                )
+            })
+            .await?;
+
+        retry(
+            &context.retry.api,
+            &format!("Removing torrent {}", torrent.name),
+            || remove_torrent(client, &torrent.hash),
+        )
+        .await?;
+        return Ok(MoveResult::Moved);
+    }
+    Ok(MoveResult::NothingToDo)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::{BandwidthConfig, Config, PathMapping};
+    use crate::errors::{error_kind, ErrorKind};
+    use crate::throttle::Bandwidth;
+    use crate::units::HumanDuration;
     use mockito::{self, Server};
+    use std::collections::HashMap;
+
+    fn test_context() -> Result<MoveContext> {
+        Ok(MoveContext {
+            queue: MoveQueue::new(1, 1),
+            copier: Copier::new(Bandwidth::from_config(&BandwidthConfig::default())?, None),
+            preflight: Arc::new(Preflight::from_config(
+                &Config::default(),
+                Notifier::default(),
+            )),
+            deferrals: Deferrals::default(),
+            path_guard: Arc::new(PathGuard::from_config(&Config::default())),
+            in_flight: InFlight::new(),
+            retry: RetryConfig::default(),
+            failures: FailureLog::new(&Config::default().failures_file, 0, Notifier::default()),
+            reports: Reports::default(),
+            outcomes: Outcomes::default(),
+            notifier: Notifier::default(),
+        })
+    }
 
     #[tokio::test]
     async fn test_new_torrent_client() {
@@ -126,7 +512,7 @@ mod tests {
             qbit_url: server.url(),
             ..Default::default()
         };
-        let torrent_client = TorrentClient::new(server_config.clone());
+        let torrent_client = TorrentClient::new(server_config.clone()).unwrap();
         assert_eq!(torrent_client.server, server_config);
     }
 
@@ -142,27 +528,107 @@ mod tests {
             qbit_url: server.url(),
             ..Default::default()
         };
-        let torrent_client = TorrentClient::new(server_config);
-        let url = format!("{}/api/v2/app/version", torrent_client.server.qbit_url);
+        let torrent_client = TorrentClient::new(server_config).unwrap();
+        let url = torrent_client.api_url("app/version");
         let response = torrent_client.make_request(&url, Method::GET).await;
         assert!(response.is_ok());
     }
 
     #[tokio::test]
-    async fn test_is_server_online() {
+    async fn test_get_version() {
         let mut server = Server::new();
         let _m = server
             .mock("GET", "/api/v2/app/version")
             .with_status(200)
+            .with_body("v4.6.0")
             .create();
 
         let server_config = ServerConfig {
             qbit_url: server.url(),
             ..Default::default()
         };
-        let torrent_client = TorrentClient::new(server_config);
-        let is_online = is_server_online(&torrent_client).await;
-        assert!(is_online.is_ok());
+        let torrent_client = TorrentClient::new(server_config).unwrap();
+        assert_eq!(get_version(&torrent_client).await.unwrap(), "v4.6.0");
+
+        server
+            .mock("GET", "/api/v2/app/version")
+            .with_status(403)
+            .create();
+        let err = get_version(&torrent_client).await.unwrap_err();
+        assert_eq!(error_kind(&err), ErrorKind::Auth);
+    }
+
+    #[tokio::test]
+    async fn test_base_path_and_headers() {
+        let mut server = Server::new();
+        let m = server
+            .mock("GET", "/qbt/api/v2/app/version")
+            .match_header("referer", "https://torrents.example.com/qbt/")
+            .match_header("x-api-key", "hunter2")
+            .with_status(200)
+            .with_body("v4.6.0")
+            .create();
+
+        let server_config = ServerConfig {
+            qbit_url: format!("{}/qbt/", server.url()),
+            headers: HashMap::from([
+                (
+                    String::from("Referer"),
+                    Secret::new("https://torrents.example.com/qbt/"),
+                ),
+                (String::from("X-Api-Key"), Secret::new("hunter2")),
+            ]),
+            ..Default::default()
+        };
+        let torrent_client = TorrentClient::new(server_config).unwrap();
+        assert_eq!(get_version(&torrent_client).await.unwrap(), "v4.6.0");
+        m.assert();
+
+        let server_config = ServerConfig {
+            headers: HashMap::from([(String::from("Bad Name"), Secret::new("x"))]),
+            ..Default::default()
+        };
+        assert!(TorrentClient::new(server_config).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_proxy() {
+        let mut proxy = Server::new();
+        let m = proxy
+            .mock("GET", "/api/v2/app/version")
+            .with_status(200)
+            .with_body("v4.6.0")
+            .create();
+
+        let server_config = ServerConfig {
+            qbit_url: String::from("http://qbittorrent.internal:8080"),
+            proxy: Some(Secret::new(proxy.url())),
+            ..Default::default()
+        };
+        let torrent_client = TorrentClient::new(server_config).unwrap();
+        assert_eq!(get_version(&torrent_client).await.unwrap(), "v4.6.0");
+        m.assert();
+
+        let server_config = ServerConfig {
+            proxy: Some(Secret::new("not a proxy")),
+            ..Default::default()
+        };
+        assert!(TorrentClient::new(server_config).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout() -> Result<()> {
+        // Accepts connections but never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let server_config = ServerConfig {
+            qbit_url: format!("http://{}", listener.local_addr()?),
+            request_timeout: HumanDuration(std::time::Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let torrent_client = TorrentClient::new(server_config)?;
+        let err = get_version(&torrent_client).await.unwrap_err();
+        assert_eq!(error_kind(&err), ErrorKind::Connectivity);
+        Ok(())
     }
 
     #[tokio::test]
@@ -179,41 +645,135 @@ mod tests {
             qbit_url: server.url(),
             ..Default::default()
         };
-        let torrent_client = TorrentClient::new(server_config);
+        let torrent_client = TorrentClient::new(server_config).unwrap();
         let torrents = get_completed_torrents(&torrent_client).await;
         assert!(torrents.is_ok());
     }
 
     #[tokio::test]
-    async fn test_remove_torrent() {
+    async fn test_get_preferences() -> Result<()> {
         let mut server = Server::new();
         let _m = server
-            .mock("DELETE", "/api/v2/torrents/delete?hashes=test_hash")
+            .mock("GET", "/api/v2/app/preferences")
             .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"save_path": "/downloads", "temp_path": "/incomplete", "temp_path_enabled": true, "locale": "en"}"#)
             .create();
 
         let server_config = ServerConfig {
             qbit_url: server.url(),
             ..Default::default()
         };
-        let torrent_client = TorrentClient::new(server_config);
+        let torrent_client = TorrentClient::new(server_config)?;
+        let preferences = get_preferences(&torrent_client).await?;
+        assert_eq!(preferences.save_path, "/downloads");
+        assert_eq!(preferences.temp_path, "/incomplete");
+        assert!(preferences.temp_path_enabled);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_get_categories() -> Result<()> {
+        let mut server = Server::new();
+        let _m = server
+            .mock("GET", "/api/v2/torrents/categories")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(r#"{"movies": {"name": "movies", "savePath": "/data/movies"}, "tv": {"name": "tv", "savePath": ""}}"#)
+            .create();
+
+        let torrent_client = TorrentClient::new(ServerConfig {
+            qbit_url: server.url(),
+            ..Default::default()
+        })?;
+        let categories = get_categories(&torrent_client).await?;
+        assert_eq!(categories.len(), 2);
+        assert_eq!(categories["movies"].save_path, "/data/movies");
+        assert_eq!(categories["tv"].save_path, "");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_create_category() -> Result<()> {
+        let mut server = Server::new();
+        let m = server
+            .mock("POST", "/api/v2/torrents/createCategory")
+            .match_body("category=movies&savePath=")
+            .with_status(200)
+            .create();
+
+        let torrent_client = TorrentClient::new(ServerConfig {
+            qbit_url: server.url(),
+            ..Default::default()
+        })?;
+        create_category(&torrent_client, "movies").await?;
+        m.assert();
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_remove_torrent() {
+        let mut server = Server::new();
+        let m = server
+            .mock("POST", "/api/v2/torrents/delete")
+            .match_body("hashes=test_hash&deleteFiles=false")
+            .with_status(200)
+            .create();
+
+        let server_config = ServerConfig {
+            qbit_url: server.url(),
+            ..Default::default()
+        };
+        let torrent_client = TorrentClient::new(server_config).unwrap();
         let hash = "test_hash";
         let result = remove_torrent(&torrent_client, hash).await;
         assert!(result.is_ok());
+        m.assert();
+    }
+
+    #[tokio::test]
+    async fn test_connect_detects_api_version() -> Result<()> {
+        let mut server = Server::new();
+        let api = server
+            .mock("GET", "/api/v2/app/webapiVersion")
+            .with_status(200)
+            .with_body("2.11.2")
+            .create();
+        let server_config = ServerConfig {
+            qbit_url: server.url(),
+            ..Default::default()
+        };
+        let torrent_client = TorrentClient::connect(server_config.clone()).await?;
+        assert_eq!(torrent_client.api_version(), ApiVersion::new(2, 11, 2));
+        api.assert();
+
+        server
+            .mock("GET", "/api/v2/app/webapiVersion")
+            .with_status(200)
+            .with_body("3.0.0")
+            .create();
+        let err = TorrentClient::connect(server_config).await.err().unwrap();
+        assert_eq!(error_kind(&err), ErrorKind::ApiSchema);
+        assert!(err.to_string().contains("is not supported"), "{}", err);
+        Ok(())
     }
 
     #[tokio::test]
     async fn test_move_and_clean_torrent_files() -> Result<()> {
-        let server = Server::new();
+        let mut server = Server::new();
+        let _delete = server
+            .mock("POST", "/api/v2/torrents/delete")
+            .with_status(200)
+            .create();
         let server_config = ServerConfig {
             qbit_url: server.url(),
             ..Default::default()
         };
-        let torrent_client = TorrentClient::new(server_config);
+        let torrent_client = TorrentClient::new(server_config)?;
 
         // Setup
-        let tmp_file = tempfile::NamedTempFile::new()?;
-        let tmp_dir = tmp_file.path().parent().unwrap();
+        let tmp = tempfile::tempdir()?;
+        let tmp_dir = tmp.path();
         println!("Temporary Directory: {:?}", tmp_dir);
         let src_dir = tmp_dir.join("src");
         let dest_dir = tmp_dir.join("dest");
@@ -227,27 +787,194 @@ mod tests {
             name: String::from("test_torrent"),
             category: String::from("test_category"),
             hash: String::from("test_hash"),
+            content_path: None,
         };
 
         // Create a file in the src directory
         let src_file = src_dir.join(&torrent.name);
         fs::File::create(&src_file)?;
 
+        // Torrents without a destination are left alone
+        assert!(!wants_move(&torrent_client.server, &torrent));
+        assert_eq!(
+            move_and_clean_torrent_files(&torrent_client, &torrent, &test_context()?).await?,
+            MoveResult::NothingToDo
+        );
+
         // Update the server config to include the dest directory
         let mut server_config = torrent_client.server.clone();
         server_config.categories.insert(
             torrent.category.clone(),
             dest_dir.to_str().unwrap().to_string(),
         );
-        let torrent_client = TorrentClient::new(server_config);
+        let torrent_client = TorrentClient::new(server_config)?;
+
+        // Without a root to keep sources inside, nothing is moved
+        let err = move_and_clean_torrent_files(&torrent_client, &torrent, &test_context()?)
+            .await
+            .unwrap_err();
+        assert_eq!(error_kind(&err), ErrorKind::PathMapping);
+        assert!(src_file.exists());
+
+        let mut server_config = torrent_client.server.clone();
+        server_config.download_root = Some(tmp_dir.to_str().unwrap().to_string());
+        let torrent_client = TorrentClient::new(server_config)?;
+
+        // Nor is anything outside of the root
+        let outside = Torrent {
+            save_path: String::from("/"),
+            name: String::from("etc"),
+            ..torrent.clone()
+        };
+        let err = move_and_clean_torrent_files(&torrent_client, &outside, &test_context()?)
+            .await
+            .unwrap_err();
+        assert_eq!(error_kind(&err), ErrorKind::PathMapping);
 
         // Move and clean the torrent files
-        move_and_clean_torrent_files(&torrent_client, &torrent).await?;
+        let context = test_context()?;
+        assert!(wants_move(&torrent_client.server, &torrent));
+        assert_eq!(
+            move_and_clean_torrent_files(&torrent_client, &torrent, &context).await?,
+            MoveResult::Moved
+        );
 
         // Check if the file was moved
         assert!(!src_file.exists());
         assert!(dest_dir.join(&torrent.name).exists());
 
+        // A torrent whose save path is its destination has nothing to move
+        let in_place = Torrent {
+            save_path: dest_dir.to_str().unwrap().to_string(),
+            ..torrent
+        };
+        assert!(wants_move(&torrent_client.server, &in_place));
+        assert_eq!(
+            move_and_clean_torrent_files(&torrent_client, &in_place, &context).await?,
+            MoveResult::NothingToDo
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_move_keeps_existing_destination() -> Result<()> {
+        let server = Server::new();
+        let tmp_dir = tempfile::tempdir()?;
+        let src_dir = tmp_dir.path().join("src");
+        let dest_dir = tmp_dir.path().join("dest");
+        fs::create_dir_all(&src_dir)?;
+        fs::create_dir_all(&dest_dir)?;
+        fs::write(src_dir.join("movie.mkv"), b"new")?;
+        fs::write(dest_dir.join("movie.mkv"), b"existing")?;
+
+        let torrent_client = TorrentClient::new(ServerConfig {
+            qbit_url: server.url(),
+            categories: HashMap::from([(
+                String::from("movies"),
+                dest_dir.to_str().unwrap().to_string(),
+            )]),
+            download_root: Some(src_dir.to_str().unwrap().to_string()),
+            ..Default::default()
+        })?;
+        let torrent = Torrent {
+            save_path: src_dir.to_str().unwrap().to_string(),
+            name: String::from("movie.mkv"),
+            category: String::from("movies"),
+            hash: String::from("test_hash"),
+            content_path: None,
+        };
+        let err = move_and_clean_torrent_files(&torrent_client, &torrent, &test_context()?)
+            .await
+            .unwrap_err();
+        assert_eq!(error_kind(&err), ErrorKind::Conflict);
+
+        // Neither copy is lost
+        assert_eq!(fs::read(src_dir.join("movie.mkv"))?, b"new");
+        assert_eq!(fs::read(dest_dir.join("movie.mkv"))?, b"existing");
+
+        // Nor is a dangling symlink the mover did not create
+        #[cfg(unix)]
+        {
+            fs::write(src_dir.join("other.mkv"), b"new")?;
+            std::os::unix::fs::symlink("missing", dest_dir.join("other.mkv"))?;
+            let other = Torrent {
+                name: String::from("other.mkv"),
+                ..torrent
+            };
+            let err = move_and_clean_torrent_files(&torrent_client, &other, &test_context()?)
+                .await
+                .unwrap_err();
+            assert_eq!(error_kind(&err), ErrorKind::Conflict);
+            assert!(fs::symlink_metadata(dest_dir.join("other.mkv")).is_ok());
+            assert!(src_dir.join("other.mkv").exists());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_move_with_path_mappings() -> Result<()> {
+        let mut server = Server::new();
+        let _delete = server
+            .mock("POST", "/api/v2/torrents/delete")
+            .with_status(200)
+            .create();
+        let tmp_dir = tempfile::tempdir()?;
+        let downloads = tmp_dir.path().join("downloads");
+        let dest_dir = tmp_dir.path().join("media");
+        fs::create_dir_all(downloads.join("movies").join("Renamed Movie"))?;
+        fs::write(
+            downloads
+                .join("movies")
+                .join("Renamed Movie")
+                .join("movie.mkv"),
+            b"movie",
+        )?;
+        fs::create_dir_all(&dest_dir)?;
+
+        let server_config = ServerConfig {
+            qbit_url: server.url(),
+            categories: HashMap::from([(
+                String::from("movies"),
+                dest_dir.to_str().unwrap().to_string(),
+            )]),
+            path_mappings: vec![
+                PathMapping {
+                    remote: String::from("/downloads"),
+                    local: downloads.to_str().unwrap().to_string(),
+                },
+                PathMapping {
+                    remote: String::from("/incomplete"),
+                    local: String::from("/nonexistent"),
+                },
+            ],
+            ..Default::default()
+        };
+        let torrent_client = TorrentClient::new(server_config)?;
+
+        // The torrent was renamed in qBittorrent, so only content_path points
+        // at the files on disk
+        let torrent = Torrent {
+            save_path: String::from("/downloads/movies"),
+            name: String::from("Movie Name"),
+            category: String::from("movies"),
+            hash: String::from("test_hash"),
+            content_path: Some(String::from("/downloads/movies/Renamed Movie")),
+        };
+        move_and_clean_torrent_files(&torrent_client, &torrent, &test_context()?).await?;
+        assert!(!downloads.join("movies").join("Renamed Movie").exists());
+        assert!(dest_dir.join("Renamed Movie").join("movie.mkv").exists());
+
+        // Paths that no mapping covers are refused
+        let unmapped = Torrent {
+            save_path: String::from("/elsewhere"),
+            ..torrent
+        };
+        let err = move_and_clean_torrent_files(&torrent_client, &unmapped, &test_context()?)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("No path mapping covers"));
+
         Ok(())
     }
 }
@@ -0,0 +1,438 @@
+/*
+qBittorrent Mover - A tool to automatically move torrents to different categories based on their state.
+Copyright (C) 2023 Harrison Chin
+
+This program is free software: you can redistribute it and/or modify
+it under the terms of the GNU Affero General Public License as published
+by the Free Software Foundation, either version 3 of the License, or
+(at your option) any later version.
+
+This program is distributed in the hope that it will be useful,
+but WITHOUT ANY WARRANTY; without even the implied warranty of
+MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+GNU Affero General Public License for more details.
+
+You should have received a copy of the GNU Affero General Public License
+along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use anyhow::Result;
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::marker::PhantomData;
+use std::str::FromStr;
+use std::time::Duration;
+
+const BINARY_UNITS: [(&str, u64); 5] = [
+    ("PiB", 1 << 50),
+    ("TiB", 1 << 40),
+    ("GiB", 1 << 30),
+    ("MiB", 1 << 20),
+    ("KiB", 1 << 10),
+];
+const DECIMAL_UNITS: [(&str, u64); 5] = [
+    ("PB", 1_000_000_000_000_000),
+    ("TB", 1_000_000_000_000),
+    ("GB", 1_000_000_000),
+    ("MB", 1_000_000),
+    ("KB", 1_000),
+];
+
+/// Starts the message of every size and rate error, so config loading can
+/// tell them apart from other invalid values.
+pub const INVALID_SIZE: &str = "Invalid size";
+
+/// Starts the message of every duration error.
+pub const INVALID_DURATION: &str = "Invalid duration";
+
+/// Splits "1.5GiB" into the number and the rest.
+fn split_number(value: &str) -> (&str, &str) {
+    let end = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    value.split_at(end)
+}
+
+/// Parses a size such as "512K", "1.5GiB" or "10MB" into bytes. Units ending
+/// in "iB" are binary (powers of 1024) and units ending in "B" are decimal
+/// (powers of 1000). A bare "K", "M", "G", "T" or "P" is binary, and a bare
+/// number is bytes.
+pub fn parse_size(size: &str) -> Result<u64> {
+    let size = size.trim();
+    let (number, unit) = split_number(size);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| anyhow::anyhow!("{}: {:?}", INVALID_SIZE, size))?;
+
+    let unit = unit.trim().to_ascii_lowercase();
+    let (prefix, binary) = match unit.strip_suffix("ib") {
+        Some(prefix) => (prefix, true),
+        None => match unit.strip_suffix('b') {
+            Some(prefix) => (prefix, false),
+            None => (unit.as_str(), true),
+        },
+    };
+    let power = match prefix {
+        "" => 0,
+        "k" => 1,
+        "m" => 2,
+        "g" => 3,
+        "t" => 4,
+        "p" => 5,
+        _ => return Err(anyhow::anyhow!("{} unit: {:?}", INVALID_SIZE, size)),
+    };
+    if prefix.is_empty() && binary && unit == "ib" {
+        return Err(anyhow::anyhow!("{} unit: {:?}", INVALID_SIZE, size));
+    }
+
+    let base: f64 = if binary { 1024.0 } else { 1000.0 };
+    let bytes = (number * base.powi(power)).round();
+    if bytes >= u64::MAX as f64 {
+        return Err(anyhow::anyhow!("{}, too large: {:?}", INVALID_SIZE, size));
+    }
+    Ok(bytes as u64)
+}
+
+/// Parses a transfer rate such as "80MiB/s" into bytes per second.
+pub fn parse_rate(rate: &str) -> Result<u64> {
+    let size = rate.trim();
+    let size = size.strip_suffix("/s").unwrap_or(size);
+    let bytes = parse_size(size)?;
+    if bytes == 0 {
+        return Err(anyhow::anyhow!(
+            "{}: a rate must be greater than zero: {:?}",
+            INVALID_SIZE,
+            rate
+        ));
+    }
+    Ok(bytes)
+}
+
+/// Parses a duration such as "30s", "5m", "1h30m" or "250ms". A bare number
+/// is seconds.
+pub fn parse_duration(duration: &str) -> Result<Duration> {
+    let trimmed = duration.trim();
+    let invalid = || {
+        anyhow::anyhow!(
+            "{} {:?}, expected something like \"30s\", \"5m\" or \"1h30m\"",
+            INVALID_DURATION,
+            duration
+        )
+    };
+    if let Ok(seconds) = trimmed.parse::<u64>() {
+        return Ok(Duration::from_secs(seconds));
+    }
+
+    let mut rest = trimmed;
+    let mut total = Duration::ZERO;
+    if rest.is_empty() {
+        return Err(invalid());
+    }
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let number: u64 = rest[..digits].parse().map_err(|_| invalid())?;
+        rest = &rest[digits..];
+        let unit_end = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let part = match &rest[..unit_end] {
+            "ms" => Duration::from_millis(number),
+            "s" => Duration::from_secs(number),
+            "m" => Duration::from_secs(number.saturating_mul(60)),
+            "h" => Duration::from_secs(number.saturating_mul(3600)),
+            "d" => Duration::from_secs(number.saturating_mul(86400)),
+            _ => return Err(invalid()),
+        };
+        total = total.saturating_add(part);
+        rest = &rest[unit_end..];
+    }
+    Ok(total)
+}
+
+/// Deserializes a setting written either as a string or as a plain number,
+/// which is read like the same number written as a string.
+struct HumanVisitor<T>(PhantomData<T>);
+
+impl<'de, T> Visitor<'de> for HumanVisitor<T>
+where
+    T: FromStr<Err = anyhow::Error>,
+{
+    type Value = T;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a number or a string with a unit")
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<T, E> {
+        self.visit_str(&value.to_string())
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<T, E> {
+        self.visit_str(&value.to_string())
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<T, E> {
+        value.parse().map_err(E::custom)
+    }
+}
+
+macro_rules! human_serde {
+    ($type:ty) => {
+        impl<'de> Deserialize<'de> for $type {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                deserializer.deserialize_any(HumanVisitor(PhantomData))
+            }
+        }
+
+        impl Serialize for $type {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+    };
+}
+
+/// A size in bytes, written in the config as "512K", "1.5GiB", "10MB" or a
+/// plain number of bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct ByteSize(pub u64);
+
+impl ByteSize {
+    pub fn bytes(self) -> u64 {
+        self.0
+    }
+}
+
+impl FromStr for ByteSize {
+    type Err = anyhow::Error;
+
+    fn from_str(size: &str) -> Result<Self> {
+        parse_size(size).map(Self)
+    }
+}
+
+/// Writes the size in the largest unit that divides it exactly, so it reads
+/// back unchanged.
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let unit = BINARY_UNITS
+            .iter()
+            .zip(DECIMAL_UNITS.iter())
+            .flat_map(|(binary, decimal)| [binary, decimal])
+            .find(|(_, multiplier)| self.0 > 0 && self.0.is_multiple_of(*multiplier));
+        match unit {
+            Some((name, multiplier)) => write!(f, "{}{}", self.0 / multiplier, name),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
+human_serde!(ByteSize);
+
+/// A transfer rate in bytes per second, written as "20MiB/s" or a plain
+/// number of bytes per second. It is never zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRate(u64);
+
+impl ByteRate {
+    pub fn bytes_per_sec(self) -> u64 {
+        self.0
+    }
+}
+
+impl FromStr for ByteRate {
+    type Err = anyhow::Error;
+
+    fn from_str(rate: &str) -> Result<Self> {
+        parse_rate(rate).map(Self)
+    }
+}
+
+impl fmt::Display for ByteRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/s", ByteSize(self.0))
+    }
+}
+
+human_serde!(ByteRate);
+
+/// A duration written as "30s", "5m", "1h30m" or a plain number of seconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct HumanDuration(pub Duration);
+
+impl HumanDuration {
+    pub fn from_secs(seconds: u64) -> Self {
+        Self(Duration::from_secs(seconds))
+    }
+
+    pub fn get(self) -> Duration {
+        self.0
+    }
+
+    pub fn is_zero(self) -> bool {
+        self.0.is_zero()
+    }
+}
+
+impl FromStr for HumanDuration {
+    type Err = anyhow::Error;
+
+    fn from_str(duration: &str) -> Result<Self> {
+        parse_duration(duration).map(Self)
+    }
+}
+
+impl fmt::Display for HumanDuration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let millis = self.0.as_millis();
+        if millis == 0 {
+            return f.write_str("0s");
+        }
+        let parts = [
+            (millis / 3_600_000, "h"),
+            (millis / 60_000 % 60, "m"),
+            (millis / 1000 % 60, "s"),
+            (millis % 1000, "ms"),
+        ];
+        for (value, unit) in parts {
+            if value > 0 {
+                write!(f, "{}{}", value, unit)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+human_serde!(HumanDuration);
+
+/// Formats a byte count for humans, e.g. "1.5 GiB".
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Formats a duration for humans, e.g. "1h2m3s".
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (hours, minutes, seconds) = (secs / 3600, secs / 60 % 60, secs % 60);
+    match (hours, minutes) {
+        (0, 0) => format!("{}s", seconds),
+        (0, _) => format!("{}m{}s", minutes, seconds),
+        _ => format!("{}h{}m{}s", hours, minutes, seconds),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1024").unwrap(), 1024);
+        assert_eq!(parse_size("512K").unwrap(), 512 * 1024);
+        assert_eq!(parse_size("10M").unwrap(), 10 * 1024 * 1024);
+        assert_eq!(parse_size("80MiB").unwrap(), 80 * 1024 * 1024);
+        assert_eq!(parse_size("2G").unwrap(), 2 * 1024 * 1024 * 1024);
+        assert_eq!(parse_size(" 1 TiB ").unwrap(), 1 << 40);
+        assert_eq!(parse_size("1.5GiB").unwrap(), 3 << 29);
+        assert_eq!(parse_size("512B").unwrap(), 512);
+    }
+
+    #[test]
+    fn test_parse_size_decimal_units() {
+        assert_eq!(parse_size("10MB").unwrap(), 10_000_000);
+        assert_eq!(parse_size("1.5kb").unwrap(), 1500);
+        assert_eq!(parse_size("2TB").unwrap(), 2_000_000_000_000);
+    }
+
+    #[test]
+    fn test_parse_size_rejects_garbage() {
+        assert!(parse_size("").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("10X").is_err());
+        assert!(parse_size("1iB").is_err());
+        assert!(parse_size("1.2.3M").is_err());
+        assert!(parse_size("99999999999P").is_err());
+    }
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("80MiB/s").unwrap(), 80 * 1024 * 1024);
+        assert_eq!(parse_rate("20M").unwrap(), 20 * 1024 * 1024);
+        assert!(parse_rate("0/s").is_err());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("30").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("5m").unwrap(), Duration::from_secs(300));
+        assert_eq!(parse_duration("1h30m").unwrap(), Duration::from_secs(5400));
+        assert_eq!(parse_duration("250ms").unwrap(), Duration::from_millis(250));
+        assert_eq!(parse_duration("1d").unwrap(), Duration::from_secs(86400));
+        for invalid in ["", "m", "5x", "1.5h", "-5s", "5 m"] {
+            assert!(parse_duration(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_display_round_trips() {
+        for size in ["0", "1000", "1023", "10MiB", "10MB", "3GiB", "1536"] {
+            let parsed: ByteSize = size.parse().unwrap();
+            assert_eq!(parsed.to_string().parse::<ByteSize>().unwrap(), parsed);
+        }
+        assert_eq!(ByteSize(10 << 20).to_string(), "10MiB");
+        assert_eq!(ByteSize(1536).to_string(), "1536");
+        assert_eq!("20M/s".parse::<ByteRate>().unwrap().to_string(), "20MiB/s");
+        assert_eq!(HumanDuration::from_secs(5400).to_string(), "1h30m");
+        assert_eq!(
+            HumanDuration(Duration::from_millis(1500)).to_string(),
+            "1s500ms"
+        );
+        assert_eq!(HumanDuration::default().to_string(), "0s");
+    }
+
+    #[test]
+    fn test_deserialize_strings_and_numbers() {
+        let size: ByteSize = serde_yaml::from_str("1.5KiB").unwrap();
+        assert_eq!(size, ByteSize(1536));
+        let size: ByteSize = serde_yaml::from_str("4096").unwrap();
+        assert_eq!(size, ByteSize(4096));
+        let delay: HumanDuration = serde_yaml::from_str("5").unwrap();
+        assert_eq!(delay, HumanDuration::from_secs(5));
+        let delay: HumanDuration = serde_json::from_str("\"2m\"").unwrap();
+        assert_eq!(delay, HumanDuration::from_secs(120));
+
+        let err = serde_yaml::from_str::<ByteSize>("lots").unwrap_err();
+        assert!(err.to_string().contains("Invalid size"), "{}", err);
+        assert!(serde_yaml::from_str::<HumanDuration>("-5").is_err());
+        assert!(serde_yaml::from_str::<ByteRate>("0/s").is_err());
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_secs(42)), "42s");
+        assert_eq!(format_duration(Duration::from_secs(90)), "1m30s");
+        assert_eq!(format_duration(Duration::from_secs(3723)), "1h2m3s");
+    }
+}